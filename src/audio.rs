@@ -13,7 +13,7 @@ impl Plugin for AudioPlugin {
                     .after(propagate)
                     .run_if(in_state(GameState::InGame)),
             )
-            .add_system(start_background.in_schedule(OnEnter(GameState::Menu)));
+            .add_system(start_background.in_schedule(OnExit(GameState::AssetLoading)));
     }
 }
 
@@ -24,6 +24,7 @@ pub enum AudioEvent {
     Lost,
     Pew,
     Revive,
+    Pickup,
 }
 
 fn start_background(audio: Res<Audio>, sound: Res<AudioAssets>) {
@@ -42,6 +43,9 @@ fn enemy_falls(mut events: EventReader<AudioEvent>, sound: Res<AudioAssets>, aud
             AudioEvent::Lost => audio.play(sound.lost.clone()),
             AudioEvent::Pew => audio.play(sound.pew.clone()),
             AudioEvent::Revive => audio.play(sound.revive.clone()),
+            // no sound of its own yet, a higher pitched revive is close enough
+            AudioEvent::Pickup => audio
+                .play_with_settings(sound.revive.clone(), PlaybackSettings::ONCE.with_speed(1.5)),
         };
     }
 }
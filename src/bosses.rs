@@ -0,0 +1,234 @@
+use crate::enemies::{spawn_spit, Enemy, Spit};
+use crate::loading::{EnemyAssets, EnemyData, FontAssets, GameData, ImageAssets};
+use crate::networking::{SeedFrame, SessionOnly};
+use crate::players::Health;
+use crate::rules::MatchRules;
+use crate::waves::{spawn_enemy, WaveDefinitions, Waves};
+use crate::GameState;
+use bevy::math::Vec3Swizzles;
+use bevy::prelude::*;
+use bevy_ggrs::RollbackIdProvider;
+use std::f32::consts::TAU;
+
+pub struct BossesPlugin;
+
+impl Plugin for BossesPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system(spawn_boss_bar.in_schedule(OnExit(GameState::Matchmaking)))
+            .add_system(update_boss_bar.run_if(in_state(GameState::InGame)));
+    }
+}
+
+/// Distance of summoned enemies from the edge of the boss
+const SUMMON_DISTANCE: f32 = 1.;
+/// Distance projectile rings travel
+const RING_RANGE: f32 = 12.;
+
+/// Attacks of a boss while its health is at most `health` of its max health
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct BossPhase {
+    pub health: f64,
+    pub attack: BossAttack,
+    /// Frames between two attacks
+    pub interval: u32,
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+pub enum BossAttack {
+    /// Call enemies from `enemies.my-assets` to the boss' side
+    Summon { enemy: String, count: u32 },
+    /// Shoot projectiles in all directions at once
+    Ring { projectiles: u32, speed: f32 },
+}
+
+#[derive(Component, Reflect, Default)]
+pub struct Boss {
+    /// Definition of the boss, holding its phases
+    pub data: Handle<EnemyData>,
+    /// Index into the phases of the boss
+    pub phase: usize,
+    pub last_attack: u32,
+}
+
+impl Boss {
+    pub fn new(data: Handle<EnemyData>) -> Self {
+        Boss {
+            data,
+            phase: 0,
+            last_attack: 0,
+        }
+    }
+}
+
+/// The latest phase whose health threshold the boss has fallen below
+fn current_phase(phases: &[BossPhase], health: &Health) -> usize {
+    let fraction = health.current / health.max;
+    phases
+        .iter()
+        .rposition(|phase| fraction <= phase.health)
+        .unwrap_or(0)
+}
+
+pub fn boss_attacks(
+    mut commands: Commands,
+    mut rip: ResMut<RollbackIdProvider>,
+    images: Res<ImageAssets>,
+    seed_frame: Res<SeedFrame>,
+    waves: Res<Waves>,
+    rules: Res<MatchRules>,
+    game_data: Res<GameData>,
+    wave_definitions: Res<Assets<WaveDefinitions>>,
+    enemy_assets: Res<EnemyAssets>,
+    enemy_data: Res<Assets<EnemyData>>,
+    mut bosses: Query<(&Transform, &Health, &Enemy, &mut Boss)>,
+) {
+    let definitions = wave_definitions.get(&game_data.waves).unwrap();
+    for (transform, health, enemy, mut boss) in &mut bosses {
+        let Some(data) = enemy_data.get(&boss.data) else {
+            continue;
+        };
+        let phase = current_phase(&data.phases, health);
+        if phase != boss.phase {
+            info!("Boss enters phase {}", phase + 1);
+            boss.phase = phase;
+            // attack right away to announce the new phase
+            boss.last_attack = 0;
+        }
+        let Some(phase) = data.phases.get(phase) else {
+            continue;
+        };
+        if boss.last_attack != 0 && boss.last_attack + phase.interval > seed_frame.0 {
+            continue;
+        }
+        boss.last_attack = seed_frame.0;
+
+        let position = transform.translation.xy();
+        match &phase.attack {
+            BossAttack::Summon { enemy: key, count } => {
+                let Some((handle, summoned)) = enemy_assets
+                    .by_key(key)
+                    .and_then(|handle| Some((handle, enemy_data.get(handle)?)))
+                else {
+                    warn!("Unknown enemy '{}' summoned by a boss", key);
+                    continue;
+                };
+                for index in 0..*count {
+                    let angle = TAU * index as f32 / *count as f32;
+                    let offset = Vec2::from_angle(angle) * (enemy.radius + SUMMON_DISTANCE);
+                    spawn_enemy(
+                        &mut commands,
+                        &mut rip,
+                        handle,
+                        summoned,
+                        (position + offset).extend(100.),
+                        &definitions.enemy_stats(waves.wave, &rules),
+                        if index % 2 == 0 { 1. } else { -1. },
+                    );
+                }
+            }
+            BossAttack::Ring { projectiles, speed } => {
+                for index in 0..*projectiles {
+                    let direction = Vec2::from_angle(TAU * index as f32 / *projectiles as f32);
+                    spawn_spit(
+                        &mut commands,
+                        &mut rip,
+                        &images,
+                        position + direction * enemy.radius,
+                        direction,
+                        Spit {
+                            damage: enemy.damage,
+                            speed: *speed,
+                            range: RING_RANGE,
+                        },
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[derive(Component)]
+struct BossBar;
+
+#[derive(Component)]
+struct BossBarFill;
+
+fn spawn_boss_bar(mut commands: Commands, font_assets: Res<FontAssets>) {
+    commands
+        .spawn(NodeBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: UiRect {
+                    top: Val::Px(70.),
+                    left: Val::Percent(20.),
+                    ..default()
+                },
+                size: Size::new(Val::Percent(60.), Val::Px(24.)),
+                justify_content: JustifyContent::Center,
+                align_items: AlignItems::Center,
+                ..default()
+            },
+            background_color: Color::DARK_GRAY.into(),
+            visibility: Visibility::Hidden,
+            ..default()
+        })
+        .insert(BossBar)
+        .insert(SessionOnly)
+        .with_children(|parent| {
+            parent
+                .spawn(NodeBundle {
+                    style: Style {
+                        position_type: PositionType::Absolute,
+                        position: UiRect {
+                            left: Val::Px(0.),
+                            top: Val::Px(0.),
+                            ..default()
+                        },
+                        size: Size::new(Val::Percent(100.), Val::Percent(100.)),
+                        ..default()
+                    },
+                    background_color: Color::RED.into(),
+                    ..default()
+                })
+                .insert(BossBarFill);
+            parent.spawn(TextBundle {
+                text: Text::from_section(
+                    "Boss",
+                    TextStyle {
+                        font: font_assets.fira_sans.clone(),
+                        font_size: 20.0,
+                        color: Color::rgb(0.9, 0.9, 0.9),
+                    },
+                ),
+                ..default()
+            });
+        });
+}
+
+/// Combined health of all living bosses; hidden while there are none
+fn update_boss_bar(
+    bosses: Query<&Health, With<Boss>>,
+    mut bar: Query<&mut Visibility, With<BossBar>>,
+    mut fill: Query<&mut Style, With<BossBarFill>>,
+) {
+    let (current, max) = bosses.iter().fold((0., 0.), |(current, max), health| {
+        (current + health.current.max(0.), max + health.max)
+    });
+    let Ok(mut visibility) = bar.get_single_mut() else {
+        return;
+    };
+    let shown = if max > 0. {
+        Visibility::Inherited
+    } else {
+        Visibility::Hidden
+    };
+    if *visibility != shown {
+        *visibility = shown;
+    }
+    if let Ok(mut style) = fill.get_single_mut() {
+        let width = Val::Percent((100. * current / max.max(1.)) as f32);
+        if style.size.width != width {
+            style.size.width = width;
+        }
+    }
+}
@@ -0,0 +1,282 @@
+use crate::bosses::Boss;
+use crate::enemies::{Behavior, Enemy, EnemyAction, Spit};
+use crate::networking::{Dead, SeedFrame};
+use crate::pickups::{Boosts, Pickup, PickupKind};
+use crate::players::{Health, MoveDir, Weapon};
+use crate::waves::Waves;
+use crate::{Bullet, Score};
+use bevy::prelude::*;
+use bevy_ggrs::Rollback;
+use std::any::type_name;
+
+/// 64 bit FNV-1a over little-endian bytes.
+///
+/// Checksums are compared between peers, which may run different Rust versions or a wasm build,
+/// so neither `DefaultHasher` nor the platform dependent size of `usize` may leak into them.
+pub struct ChecksumHasher(u64);
+
+impl Default for ChecksumHasher {
+    fn default() -> Self {
+        ChecksumHasher(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl ChecksumHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.write(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.write(&value.to_le_bytes());
+    }
+
+    /// Hashed as `u64` on every platform
+    pub fn usize(&mut self, value: usize) {
+        self.u64(value as u64);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.write(&[value as u8]);
+    }
+
+    pub fn f32s(&mut self, values: &[f32]) {
+        for value in values {
+            self.u32(value.to_bits());
+        }
+    }
+
+    pub fn f64(&mut self, value: f64) {
+        self.u64(value.to_bits());
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Feeds the simulation relevant parts of a value into a hasher
+pub trait Checksum {
+    fn checksum(&self, hasher: &mut ChecksumHasher);
+}
+
+impl Checksum for Transform {
+    fn checksum(&self, hasher: &mut ChecksumHasher) {
+        hasher.f32s(&self.translation.to_array());
+        hasher.f32s(&self.rotation.to_array());
+    }
+}
+
+impl Checksum for Health {
+    fn checksum(&self, hasher: &mut ChecksumHasher) {
+        hasher.f64(self.max);
+        hasher.f64(self.current);
+    }
+}
+
+impl Checksum for Bullet {
+    fn checksum(&self, hasher: &mut ChecksumHasher) {
+        hasher.f64(self.damage);
+        hasher.usize(self.max_hits);
+        hasher.f32s(&[self.speed, self.range]);
+        // entity ids are not stable across rollbacks
+        hasher.usize(self.already_hit.len());
+    }
+}
+
+impl Checksum for MoveDir {
+    fn checksum(&self, hasher: &mut ChecksumHasher) {
+        hasher.f32s(&self.0.to_array());
+    }
+}
+
+impl Checksum for Weapon {
+    fn checksum(&self, hasher: &mut ChecksumHasher) {
+        hasher.u32(self.fire_frame);
+        hasher.u32(self.frame_cooldown);
+        hasher.f64(self.damage);
+        hasher.f32s(&[self.speed, self.spread, self.range]);
+        hasher.u32(self.projectiles);
+        hasher.usize(self.pierce);
+        hasher.u32(self.ammo);
+        hasher.u32(self.magazine);
+        hasher.u32(self.reload_time);
+        hasher.u32(self.reload_frame);
+        hasher.u32(self.reserve_ammo);
+        hasher.bool(self.unlimited_reserve);
+    }
+}
+
+impl Checksum for Pickup {
+    fn checksum(&self, hasher: &mut ChecksumHasher) {
+        hasher.u32(match self.kind {
+            PickupKind::Health => 0,
+            PickupKind::Ammo => 1,
+            PickupKind::DamageBoost => 2,
+            PickupKind::FireRateBoost => 3,
+            PickupKind::Shotgun => 4,
+            PickupKind::Rifle => 5,
+            PickupKind::PiercingGun => 6,
+        });
+        hasher.u32(self.expires);
+    }
+}
+
+impl Checksum for Boosts {
+    fn checksum(&self, hasher: &mut ChecksumHasher) {
+        hasher.u32(self.damage_until);
+        hasher.u32(self.fire_rate_until);
+    }
+}
+
+impl Checksum for Enemy {
+    fn checksum(&self, hasher: &mut ChecksumHasher) {
+        hasher.f64(self.damage);
+        hasher.f32s(&[self.speed]);
+        hasher.u32(self.last_attack);
+        hasher.u32(self.attack_cooldown);
+        self.behavior.checksum(hasher);
+        self.action.checksum(hasher);
+        hasher.f32s(&[self.side, self.radius]);
+    }
+}
+
+impl Checksum for Behavior {
+    fn checksum(&self, hasher: &mut ChecksumHasher) {
+        match *self {
+            Behavior::Chase => hasher.u32(0),
+            Behavior::Spitter {
+                range,
+                projectile_speed,
+            } => {
+                hasher.u32(1);
+                hasher.f32s(&[range, projectile_speed]);
+            }
+            Behavior::Charger {
+                range,
+                windup,
+                dash_speed,
+                dash_frames,
+            } => {
+                hasher.u32(2);
+                hasher.f32s(&[range, dash_speed]);
+                hasher.u32(windup);
+                hasher.u32(dash_frames);
+            }
+            Behavior::Swarmer { flank_distance } => {
+                hasher.u32(3);
+                hasher.f32s(&[flank_distance]);
+            }
+        }
+    }
+}
+
+impl Checksum for EnemyAction {
+    fn checksum(&self, hasher: &mut ChecksumHasher) {
+        let (tag, until, direction) = match *self {
+            EnemyAction::Approach => (0, 0, Vec2::ZERO),
+            EnemyAction::WindUp { until, direction } => (1, until, direction),
+            EnemyAction::Dash { until, direction } => (2, until, direction),
+        };
+        hasher.u32(tag);
+        hasher.u32(until);
+        hasher.f32s(&direction.to_array());
+    }
+}
+
+impl Checksum for Boss {
+    fn checksum(&self, hasher: &mut ChecksumHasher) {
+        hasher.usize(self.phase);
+        hasher.u32(self.last_attack);
+    }
+}
+
+impl Checksum for Spit {
+    fn checksum(&self, hasher: &mut ChecksumHasher) {
+        hasher.f64(self.damage);
+        hasher.f32s(&[self.speed, self.range]);
+    }
+}
+
+impl Checksum for Dead {
+    // the number of dead players still changes the combined checksum
+    fn checksum(&self, _hasher: &mut ChecksumHasher) {}
+}
+
+impl Checksum for SeedFrame {
+    fn checksum(&self, hasher: &mut ChecksumHasher) {
+        hasher.u32(self.0);
+    }
+}
+
+impl Checksum for Score {
+    fn checksum(&self, hasher: &mut ChecksumHasher) {
+        hasher.f64(self.0);
+    }
+}
+
+impl Checksum for Waves {
+    fn checksum(&self, hasher: &mut ChecksumHasher) {
+        hasher.u32(self.wave);
+        hasher.u32(self.spawned);
+        hasher.u32(self.cooldown);
+        hasher.bool(self.breather);
+        hasher.usize(self.score_bosses.len());
+        for bosses in &self.score_bosses {
+            hasher.u32(*bosses);
+        }
+    }
+}
+
+/// Combines the checksums of all rolled back entities with component `T`.
+///
+/// Rollback ids and entities change when entities are respawned during a rollback,
+/// so the checksums are summed up independent of iteration order.
+fn component_checksum<T: Component + Checksum>(world: &mut World) -> u64 {
+    world
+        .query_filtered::<&T, With<Rollback>>()
+        .iter(world)
+        .fold(0u64, |sum, component| {
+            let mut hasher = ChecksumHasher::default();
+            component.checksum(&mut hasher);
+            sum.wrapping_add(hasher.finish())
+        })
+}
+
+fn resource_checksum<T: Resource + Checksum>(world: &World) -> u64 {
+    let mut hasher = ChecksumHasher::default();
+    world.resource::<T>().checksum(&mut hasher);
+    hasher.finish()
+}
+
+/// Checksums of the rollback state, labeled with the name of the checked type
+pub fn world_checksums(world: &mut World) -> Vec<(&'static str, u64)> {
+    vec![
+        (
+            type_name::<SeedFrame>(),
+            resource_checksum::<SeedFrame>(world),
+        ),
+        (type_name::<Score>(), resource_checksum::<Score>(world)),
+        (type_name::<Waves>(), resource_checksum::<Waves>(world)),
+        (
+            type_name::<Transform>(),
+            component_checksum::<Transform>(world),
+        ),
+        (type_name::<Health>(), component_checksum::<Health>(world)),
+        (type_name::<Bullet>(), component_checksum::<Bullet>(world)),
+        (type_name::<MoveDir>(), component_checksum::<MoveDir>(world)),
+        (type_name::<Weapon>(), component_checksum::<Weapon>(world)),
+        (type_name::<Enemy>(), component_checksum::<Enemy>(world)),
+        (type_name::<Spit>(), component_checksum::<Spit>(world)),
+        (type_name::<Boss>(), component_checksum::<Boss>(world)),
+        (type_name::<Dead>(), component_checksum::<Dead>(world)),
+        (type_name::<Pickup>(), component_checksum::<Pickup>(world)),
+        (type_name::<Boosts>(), component_checksum::<Boosts>(world)),
+    ]
+}
@@ -0,0 +1,138 @@
+use crate::loading::FontAssets;
+use crate::matchmaking::{Host, LocalPlayer, RemotePlayers};
+use crate::networking::{leave_game, GgrsConfig, SessionOnly};
+use crate::{GameMode, GameState};
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use bevy_ggrs::Session;
+use ggrs::GGRSEvent;
+use matchbox_socket::PeerId;
+
+pub struct ConnectionPlugin;
+
+impl Plugin for ConnectionPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<InterruptedPeers>()
+            .add_system(prepare_reconnecting_ui.in_schedule(OnExit(GameState::Matchmaking)))
+            .add_systems(
+                (handle_session_events, update_reconnecting_ui)
+                    .chain()
+                    .run_if(resource_exists::<Session<GgrsConfig>>()),
+            );
+    }
+}
+
+/// Peers we currently don't receive anything from, with their player name
+#[derive(Default, Resource)]
+struct InterruptedPeers(HashMap<PeerId, String>);
+
+/// Disconnected players are killed inside the simulation, see `disconnect_players`.
+/// Without the host nobody can start the next round, so everyone goes back to the lobby instead.
+fn handle_session_events(
+    mut commands: Commands,
+    mut session: ResMut<Session<GgrsConfig>>,
+    mut interrupted: ResMut<InterruptedPeers>,
+    mut state: ResMut<NextState<GameState>>,
+    mut players: ResMut<RemotePlayers>,
+    local_player: Res<LocalPlayer>,
+    host: Option<Res<Host>>,
+) {
+    let Session::P2PSession(session) = session.as_mut() else {
+        return;
+    };
+    let mut host_left = false;
+    for event in session.events() {
+        let name = |peer: &PeerId| {
+            players
+                .0
+                .iter()
+                .find(|player| player.id == peer.0.to_string())
+                .map(|player| player.name.clone())
+                .unwrap_or_else(|| peer.0.to_string())
+        };
+        match event {
+            GGRSEvent::NetworkInterrupted { addr, .. } => {
+                warn!("Connection to {} interrupted", addr.0);
+                interrupted.0.insert(addr, name(&addr));
+            }
+            GGRSEvent::NetworkResumed { addr } => {
+                info!("Connection to {} resumed", addr.0);
+                interrupted.0.remove(&addr);
+            }
+            GGRSEvent::Disconnected { addr } => {
+                info!("Player {} disconnected", addr.0);
+                interrupted.0.remove(&addr);
+                players.0.retain(|player| player.id != addr.0.to_string());
+                host_left |= host.as_ref().map(|host| host.0) == Some(addr);
+            }
+            _ => (),
+        }
+    }
+    if !host_left {
+        return;
+    }
+
+    // every remaining player picks the same new host
+    let new_host = players.0.iter().map(|player| &player.id).min();
+    let is_new_host = new_host == Some(&local_player.0.id);
+    info!("The host left the game, returning to the lobby");
+    interrupted.0.clear();
+    commands.add(leave_game);
+    commands.insert_resource(GameMode::Multi(is_new_host));
+    state.set(GameState::Connect);
+}
+
+#[derive(Component)]
+struct ReconnectingText;
+
+fn prepare_reconnecting_ui(mut commands: Commands, font_assets: Res<FontAssets>) {
+    commands
+        .spawn(TextBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: UiRect {
+                    top: Val::Percent(40.),
+                    left: Val::Percent(30.),
+                    ..default()
+                },
+                ..default()
+            },
+            text: Text {
+                sections: vec![TextSection {
+                    value: "".to_owned(),
+                    style: TextStyle {
+                        font: font_assets.fira_sans.clone(),
+                        font_size: 40.0,
+                        color: Color::rgb(0.9, 0.9, 0.9),
+                    },
+                }],
+                alignment: TextAlignment::Center,
+                ..default()
+            },
+            background_color: BackgroundColor(Color::rgba(0., 0., 0., 0.6)),
+            visibility: Visibility::Hidden,
+            ..Default::default()
+        })
+        .insert(ReconnectingText)
+        .insert(SessionOnly);
+}
+
+fn update_reconnecting_ui(
+    interrupted: Res<InterruptedPeers>,
+    mut text: Query<(&mut Text, &mut Visibility), With<ReconnectingText>>,
+) {
+    if !interrupted.is_changed() {
+        return;
+    }
+    let Ok((mut text, mut visibility)) = text.get_single_mut() else {
+        return;
+    };
+    if interrupted.0.is_empty() {
+        *visibility = Visibility::Hidden;
+        return;
+    }
+    let mut names: Vec<&str> = interrupted.0.values().map(String::as_str).collect();
+    names.sort();
+    text.sections[0].value = format!("Reconnecting to {}…", names.join(", "));
+    *visibility = Visibility::Visible;
+}
@@ -0,0 +1,598 @@
+use crate::gamepad::{any_just_pressed, any_pressed, navigate_buttons};
+use crate::input::{
+    INPUT_DOWN, INPUT_FIRE, INPUT_LEFT, INPUT_RELOAD, INPUT_REVIVE, INPUT_RIGHT, INPUT_UP,
+};
+use crate::loading::FontAssets;
+use crate::menu::ButtonColors;
+use crate::GameState;
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+
+pub struct ControlsPlugin;
+
+impl Plugin for ControlsPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(Controls::load())
+            .init_resource::<Rebinding>()
+            .add_system(setup_controls_screen.in_schedule(OnEnter(GameState::Controls)))
+            .add_systems((
+                click_controls_button.run_if(in_state(GameState::Controls)),
+                capture_binding
+                    .after(click_controls_button)
+                    .before(navigate_buttons)
+                    .run_if(in_state(GameState::Controls)),
+                update_controls_screen
+                    .after(capture_binding)
+                    .run_if(in_state(GameState::Controls)),
+            ))
+            .add_system(cleanup_controls_screen.in_schedule(OnExit(GameState::Controls)));
+    }
+}
+
+/// Relative to the working directory
+#[cfg(not(target_arch = "wasm32"))]
+const CONTROLS_FILE: &str = "controls.ron";
+#[cfg(target_arch = "wasm32")]
+const CONTROLS_STORAGE_KEY: &str = "friends_vs_zombies_controls";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Action {
+    Up,
+    Down,
+    Left,
+    Right,
+    Fire,
+    Revive,
+    Reload,
+    /// Start the game from the lobby as host
+    StartGame,
+}
+
+impl Action {
+    pub const ALL: [Action; 8] = [
+        Action::Up,
+        Action::Down,
+        Action::Left,
+        Action::Right,
+        Action::Fire,
+        Action::Revive,
+        Action::Reload,
+        Action::StartGame,
+    ];
+
+    fn label(&self) -> &'static str {
+        match self {
+            Action::Up => "Up",
+            Action::Down => "Down",
+            Action::Left => "Left",
+            Action::Right => "Right",
+            Action::Fire => "Fire",
+            Action::Revive => "Revive",
+            Action::Reload => "Reload",
+            Action::StartGame => "Start game",
+        }
+    }
+
+    /// The bit of the action in the rollback input; `None` for actions outside of the simulation
+    pub fn input_bit(&self) -> Option<u8> {
+        match self {
+            Action::Up => Some(INPUT_UP),
+            Action::Down => Some(INPUT_DOWN),
+            Action::Left => Some(INPUT_LEFT),
+            Action::Right => Some(INPUT_RIGHT),
+            Action::Fire => Some(INPUT_FIRE),
+            Action::Revive => Some(INPUT_REVIVE),
+            Action::Reload => Some(INPUT_RELOAD),
+            Action::StartGame => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Binding {
+    Key(KeyCode),
+    Gamepad(GamepadButtonType),
+}
+
+impl Display for Binding {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Binding::Key(key) => write!(f, "{:?}", key),
+            Binding::Gamepad(button) => write!(f, "Pad {:?}", button),
+        }
+    }
+}
+
+/// Keys and gamepad buttons of all actions, stored in `controls.ron` (native) or local storage (wasm)
+#[derive(Resource, Clone, Serialize, Deserialize)]
+pub struct Controls {
+    bindings: Vec<(Action, Vec<Binding>)>,
+}
+
+impl Default for Controls {
+    fn default() -> Self {
+        use Binding::{Gamepad, Key};
+        Controls {
+            bindings: vec![
+                (
+                    Action::Up,
+                    vec![
+                        Key(KeyCode::W),
+                        Key(KeyCode::Up),
+                        Gamepad(GamepadButtonType::DPadUp),
+                    ],
+                ),
+                (
+                    Action::Down,
+                    vec![
+                        Key(KeyCode::S),
+                        Key(KeyCode::Down),
+                        Gamepad(GamepadButtonType::DPadDown),
+                    ],
+                ),
+                (
+                    Action::Left,
+                    vec![
+                        Key(KeyCode::A),
+                        Key(KeyCode::Left),
+                        Gamepad(GamepadButtonType::DPadLeft),
+                    ],
+                ),
+                (
+                    Action::Right,
+                    vec![
+                        Key(KeyCode::D),
+                        Key(KeyCode::Right),
+                        Gamepad(GamepadButtonType::DPadRight),
+                    ],
+                ),
+                (
+                    Action::Fire,
+                    vec![
+                        Key(KeyCode::Space),
+                        Gamepad(GamepadButtonType::South),
+                        Gamepad(GamepadButtonType::RightTrigger2),
+                    ],
+                ),
+                (
+                    Action::Revive,
+                    vec![Key(KeyCode::R), Gamepad(GamepadButtonType::North)],
+                ),
+                (
+                    Action::Reload,
+                    vec![Key(KeyCode::Q), Gamepad(GamepadButtonType::West)],
+                ),
+                (
+                    Action::StartGame,
+                    vec![Key(KeyCode::Return), Gamepad(GamepadButtonType::Start)],
+                ),
+            ],
+        }
+    }
+}
+
+impl Controls {
+    pub fn bindings(&self, action: Action) -> &[Binding] {
+        self.bindings
+            .iter()
+            .find(|(bound, _)| *bound == action)
+            .map(|(_, bindings)| bindings.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// The other action already using the binding
+    fn conflict(&self, action: Action, binding: Binding) -> Option<Action> {
+        self.bindings
+            .iter()
+            .find(|(bound, bindings)| *bound != action && bindings.contains(&binding))
+            .map(|(bound, _)| *bound)
+    }
+
+    /// Replaces the keyboard or the gamepad bindings of the action, depending on the new binding
+    fn rebind(&mut self, action: Action, binding: Binding) {
+        let Some((_, bindings)) = self.bindings.iter_mut().find(|(bound, _)| *bound == action)
+        else {
+            self.bindings.push((action, vec![binding]));
+            return;
+        };
+        bindings.retain(|bound| {
+            !matches!(
+                (bound, binding),
+                (Binding::Key(_), Binding::Key(_)) | (Binding::Gamepad(_), Binding::Gamepad(_))
+            )
+        });
+        bindings.push(binding);
+    }
+
+    /// Stored controls, with defaults for actions that weren't stored
+    fn load() -> Self {
+        let mut controls = Controls::default();
+        let Some(stored) = read_stored_controls() else {
+            return controls;
+        };
+        match ron::from_str::<Controls>(&stored) {
+            Ok(stored) => {
+                for (action, bindings) in stored.bindings {
+                    if let Some((_, default)) = controls
+                        .bindings
+                        .iter_mut()
+                        .find(|(bound, _)| *bound == action)
+                    {
+                        *default = bindings;
+                    }
+                }
+            }
+            Err(error) => warn!("Failed to read the stored controls: {}", error),
+        }
+        controls
+    }
+
+    fn save(&self) {
+        let serialized = match ron::ser::to_string_pretty(self, default()) {
+            Ok(serialized) => serialized,
+            Err(error) => {
+                warn!("Failed to serialize the controls: {}", error);
+                return;
+            }
+        };
+        if let Err(error) = store_controls(&serialized) {
+            warn!("Failed to store the controls: {}", error);
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn read_stored_controls() -> Option<String> {
+    std::fs::read_to_string(CONTROLS_FILE).ok()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn store_controls(controls: &str) -> Result<(), String> {
+    std::fs::write(CONTROLS_FILE, controls).map_err(|error| error.to_string())
+}
+
+#[cfg(target_arch = "wasm32")]
+fn read_stored_controls() -> Option<String> {
+    web_sys::window()?
+        .local_storage()
+        .ok()??
+        .get_item(CONTROLS_STORAGE_KEY)
+        .ok()?
+}
+
+#[cfg(target_arch = "wasm32")]
+fn store_controls(controls: &str) -> Result<(), String> {
+    web_sys::window()
+        .and_then(|window| window.local_storage().ok().flatten())
+        .ok_or_else(|| "no local storage".to_owned())?
+        .set_item(CONTROLS_STORAGE_KEY, controls)
+        .map_err(|error| format!("{:?}", error))
+}
+
+/// Keyboard and gamepad state read through the configured controls
+#[derive(SystemParam)]
+pub struct ActionInput<'w> {
+    controls: Res<'w, Controls>,
+    keys: Res<'w, Input<KeyCode>>,
+    gamepads: Res<'w, Gamepads>,
+    gamepad_buttons: Res<'w, Input<GamepadButton>>,
+}
+
+impl ActionInput<'_> {
+    pub fn pressed(&self, action: Action) -> bool {
+        self.controls
+            .bindings(action)
+            .iter()
+            .any(|binding| match binding {
+                Binding::Key(key) => self.keys.pressed(*key),
+                Binding::Gamepad(button) => {
+                    any_pressed(&self.gamepads, &self.gamepad_buttons, *button)
+                }
+            })
+    }
+
+    pub fn just_pressed(&self, action: Action) -> bool {
+        self.controls
+            .bindings(action)
+            .iter()
+            .any(|binding| match binding {
+                Binding::Key(key) => self.keys.just_pressed(*key),
+                Binding::Gamepad(button) => {
+                    any_just_pressed(&self.gamepads, &self.gamepad_buttons, *button)
+                }
+            })
+    }
+
+    /// Input bits of all pressed actions
+    pub fn input_bits(&self) -> u8 {
+        self.bits(|binding| match binding {
+            Binding::Key(key) => self.keys.pressed(*key),
+            Binding::Gamepad(button) => any_pressed(&self.gamepads, &self.gamepad_buttons, *button),
+        })
+    }
+
+    /// Input bits of the keyboard bindings, ignoring the given keys
+    pub fn keyboard_bits(&self, except: &[KeyCode]) -> u8 {
+        self.bits(|binding| match binding {
+            Binding::Key(key) => !except.contains(key) && self.keys.pressed(*key),
+            Binding::Gamepad(_) => false,
+        })
+    }
+
+    /// Input bits of the gamepad bindings on one gamepad
+    pub fn gamepad_bits(&self, gamepad: Gamepad) -> u8 {
+        self.bits(|binding| match binding {
+            Binding::Key(_) => false,
+            Binding::Gamepad(button) => self
+                .gamepad_buttons
+                .pressed(GamepadButton::new(gamepad, *button)),
+        })
+    }
+
+    pub fn keys(&self) -> &Input<KeyCode> {
+        &self.keys
+    }
+
+    fn bits(&self, pressed: impl Fn(&Binding) -> bool) -> u8 {
+        Action::ALL
+            .iter()
+            .filter(|action| self.controls.bindings(**action).iter().any(&pressed))
+            .filter_map(Action::input_bit)
+            .fold(0, |bits, bit| bits | bit)
+    }
+}
+
+/// The action waiting for a new key or button
+#[derive(Default, Resource)]
+struct Rebinding(Option<Action>);
+
+#[derive(Component, Clone, Copy, PartialEq, Eq)]
+enum ControlsScreenButton {
+    Rebind(Action),
+    Reset,
+    Back,
+}
+
+#[derive(Component)]
+struct BindingText(Action);
+
+#[derive(Component)]
+struct ControlsMessage;
+
+#[derive(Component)]
+struct ControlsUi;
+
+fn setup_controls_screen(
+    mut commands: Commands,
+    font_assets: Res<FontAssets>,
+    button_colors: Res<ButtonColors>,
+) {
+    let text_style = TextStyle {
+        font: font_assets.fira_sans.clone(),
+        font_size: 30.0,
+        color: Color::rgb(0.9, 0.9, 0.9),
+    };
+    let button_style = |width: f32| Style {
+        size: Size::new(Val::Px(width), Val::Px(40.0)),
+        margin: UiRect::all(Val::Px(5.)),
+        justify_content: JustifyContent::Center,
+        align_items: AlignItems::Center,
+        ..Default::default()
+    };
+    commands
+        .spawn(NodeBundle {
+            style: Style {
+                size: Size {
+                    width: Val::Percent(100.),
+                    height: Val::Percent(100.),
+                },
+                flex_direction: FlexDirection::Column,
+                justify_content: JustifyContent::Center,
+                align_items: AlignItems::Center,
+                ..default()
+            },
+            background_color: BackgroundColor(Color::NONE),
+            ..default()
+        })
+        .insert(ControlsUi)
+        .with_children(|parent| {
+            for action in Action::ALL {
+                parent
+                    .spawn(NodeBundle {
+                        style: Style {
+                            flex_direction: FlexDirection::Row,
+                            align_items: AlignItems::Center,
+                            ..default()
+                        },
+                        background_color: BackgroundColor(Color::NONE),
+                        ..default()
+                    })
+                    .with_children(|parent| {
+                        parent.spawn(TextBundle {
+                            style: Style {
+                                size: Size::new(Val::Px(160.), Val::Auto),
+                                ..default()
+                            },
+                            text: Text::from_section(action.label(), text_style.clone()),
+                            ..default()
+                        });
+                        parent
+                            .spawn(ButtonBundle {
+                                style: button_style(520.),
+                                background_color: button_colors.normal.into(),
+                                ..default()
+                            })
+                            .insert(ControlsScreenButton::Rebind(action))
+                            .with_children(|parent| {
+                                parent
+                                    .spawn(TextBundle {
+                                        text: Text::from_section("", text_style.clone()),
+                                        ..default()
+                                    })
+                                    .insert(BindingText(action));
+                            });
+                    });
+            }
+            parent
+                .spawn(TextBundle {
+                    style: Style {
+                        margin: UiRect::all(Val::Px(10.)),
+                        ..default()
+                    },
+                    text: Text::from_section("", text_style.clone())
+                        .with_alignment(TextAlignment::Center),
+                    ..default()
+                })
+                .insert(ControlsMessage);
+            parent.spawn(TextBundle {
+                style: Style {
+                    margin: UiRect::all(Val::Px(10.)),
+                    ..default()
+                },
+                text: Text::from_section(
+                    "A new key replaces the keys of the action, a new button its buttons.\nEsc cancels and can't be bound.",
+                    TextStyle {
+                        font_size: 20.0,
+                        ..text_style.clone()
+                    },
+                )
+                .with_alignment(TextAlignment::Center),
+                ..default()
+            });
+            for (button, label) in [
+                (ControlsScreenButton::Reset, "Reset to defaults"),
+                (ControlsScreenButton::Back, "Back"),
+            ] {
+                parent
+                    .spawn(ButtonBundle {
+                        style: button_style(300.),
+                        background_color: button_colors.normal.into(),
+                        ..default()
+                    })
+                    .insert(button)
+                    .with_children(|parent| {
+                        parent.spawn(TextBundle {
+                            text: Text::from_section(label, text_style.clone())
+                                .with_alignment(TextAlignment::Center),
+                            ..default()
+                        });
+                    });
+            }
+        });
+}
+
+fn click_controls_button(
+    button_colors: Res<ButtonColors>,
+    mut controls: ResMut<Controls>,
+    mut rebinding: ResMut<Rebinding>,
+    mut state: ResMut<NextState<GameState>>,
+    mut interaction_query: Query<
+        (&Interaction, &mut BackgroundColor, &ControlsScreenButton),
+        Changed<Interaction>,
+    >,
+) {
+    for (interaction, mut color, button) in &mut interaction_query {
+        match *interaction {
+            Interaction::Clicked => match button {
+                ControlsScreenButton::Rebind(action) => rebinding.0 = Some(*action),
+                ControlsScreenButton::Reset => {
+                    *controls = Controls::default();
+                    controls.save();
+                    rebinding.0 = None;
+                }
+                ControlsScreenButton::Back => state.set(GameState::Menu),
+            },
+            Interaction::Hovered => {
+                *color = button_colors.hovered.into();
+            }
+            Interaction::None => {
+                *color = button_colors.normal.into();
+            }
+        }
+    }
+}
+
+/// Bind the next key or gamepad button to the action waiting for it; escape cancels
+fn capture_binding(
+    keys: Res<Input<KeyCode>>,
+    mut gamepad_buttons: ResMut<Input<GamepadButton>>,
+    mut rebinding: ResMut<Rebinding>,
+    mut controls: ResMut<Controls>,
+    mut message: Query<&mut Text, With<ControlsMessage>>,
+) {
+    // the press that started the rebinding doesn't count
+    if rebinding.is_changed() {
+        return;
+    }
+    let Some(action) = rebinding.0 else {
+        return;
+    };
+    if keys.just_pressed(KeyCode::Escape) {
+        rebinding.0 = None;
+        return;
+    }
+    let binding = keys
+        .get_just_pressed()
+        .next()
+        .map(|key| Binding::Key(*key))
+        .or_else(|| {
+            let button = *gamepad_buttons.get_just_pressed().next()?;
+            // the button shouldn't also press whatever the gamepad has selected
+            gamepad_buttons.reset(button);
+            Some(Binding::Gamepad(button.button_type))
+        });
+    let Some(binding) = binding else {
+        return;
+    };
+    let Ok(mut message) = message.get_single_mut() else {
+        return;
+    };
+    if let Some(conflict) = controls.conflict(action, binding) {
+        message.sections[0].value = format!(
+            "{} is already used for {}, pick another one",
+            binding,
+            conflict.label()
+        );
+        return;
+    }
+    controls.rebind(action, binding);
+    controls.save();
+    message.sections[0].value = "".to_owned();
+    rebinding.0 = None;
+}
+
+fn update_controls_screen(
+    controls: Res<Controls>,
+    rebinding: Res<Rebinding>,
+    mut texts: Query<(&mut Text, &BindingText)>,
+    new_texts: Query<(), Added<BindingText>>,
+) {
+    if !controls.is_changed() && !rebinding.is_changed() && new_texts.is_empty() {
+        return;
+    }
+    for (mut text, BindingText(action)) in &mut texts {
+        text.sections[0].value = if rebinding.0 == Some(*action) {
+            "Press a key or button (Esc cancels)".to_owned()
+        } else {
+            controls
+                .bindings(*action)
+                .iter()
+                .map(Binding::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+    }
+}
+
+fn cleanup_controls_screen(
+    mut commands: Commands,
+    mut rebinding: ResMut<Rebinding>,
+    ui: Query<Entity, With<ControlsUi>>,
+) {
+    rebinding.0 = None;
+    for entity in &ui {
+        commands.entity(entity).despawn_recursive();
+    }
+}
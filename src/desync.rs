@@ -0,0 +1,341 @@
+use crate::checksum::world_checksums;
+use crate::enemies::Enemy;
+use crate::matchmaking::{
+    receive_game_packets, GamePacket, GameSocket, CHECKSUMS, DESYNC, GAME_CHANNEL,
+};
+use crate::networking::{end_game, in_round, Dead, GgrsConfig, SessionFrame};
+use crate::players::{Health, Player};
+use crate::{Bullet, GameState};
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use bevy_ggrs::{GGRSSchedule, Rollback, Session};
+use ggrs::PlayerType;
+use matchbox_socket::PeerId;
+use std::fmt::Write;
+
+/// Number of frames to keep local checksums for, waiting for the checksums of slower peers
+const CHECKSUM_HISTORY: u32 = 600;
+/// Number of frames to keep the rollback state for, to dump it if the frame turns out to desync
+const SNAPSHOT_HISTORY: u32 = 180;
+
+pub struct DesyncPlugin;
+
+impl Plugin for DesyncPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<LocalChecksums>()
+            .init_resource::<RemoteChecksums>()
+            .add_system(reset_checksums.in_schedule(OnEnter(GameState::Matchmaking)))
+            .add_system(
+                record_checksums
+                    .after(end_game)
+                    .run_if(in_round)
+                    .in_schedule(GGRSSchedule),
+            )
+            .add_systems((
+                exchange_checksums
+                    .after(receive_game_packets)
+                    .run_if(in_state(GameState::InGame)),
+                dump_world_state
+                    .after(exchange_checksums)
+                    .run_if(resource_added::<Desync>()),
+            ));
+    }
+}
+
+/// The first confirmed frame in which a peer's checksums disagreed with ours.
+///
+/// Whoever notices it first tells all peers with a DESYNC packet, so every peer dumps its world of that frame.
+#[derive(Resource)]
+pub struct Desync {
+    pub frame: u32,
+    pub peer: PeerId,
+    local: Vec<(&'static str, u64)>,
+    remote: Vec<u64>,
+    /// Local rollback state in the desynced frame, if it was still kept
+    snapshot: Option<Vec<EntityState>>,
+}
+
+#[derive(Clone)]
+enum EntityKind {
+    Player(usize),
+    Enemy { last_attack: u32 },
+    Bullet { damage: f64 },
+    Other,
+}
+
+/// The parts of a rolled back entity written to a desync dump
+#[derive(Clone)]
+struct EntityState {
+    kind: EntityKind,
+    translation: Vec3,
+    rotation: Quat,
+    health: Option<f64>,
+    dead: bool,
+}
+
+/// Checksums of the latest simulation of each [`SessionFrame`].
+///
+/// Re-simulations after a rollback overwrite earlier entries,
+/// so everything older than the prediction window holds the checksums of confirmed frames.
+#[derive(Default, Resource)]
+struct LocalChecksums {
+    frames: HashMap<u32, Vec<(&'static str, u64)>>,
+    snapshots: HashMap<u32, Vec<EntityState>>,
+    sent_until: u32,
+}
+
+#[derive(Default, Resource)]
+struct RemoteChecksums(Vec<(PeerId, u32, Vec<u64>)>);
+
+/// Frames start from zero again in a new session
+fn reset_checksums(mut commands: Commands) {
+    commands.insert_resource(LocalChecksums::default());
+    commands.insert_resource(RemoteChecksums::default());
+}
+
+fn record_checksums(world: &mut World) {
+    let frame = world.resource::<SessionFrame>().0;
+    let checksums = world_checksums(world);
+    let snapshot = snapshot_entities(world);
+    let mut local = world.resource_mut::<LocalChecksums>();
+    local.frames.insert(frame, checksums);
+    local
+        .frames
+        .retain(|recorded, _| recorded.wrapping_add(CHECKSUM_HISTORY) > frame);
+    local.snapshots.insert(frame, snapshot);
+    local
+        .snapshots
+        .retain(|recorded, _| recorded.wrapping_add(SNAPSHOT_HISTORY) > frame);
+}
+
+fn snapshot_entities(world: &mut World) -> Vec<EntityState> {
+    world
+        .query_filtered::<(
+            &Transform,
+            Option<&Health>,
+            Option<&Player>,
+            Option<&Enemy>,
+            Option<&Bullet>,
+            Option<&Dead>,
+        ), With<Rollback>>()
+        .iter(world)
+        .map(
+            |(transform, health, player, enemy, bullet, dead)| EntityState {
+                kind: match (player, enemy, bullet) {
+                    (Some(player), _, _) => EntityKind::Player(player.handle),
+                    (_, Some(enemy), _) => EntityKind::Enemy {
+                        last_attack: enemy.last_attack,
+                    },
+                    (_, _, Some(bullet)) => EntityKind::Bullet {
+                        damage: bullet.damage,
+                    },
+                    _ => EntityKind::Other,
+                },
+                translation: transform.translation,
+                rotation: transform.rotation,
+                health: health.map(|health| health.current),
+                dead: dead.is_some(),
+            },
+        )
+        .collect()
+}
+
+fn exchange_checksums(
+    mut commands: Commands,
+    mut socket: ResMut<GameSocket>,
+    mut local: ResMut<LocalChecksums>,
+    mut remote: ResMut<RemoteChecksums>,
+    mut packets: EventReader<GamePacket>,
+    session: Option<Res<Session<GgrsConfig>>>,
+    desync: Option<Res<Desync>>,
+) {
+    let GameSocket(Some(socket)) = socket.as_mut() else {
+        return;
+    };
+    let Some(Session::P2PSession(session)) = session.as_deref() else {
+        return;
+    };
+    // no frame has been confirmed yet while this is negative; session frames count GGRS frames
+    let Ok(confirmed) = u32::try_from(session.confirmed_frame()) else {
+        return;
+    };
+
+    let peers: Vec<PeerId> = socket
+        .players()
+        .into_iter()
+        .filter_map(|player| match player {
+            PlayerType::Remote(id) => Some(id),
+            _ => None,
+        })
+        .collect();
+    let first_unsent = local
+        .sent_until
+        .max(confirmed.saturating_sub(CHECKSUM_HISTORY))
+        + 1;
+    for frame in first_unsent..=confirmed {
+        let Some(checksums) = local.frames.get(&frame) else {
+            continue;
+        };
+        let mut packet = vec![CHECKSUMS];
+        packet.extend_from_slice(&frame.to_le_bytes());
+        for (_, checksum) in checksums {
+            packet.extend_from_slice(&checksum.to_le_bytes());
+        }
+        for peer in &peers {
+            socket
+                .channel(GAME_CHANNEL)
+                .send(packet.clone().into_boxed_slice(), *peer);
+        }
+    }
+    local.sent_until = local.sent_until.max(confirmed);
+
+    let mut desync_found = desync.is_some();
+    for GamePacket { peer, packet } in packets.iter() {
+        if packet.len() < 5 {
+            continue;
+        }
+        let frame = u32::from_le_bytes(packet[1..5].try_into().unwrap());
+        match packet[0] {
+            CHECKSUMS => {
+                let checksums = packet[5..]
+                    .chunks_exact(8)
+                    .map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+                    .collect();
+                remote.0.push((*peer, frame, checksums));
+            }
+            // the peer found a mismatch first, dump our side of it too
+            DESYNC if !desync_found => {
+                error!("Peer {} reported a desync in frame {}", peer.0, frame);
+                desync_found = true;
+                let remote_checksums = remote
+                    .0
+                    .iter()
+                    .find(|(from, remote_frame, _)| from == peer && *remote_frame == frame)
+                    .map(|(.., checksums)| checksums.clone())
+                    .unwrap_or_default();
+                commands.insert_resource(Desync {
+                    frame,
+                    peer: *peer,
+                    local: local.frames.get(&frame).cloned().unwrap_or_default(),
+                    remote: remote_checksums,
+                    snapshot: local.snapshots.get(&frame).cloned(),
+                });
+            }
+            _ => (),
+        }
+    }
+
+    remote.0.retain(|(peer, frame, checksums)| {
+        if *frame > confirmed {
+            return true;
+        }
+        let Some(local_checksums) = local.frames.get(frame) else {
+            return false;
+        };
+        let agree = local_checksums.len() == checksums.len()
+            && local_checksums
+                .iter()
+                .zip(checksums.iter())
+                .all(|((_, local), remote)| local == remote);
+        if !agree && !desync_found {
+            error!("Desync with peer {} in frame {}", peer.0, frame);
+            desync_found = true;
+            let mut report = vec![DESYNC];
+            report.extend_from_slice(&frame.to_le_bytes());
+            for peer in &peers {
+                socket
+                    .channel(GAME_CHANNEL)
+                    .send(report.clone().into_boxed_slice(), *peer);
+            }
+            commands.insert_resource(Desync {
+                frame: *frame,
+                peer: *peer,
+                local: local_checksums.clone(),
+                remote: checksums.clone(),
+                snapshot: local.snapshots.get(frame).cloned(),
+            });
+        }
+        false
+    });
+}
+
+/// Write the per-type checksums of both peers and the local rollback state of the desynced frame to disk.
+///
+/// Every peer notices the desync and dumps its own world, so the files of all players can be compared.
+fn dump_world_state(world: &mut World) {
+    let mut dump = String::new();
+    let desync = world.resource::<Desync>();
+    let frame = desync.frame;
+    writeln!(
+        dump,
+        "Checksums of frame {} disagree with peer {}",
+        frame, desync.peer.0
+    )
+    .unwrap();
+    if desync.local.is_empty() || desync.remote.is_empty() {
+        writeln!(
+            dump,
+            "The checksums of frame {} are not known on both sides",
+            frame
+        )
+        .unwrap();
+    }
+    for ((name, local), remote) in desync.local.iter().zip(desync.remote.iter()) {
+        writeln!(
+            dump,
+            "{}: local {:016x}, remote {:016x}{}",
+            name,
+            local,
+            remote,
+            if local == remote { "" } else { " <- mismatch" }
+        )
+        .unwrap();
+    }
+    match &desync.snapshot {
+        Some(snapshot) => {
+            writeln!(dump, "\nLocal rollback state in frame {}:", frame).unwrap();
+            let mut entities: Vec<String> = snapshot.iter().map(describe).collect();
+            entities.sort();
+            dump.push_str(&entities.join("\n"));
+        }
+        None => writeln!(
+            dump,
+            "\nThe rollback state of frame {} is no longer kept",
+            frame
+        )
+        .unwrap(),
+    }
+
+    let local_id = world
+        .resource::<GameSocket>()
+        .0
+        .as_ref()
+        .and_then(|socket| socket.id())
+        .map(|id| id.0.to_string())
+        .unwrap_or_default();
+    let file = format!("desync_frame{}_{}.txt", frame, local_id);
+    #[cfg(not(target_arch = "wasm32"))]
+    match std::fs::write(&file, dump) {
+        Ok(()) => info!("Dumped world state to {}", file),
+        Err(error) => warn!("Failed to write {}: {}", file, error),
+    }
+    #[cfg(target_arch = "wasm32")]
+    info!("{}:\n{}", file, dump);
+}
+
+fn describe(entity: &EntityState) -> String {
+    let kind = match entity.kind {
+        EntityKind::Player(handle) => format!("player {}", handle),
+        EntityKind::Enemy { last_attack } => format!("enemy (last attack {})", last_attack),
+        EntityKind::Bullet { damage } => format!("bullet ({} damage)", damage),
+        EntityKind::Other => "other".to_owned(),
+    };
+    format!(
+        "{} at {:?} rotated {:?}, health {:?}{}",
+        kind,
+        entity.translation,
+        entity.rotation,
+        entity.health,
+        if entity.dead { ", dead" } else { "" }
+    )
+}
@@ -1,23 +1,96 @@
+use crate::loading::ImageAssets;
+use crate::map::Obstacles;
+use crate::matchmaking::Seed;
 use crate::networking::{Dead, SeedFrame};
-use crate::players::{Health, Player};
-use crate::{Bullet, Score, BULLET_RADIUS, ENEMY_RADIUS, PLAYER_RADIUS};
+use crate::pickups::drop_pickup;
+use crate::players::{Health, MoveDir, Player};
+use crate::spatial::{GridEntry, SpatialGrid};
+use crate::{Bullet, GameState, Score, BULLET_RADIUS, PLAYER_RADIUS};
 use bevy::math::Vec3Swizzles;
 use bevy::prelude::*;
+use bevy_ggrs::{Rollback, RollbackIdProvider};
 
 pub struct EnemiesPlugin;
 
 impl Plugin for EnemiesPlugin {
     fn build(&self, app: &mut App) {
-        app.init_resource::<RollbackSafeEvents>();
+        app.init_resource::<RollbackSafeEvents>()
+            .add_system(flash_winding_up_enemies.run_if(in_state(GameState::InGame)));
     }
 }
 
-#[derive(Component)]
+const SPIT_RADIUS: f32 = 0.1;
+/// How far past touching a player enemies can hit it
+const MELEE_RANGE: f32 = 0.1;
+/// Share of the overlap between two enemies resolved per frame; lower values make hordes squishier
+const SEPARATION_STIFFNESS: f32 = 0.5;
+
+#[derive(Component, Reflect, Default)]
 pub struct Enemy {
     pub damage: f64,
     pub speed: f32,
     pub last_attack: u32,
     pub attack_cooldown: u32,
+    pub behavior: Behavior,
+    pub action: EnemyAction,
+    /// Swarmers flank on the left of their target with 1 and on the right with -1
+    pub side: f32,
+    /// Size of the hitbox, larger than `ENEMY_RADIUS` for bosses
+    pub radius: f32,
+}
+
+impl Enemy {
+    fn can_attack(&self, frame: u32) -> bool {
+        self.last_attack + self.attack_cooldown < frame
+    }
+
+    /// Distance between the centers of this enemy and a player it can hit
+    fn hit_distance(&self) -> f32 {
+        self.radius + PLAYER_RADIUS + MELEE_RANGE
+    }
+}
+
+/// How an enemy moves and attacks; chosen per enemy type in `enemies.my-assets`
+#[derive(serde::Deserialize, Reflect, FromReflect, Default, Clone, Copy, PartialEq, Debug)]
+pub enum Behavior {
+    /// Walk straight at the closest player and hit it
+    #[default]
+    Chase,
+    /// Keep within `range` of the closest player and spit projectiles at it
+    Spitter { range: f32, projectile_speed: f32 },
+    /// Stand still for `windup` frames once within `range`, then dash in a straight line
+    Charger {
+        range: f32,
+        windup: u32,
+        dash_speed: f32,
+        dash_frames: u32,
+    },
+    /// Circle around to the side of the closest player until within `flank_distance`
+    Swarmer { flank_distance: f32 },
+}
+
+/// What a charger is doing right now; the other behaviors always approach
+#[derive(Reflect, FromReflect, Default, Clone, Copy, PartialEq, Debug)]
+pub enum EnemyAction {
+    #[default]
+    Approach,
+    WindUp {
+        until: u32,
+        direction: Vec2,
+    },
+    Dash {
+        until: u32,
+        direction: Vec2,
+    },
+}
+
+/// Projectile of a spitter, hurting the first player it touches
+#[derive(Component, Reflect, Default)]
+pub struct Spit {
+    pub damage: f64,
+    pub speed: f32,
+    /// Remaining distance before the spit vanishes
+    pub range: f32,
 }
 
 pub struct SafeEvent {
@@ -43,6 +116,7 @@ pub enum FvzEvent {
     Lost,
     Pew,
     Revive,
+    Pickup,
 }
 
 #[derive(Default, Resource)]
@@ -50,28 +124,55 @@ pub struct RollbackSafeEvents(pub(crate) Vec<SafeEvent>);
 
 pub fn kill_enemies(
     mut commands: Commands,
+    seed: Res<Seed>,
+    seed_frame: Res<SeedFrame>,
+    mut rollback_id_provider: ResMut<RollbackIdProvider>,
     mut score: ResMut<Score>,
-    mut enemy_query: Query<(Entity, &Transform, &mut Health), (With<Enemy>, Without<Bullet>)>,
-    mut bullet_query: Query<(Entity, &Transform, &mut Bullet)>,
+    mut enemy_query: Query<(Entity, &Rollback, &Transform, &mut Health, &Enemy), Without<Bullet>>,
+    mut bullet_query: Query<(Entity, &Rollback, &Transform, &mut Bullet)>,
     mut rollback_safe_events: ResMut<RollbackSafeEvents>,
+    mut grid: Local<SpatialGrid>,
 ) {
-    'bullets: for (bullet_entity, bullet_transform, mut bullet) in bullet_query.iter_mut() {
-        if bullet.is_used_up() {
+    grid.rebuild(
+        enemy_query
+            .iter()
+            .map(|(entity, rollback, transform, _, enemy)| GridEntry {
+                id: rollback.id(),
+                entity,
+                position: transform.translation.xy(),
+                radius: enemy.radius,
+            }),
+    );
+    // piercing bullets and the score depend on the order of hits
+    let mut bullets: Vec<_> = bullet_query.iter_mut().collect();
+    bullets.sort_unstable_by_key(|(_, rollback, ..)| rollback.id());
+
+    'bullets: for (bullet_entity, _, bullet_transform, mut bullet) in bullets {
+        if bullet.is_used_up() || bullet.is_spent() {
             continue;
         }
-        for (enemy, enemy_transform, mut health) in enemy_query.iter_mut() {
-            let distance = Vec2::distance(
-                enemy_transform.translation.xy(),
-                bullet_transform.translation.xy(),
-            );
-            if distance < ENEMY_RADIUS + BULLET_RADIUS && bullet.hit(enemy) {
+        let position = bullet_transform.translation.xy();
+        for index in grid.near(position, BULLET_RADIUS) {
+            let entry = grid.get(index);
+            let distance = entry.position.distance(position);
+            if distance < entry.radius + BULLET_RADIUS && bullet.hit(entry.entity) {
+                let (enemy, _, enemy_transform, mut health, _) =
+                    enemy_query.get_mut(entry.entity).unwrap();
                 score.0 += bullet.damage;
+                let was_alive = health.current > 0.;
                 health.current = (health.current - bullet.damage).max(0.);
-                if health.current <= 0. {
+                if was_alive && health.current <= 0. {
                     rollback_safe_events.0.push(SafeEvent::new(
                         FvzEvent::EnemyFall,
                         enemy.index().wrapping_add(enemy.generation()),
                     ));
+                    drop_pickup(
+                        &mut commands,
+                        &mut rollback_id_provider,
+                        &seed,
+                        &seed_frame,
+                        enemy_transform.translation.xy(),
+                    );
                     commands.entity(enemy).despawn_recursive();
                 }
             }
@@ -83,8 +184,24 @@ pub fn kill_enemies(
     }
 }
 
+/// A linear scan, as there are only a few players and the closest one may be anywhere in the arena
+fn closest<'a, T>(
+    position: Vec2,
+    players: impl Iterator<Item = (T, &'a Transform)>,
+) -> Option<(T, Vec2)> {
+    players
+        .map(|(item, transform)| (item, transform.translation.xy()))
+        .reduce(|closest, current| {
+            if closest.1.distance_squared(position) > current.1.distance_squared(position) {
+                current
+            } else {
+                closest
+            }
+        })
+}
+
 pub fn move_enemies(
-    mut enemy_query: Query<(Entity, &mut Transform, &mut Enemy)>,
+    mut enemy_query: Query<(Entity, &Rollback, &mut Transform, &mut Enemy)>,
     mut player_query: Query<
         (Entity, &Transform, &mut Health),
         (Without<Enemy>, With<Player>, Without<Dead>),
@@ -92,41 +209,309 @@ pub fn move_enemies(
     seed_frame: Res<SeedFrame>,
     mut rollback_safe_events: ResMut<RollbackSafeEvents>,
 ) {
-    for (enemy_entity, mut transform, mut enemy) in &mut enemy_query {
-        if let Some((player, closest_position, mut player_health)) =
-            player_query.iter_mut().reduce(|closest, current| {
-                if closest
-                    .1
-                    .translation
-                    .distance_squared(transform.translation)
-                    > current
-                        .1
-                        .translation
-                        .distance_squared(transform.translation)
-                {
-                    current
+    let frame = seed_frame.0;
+    // the health of players depends on the order of hits
+    let mut enemies: Vec<_> = enemy_query.iter_mut().collect();
+    enemies.sort_unstable_by_key(|(_, rollback, ..)| rollback.id());
+
+    for (enemy_entity, _, mut transform, mut enemy) in enemies {
+        let position = transform.translation.xy();
+        let Some((player, player_position)) = closest(
+            position,
+            player_query
+                .iter()
+                .map(|(player, transform, _)| (player, transform)),
+        ) else {
+            continue;
+        };
+        let distance = player_position - position;
+        let direction = distance.normalize_or_zero();
+
+        let (move_delta, hit_distance) = match (enemy.action, enemy.behavior) {
+            (EnemyAction::WindUp { until, direction }, Behavior::Charger { dash_frames, .. }) => {
+                if frame >= until {
+                    enemy.action = EnemyAction::Dash {
+                        until: frame + dash_frames,
+                        direction,
+                    };
+                }
+                (Vec2::ZERO, None)
+            }
+            (EnemyAction::Dash { until, direction }, Behavior::Charger { dash_speed, .. }) => {
+                if frame >= until {
+                    enemy.action = EnemyAction::Approach;
+                    // the next charge waits for the attack cooldown
+                    enemy.last_attack = frame;
+                }
+                (direction * dash_speed, Some(enemy.hit_distance()))
+            }
+            (_, Behavior::Charger { range, windup, .. })
+                if distance.length() < range && enemy.can_attack(frame) =>
+            {
+                enemy.action = EnemyAction::WindUp {
+                    until: frame + windup,
+                    direction,
+                };
+                (Vec2::ZERO, None)
+            }
+            (_, Behavior::Spitter { range, .. }) => {
+                // spitting is done by `spit_at_players`
+                if distance.length() > range {
+                    (direction * enemy.speed, None)
+                } else if distance.length() < range / 2. {
+                    (-direction * enemy.speed, None)
                 } else {
-                    closest
+                    (Vec2::ZERO, None)
                 }
-            })
-        {
-            let distance = closest_position.translation.xy() - transform.translation.xy();
-            if distance.length() < PLAYER_RADIUS / 4. {
-                if enemy.last_attack + enemy.attack_cooldown < seed_frame.0 {
+            }
+            (_, Behavior::Swarmer { flank_distance }) if distance.length() > flank_distance => {
+                let flank = player_position + direction.perp() * enemy.side * flank_distance;
+                let move_delta = (flank - position).normalize_or_zero() * enemy.speed;
+                (move_delta, None)
+            }
+            _ => (direction * enemy.speed, Some(enemy.hit_distance())),
+        };
+
+        if let Some(hit_distance) = hit_distance {
+            if distance.length() < hit_distance {
+                if enemy.can_attack(frame) {
                     rollback_safe_events.0.push(SafeEvent::new(
                         FvzEvent::PlayerHit,
                         (3 * player.index()).wrapping_add(enemy_entity.index()),
                     ));
 
-                    enemy.last_attack = seed_frame.0;
+                    enemy.last_attack = frame;
+                    let (_, _, mut player_health) = player_query.get_mut(player).unwrap();
                     player_health.current -= enemy.damage;
                 }
-                continue;
+                if enemy.action == EnemyAction::Approach {
+                    continue;
+                }
+            }
+        }
+
+        transform.translation.x += move_delta.x;
+        transform.translation.y += move_delta.y;
+    }
+}
+
+/// Pushes overlapping enemies apart, out of the players they walk into and out of obstacles.
+///
+/// All pushes are computed from the positions at the start of the step, in rollback id order,
+/// so the result doesn't depend on query order and is identical on every peer.
+pub fn separate_enemies(
+    mut enemy_query: Query<(Entity, &Rollback, &mut Transform, &Enemy)>,
+    player_query: Query<(&Player, &Transform), (Without<Dead>, Without<Enemy>)>,
+    obstacles: Res<Obstacles>,
+    mut grid: Local<SpatialGrid>,
+) {
+    grid.rebuild(
+        enemy_query
+            .iter()
+            .map(|(entity, rollback, transform, enemy)| GridEntry {
+                id: rollback.id(),
+                entity,
+                position: transform.translation.xy(),
+                radius: enemy.radius,
+            }),
+    );
+    let mut players: Vec<(usize, Vec2)> = player_query
+        .iter()
+        .map(|(player, transform)| (player.handle, transform.translation.xy()))
+        .collect();
+    players.sort_unstable_by_key(|(handle, _)| *handle);
+
+    let pushes: Vec<Vec2> = grid
+        .entries()
+        .iter()
+        .enumerate()
+        .map(|(index, entry)| {
+            let mut push = Vec2::ZERO;
+            for other_index in grid.near(entry.position, entry.radius) {
+                if other_index == index {
+                    continue;
+                }
+                let other = grid.get(other_index);
+                // enemies on the same spot, e.g. spawned together, split along x
+                let fallback = if index < other_index {
+                    -Vec2::X
+                } else {
+                    Vec2::X
+                };
+                // each of the two resolves half of the overlap
+                push += separation(
+                    entry.position,
+                    other.position,
+                    entry.radius + other.radius,
+                    fallback,
+                ) * SEPARATION_STIFFNESS
+                    / 2.;
+            }
+            for &(_, player) in &players {
+                push += separation(
+                    entry.position,
+                    player,
+                    entry.radius + PLAYER_RADIUS,
+                    Vec2::X,
+                );
+            }
+            push
+        })
+        .collect();
+
+    for (_, rollback, mut transform, _) in &mut enemy_query {
+        let Some(index) = grid.index_of(rollback.id()) else {
+            continue;
+        };
+        let entry = grid.get(index);
+        // obstacles win over other enemies and players, nobody pushes an enemy into a wall
+        let position = obstacles.push_out(entry.position + pushes[index], entry.radius);
+        if position != entry.position {
+            transform.translation.x = position.x;
+            transform.translation.y = position.y;
+        }
+    }
+}
+
+/// Offset moving `position` to `min_distance` away from `other`, zero if already that far apart
+fn separation(position: Vec2, other: Vec2, min_distance: f32, fallback: Vec2) -> Vec2 {
+    let offset = position - other;
+    let distance = offset.length();
+    if distance >= min_distance {
+        return Vec2::ZERO;
+    }
+    let direction = if distance > 0. {
+        offset / distance
+    } else {
+        fallback
+    };
+    direction * (min_distance - distance)
+}
+
+/// Spitters in range of a player shoot at it whenever their attack cooldown is over
+pub fn spit_at_players(
+    mut commands: Commands,
+    mut rip: ResMut<RollbackIdProvider>,
+    images: Res<ImageAssets>,
+    seed_frame: Res<SeedFrame>,
+    mut enemy_query: Query<(&Transform, &mut Enemy)>,
+    player_query: Query<&Transform, (With<Player>, Without<Dead>, Without<Enemy>)>,
+) {
+    for (transform, mut enemy) in &mut enemy_query {
+        let Behavior::Spitter {
+            range,
+            projectile_speed,
+        } = enemy.behavior
+        else {
+            continue;
+        };
+        let position = transform.translation.xy();
+        let Some((_, player_position)) = closest(
+            position,
+            player_query.iter().map(|transform| ((), transform)),
+        ) else {
+            continue;
+        };
+        if player_position.distance(position) > range || !enemy.can_attack(seed_frame.0) {
+            continue;
+        }
+        enemy.last_attack = seed_frame.0;
+        spawn_spit(
+            &mut commands,
+            &mut rip,
+            &images,
+            position,
+            (player_position - position).normalize_or_zero(),
+            Spit {
+                damage: enemy.damage,
+                speed: projectile_speed,
+                range: range * 1.5,
+            },
+        );
+    }
+}
+
+pub fn spawn_spit(
+    commands: &mut Commands,
+    rip: &mut RollbackIdProvider,
+    images: &ImageAssets,
+    position: Vec2,
+    direction: Vec2,
+    spit: Spit,
+) {
+    commands
+        .spawn(SpriteBundle {
+            transform: Transform::from_translation(position.extend(200.)),
+            texture: images.bullet.clone(),
+            sprite: Sprite {
+                color: Color::rgb(0.4, 0.9, 0.2),
+                custom_size: Some(Vec2::splat(2. * SPIT_RADIUS)),
+                ..default()
+            },
+            ..default()
+        })
+        .insert(MoveDir(direction))
+        .insert(spit)
+        .insert(Rollback::new(rip.next_id()));
+}
+
+pub fn move_spit(
+    mut commands: Commands,
+    mut spit_query: Query<(Entity, &Rollback, &mut Transform, &MoveDir, &mut Spit)>,
+    mut player_query: Query<
+        (Entity, &Player, &Transform, &mut Health),
+        (Without<Dead>, Without<Spit>),
+    >,
+    obstacles: Res<Obstacles>,
+    mut rollback_safe_events: ResMut<RollbackSafeEvents>,
+) {
+    // the health of players depends on the order of hits, and a spit hits only one player
+    let mut spits: Vec<_> = spit_query.iter_mut().collect();
+    spits.sort_unstable_by_key(|(_, rollback, ..)| rollback.id());
+    let mut players: Vec<_> = player_query.iter_mut().collect();
+    players.sort_unstable_by_key(|(_, player, ..)| player.handle);
+
+    'spit: for (entity, _, mut transform, direction, mut spit) in spits {
+        if spit.range <= 0. {
+            commands.entity(entity).despawn_recursive();
+            continue;
+        }
+        transform.translation += (direction.0 * spit.speed).extend(0.);
+        spit.range -= spit.speed;
+        if obstacles.blocks(transform.translation.xy(), SPIT_RADIUS) {
+            commands.entity(entity).despawn_recursive();
+            continue;
+        }
+        for (player, _, player_transform, health) in &mut players {
+            let distance = player_transform
+                .translation
+                .xy()
+                .distance(transform.translation.xy());
+            if distance < PLAYER_RADIUS + SPIT_RADIUS {
+                rollback_safe_events.0.push(SafeEvent::new(
+                    FvzEvent::PlayerHit,
+                    (3 * player.index()).wrapping_add(entity.index()),
+                ));
+                health.current -= spit.damage;
+                commands.entity(entity).despawn_recursive();
+                continue 'spit;
             }
+        }
+    }
+}
 
-            let move_delta = distance.normalize_or_zero() * enemy.speed;
-            transform.translation.x += move_delta.x;
-            transform.translation.y += move_delta.y;
+/// Chargers blink red while winding up for a dash
+fn flash_winding_up_enemies(
+    seed_frame: Res<SeedFrame>,
+    mut enemies: Query<(&Enemy, &mut TextureAtlasSprite)>,
+) {
+    for (enemy, mut sprite) in &mut enemies {
+        let color = match enemy.action {
+            EnemyAction::WindUp { .. } if seed_frame.0 / 6 % 2 == 0 => Color::rgb(1., 0.3, 0.3),
+            _ => Color::WHITE,
+        };
+        if sprite.color != color {
+            sprite.color = color;
         }
     }
 }
@@ -32,6 +32,7 @@ pub fn propagate(
             FvzEvent::Lost => audio_events.send(AudioEvent::Lost),
             FvzEvent::Pew => audio_events.send(AudioEvent::Pew),
             FvzEvent::Revive => audio_events.send(AudioEvent::Revive),
+            FvzEvent::Pickup => audio_events.send(AudioEvent::Pickup),
         }
     }
     events_cache
@@ -0,0 +1,130 @@
+use crate::menu::ButtonColors;
+use bevy::prelude::*;
+
+pub struct GamepadPlugin;
+
+impl Plugin for GamepadPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<GamepadFocus>()
+            .add_system(navigate_buttons);
+    }
+}
+
+/// Stick deflection below which sticks are ignored
+pub const STICK_DEAD_ZONE: f32 = 0.3;
+
+/// Button currently selected with a gamepad
+#[derive(Default, Resource)]
+pub struct GamepadFocus(Option<Entity>);
+
+pub fn any_just_pressed(
+    gamepads: &Gamepads,
+    buttons: &Input<GamepadButton>,
+    button_type: GamepadButtonType,
+) -> bool {
+    gamepads
+        .iter()
+        .any(|gamepad| buttons.just_pressed(GamepadButton::new(gamepad, button_type)))
+}
+
+pub fn any_pressed(
+    gamepads: &Gamepads,
+    buttons: &Input<GamepadButton>,
+    button_type: GamepadButtonType,
+) -> bool {
+    gamepads
+        .iter()
+        .any(|gamepad| buttons.pressed(GamepadButton::new(gamepad, button_type)))
+}
+
+/// Position of the given stick on the first gamepad that moves it past the dead zone
+pub fn stick(gamepads: &Gamepads, axes: &Axis<GamepadAxis>, right: bool) -> Option<Vec2> {
+    gamepads
+        .iter()
+        .find_map(|gamepad| gamepad_stick(gamepad, axes, right))
+}
+
+/// Position of the given stick of one gamepad, if moved past the dead zone
+pub fn gamepad_stick(gamepad: Gamepad, axes: &Axis<GamepadAxis>, right: bool) -> Option<Vec2> {
+    let (x, y) = if right {
+        (GamepadAxisType::RightStickX, GamepadAxisType::RightStickY)
+    } else {
+        (GamepadAxisType::LeftStickX, GamepadAxisType::LeftStickY)
+    };
+    let stick = Vec2::new(
+        axes.get(GamepadAxis::new(gamepad, x))?,
+        axes.get(GamepadAxis::new(gamepad, y))?,
+    );
+    (stick.length() > STICK_DEAD_ZONE).then_some(stick)
+}
+
+/// Move between the visible buttons with the D-pad and press them with the south button.
+///
+/// Pressing sets the button's `Interaction` to `Clicked`, so the usual click handlers pick it up.
+pub fn navigate_buttons(
+    gamepads: Res<Gamepads>,
+    gamepad_buttons: Res<Input<GamepadButton>>,
+    button_colors: Res<ButtonColors>,
+    mut focus: ResMut<GamepadFocus>,
+    mut ui_buttons: Query<
+        (
+            Entity,
+            &GlobalTransform,
+            &ComputedVisibility,
+            &mut Interaction,
+            &mut BackgroundColor,
+        ),
+        With<Button>,
+    >,
+) {
+    let pressed = |button_type| any_just_pressed(&gamepads, &gamepad_buttons, button_type);
+    let step = if pressed(GamepadButtonType::DPadDown) {
+        1
+    } else if pressed(GamepadButtonType::DPadUp) {
+        -1
+    } else {
+        0
+    };
+    let confirm = pressed(GamepadButtonType::South);
+    if step == 0 && !confirm {
+        return;
+    }
+
+    // top to bottom, UI coordinates grow downwards
+    let mut visible: Vec<(Entity, Vec3)> = ui_buttons
+        .iter()
+        .filter(|(_, _, visibility, _, _)| visibility.is_visible())
+        .map(|(entity, transform, ..)| (entity, transform.translation()))
+        .collect();
+    if visible.is_empty() {
+        focus.0 = None;
+        return;
+    }
+    visible.sort_by(|(_, a), (_, b)| a.y.total_cmp(&b.y).then(a.x.total_cmp(&b.x)));
+    let current = focus
+        .0
+        .and_then(|focused| visible.iter().position(|(entity, _)| *entity == focused));
+
+    if confirm {
+        if let Some(index) = current {
+            if let Ok((.., mut interaction, _)) = ui_buttons.get_mut(visible[index].0) {
+                *interaction = Interaction::Clicked;
+            }
+        }
+        return;
+    }
+
+    let next = match current {
+        Some(index) => (index as i32 + step).rem_euclid(visible.len() as i32) as usize,
+        None => 0,
+    };
+    if let Some(index) = current {
+        if let Ok((.., mut color)) = ui_buttons.get_mut(visible[index].0) {
+            *color = button_colors.normal.into();
+        }
+    }
+    if let Ok((.., mut color)) = ui_buttons.get_mut(visible[next].0) {
+        *color = button_colors.hovered.into();
+    }
+    focus.0 = Some(visible[next].0);
+}
@@ -0,0 +1,508 @@
+use crate::desync::{Desync, DesyncPlugin};
+use crate::enemies::{Enemy, FvzEvent, RollbackSafeEvents};
+use crate::input::FvzInput;
+use crate::loading::{
+    CustomDynamicAssetCollection, EnemyAssets, EnemyData, GameData, ImageAssets, PlayerAssets,
+    WeaponAssets, WeaponData,
+};
+use crate::map::Obstacles;
+use crate::matchmaking::{receive_game_packets, GamePacket, GameSocket, Seed, GGRS_CHANNEL};
+use crate::networking::{add_simulation, Dead, GgrsConfig, NextRound, SeedFrame, MAX_PREDICTION};
+use crate::players::{Health, MoveDir, Player, Weapon};
+use crate::waves::{spawn_enemy, WaveDefinitions, Waves};
+use crate::{Bullet, GameState, Score, MAP_SIZE};
+use bevy::ecs::query::ReadOnlyWorldQuery;
+use bevy::ecs::system::CommandQueue;
+use bevy::prelude::*;
+use bevy::tasks::{IoTaskPool, TaskPool};
+use bevy::time::TimeUpdateStrategy;
+use bevy_ggrs::{Rollback, RollbackIdProvider, Session};
+use ggrs::{PlayerHandle, SessionBuilder};
+use matchbox_socket::{ChannelConfig, WebRtcSocket, WebRtcSocketBuilder};
+use rand::Rng;
+use rand_chacha::ChaCha8Rng;
+use std::f32::consts::TAU;
+use std::time::{Duration, Instant};
+
+const ENEMIES: &str = include_str!("../assets/enemies.my-assets");
+const WEAPONS: &str = include_str!("../assets/weapons.my-assets");
+const WAVES: &str = include_str!("../assets/survival.waves");
+/// Online clients simulate in real time, one frame per update
+const FRAME_DURATION: Duration = Duration::from_micros(16_667);
+/// How long online clients wait for each other to join the room
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+/// Online clients give up if the others stop sending inputs for this long
+const STALL_TIMEOUT: Duration = Duration::from_secs(10);
+/// Online clients stop holding fire this many frames before the end,
+/// so predicting the inputs of the others as unchanged is right by then
+const SETTLE_FRAMES: u32 = 120;
+/// Updates online clients keep running after the last frame, so the others get their last inputs and checksums
+const LINGER_UPDATES: u32 = 120;
+
+/// Inputs per player handle, one entry per frame.
+///
+/// Frames past the end of a script are simulated without any input.
+#[derive(Default, Resource)]
+pub struct InputScripts(pub Vec<Vec<FvzInput>>);
+
+fn scripted_input(
+    In(handle): In<PlayerHandle>,
+    scripts: Res<InputScripts>,
+    frame: Res<SeedFrame>,
+) -> FvzInput {
+    scripts
+        .0
+        .get(handle)
+        .and_then(|script| script.get(frame.0 as usize))
+        .copied()
+        .unwrap_or_default()
+}
+
+/// Runs the `GGRSSchedule` systems without window or audio, in a sync test session or online with other headless clients
+pub struct HeadlessSimulation {
+    app: App,
+    kills: u32,
+    online: bool,
+}
+
+impl HeadlessSimulation {
+    pub fn new(num_players: usize, seed: Seed) -> Self {
+        Self::sync_test(num_players, seed, SessionBuilder::new())
+    }
+
+    /// Without rollbacks every frame is simulated once, and entities added between frames stay
+    pub fn without_rollbacks(num_players: usize, seed: Seed) -> Self {
+        Self::sync_test(
+            num_players,
+            seed,
+            SessionBuilder::new().with_check_distance(0),
+        )
+    }
+
+    fn sync_test(num_players: usize, seed: Seed, session: SessionBuilder<GgrsConfig>) -> Self {
+        let session = session
+            .with_num_players(num_players)
+            .start_synctest_session()
+            .expect("failed to start sync test session");
+        Self::build(seed, Session::SyncTestSession(session), None)
+    }
+
+    /// Plays one of the players in a P2P session with the other clients joining the same room.
+    ///
+    /// Handles follow the order of the socket's players, like in [`crate::matchmaking`] with one player per machine.
+    /// Desyncs are detected by exchanging checksums as in the game.
+    pub fn online(room_url: &str, num_players: usize, seed: Seed) -> Self {
+        let (mut socket, message_loop) = WebRtcSocketBuilder::new(room_url)
+            .add_channel(ChannelConfig::reliable())
+            .add_channel(ChannelConfig::reliable())
+            .build();
+        IoTaskPool::init(TaskPool::default)
+            .spawn(message_loop)
+            .detach();
+        let started = Instant::now();
+        while socket.connected_peers().count() + 1 < num_players {
+            assert!(
+                started.elapsed() < CONNECT_TIMEOUT,
+                "only {} of {} players joined {}",
+                socket.connected_peers().count() + 1,
+                num_players,
+                room_url
+            );
+            socket.update_peers();
+            std::thread::sleep(FRAME_DURATION);
+        }
+
+        let mut session = SessionBuilder::<GgrsConfig>::new()
+            .with_num_players(num_players)
+            .with_max_prediction_window(MAX_PREDICTION)
+            .with_input_delay(2);
+        for (handle, player) in socket.players().into_iter().enumerate() {
+            session = session
+                .add_player(player, handle)
+                .expect("failed to add player");
+        }
+        let channel = socket.take_channel(GGRS_CHANNEL).unwrap();
+        let session = session
+            .start_p2p_session(channel)
+            .expect("failed to start session");
+        Self::build(seed, Session::P2PSession(session), Some(socket))
+    }
+
+    fn build(seed: Seed, session: Session<GgrsConfig>, socket: Option<WebRtcSocket>) -> Self {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .add_plugin(AssetPlugin::default())
+            .add_asset::<EnemyData>()
+            .add_asset::<WaveDefinitions>()
+            .add_asset::<WeaponData>()
+            .add_state::<GameState>()
+            .insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_secs_f64(
+                1. / 60.,
+            )))
+            .insert_resource(Obstacles::generate(&seed))
+            .insert_resource(seed)
+            .init_resource::<InputScripts>()
+            .init_resource::<ImageAssets>()
+            .init_resource::<PlayerAssets>();
+        add_simulation(&mut app, scripted_input);
+
+        let enemies: CustomDynamicAssetCollection =
+            ron::from_str(ENEMIES).expect("Failed to parse enemy definitions");
+        let mut enemy_data = app.world.resource_mut::<Assets<EnemyData>>();
+        let enemy_assets = EnemyAssets {
+            devil: enemy_data.add(enemies.0["devil"].enemy_data(default()).unwrap()),
+            zombie: enemy_data.add(enemies.0["zombie"].enemy_data(default()).unwrap()),
+            spitter: enemy_data.add(enemies.0["spitter"].enemy_data(default()).unwrap()),
+            charger: enemy_data.add(enemies.0["charger"].enemy_data(default()).unwrap()),
+            swarmer: enemy_data.add(enemies.0["swarmer"].enemy_data(default()).unwrap()),
+            abomination: enemy_data.add(enemies.0["abomination"].enemy_data(default()).unwrap()),
+        };
+        app.insert_resource(enemy_assets);
+
+        let weapons: CustomDynamicAssetCollection =
+            ron::from_str(WEAPONS).expect("Failed to parse weapon definitions");
+        let mut weapon_data = app.world.resource_mut::<Assets<WeaponData>>();
+        let mut weapon = |key: &str| weapon_data.add(weapons.0[key].weapon_data().unwrap());
+        let weapon_assets = WeaponAssets {
+            pistol: weapon("pistol"),
+            shotgun: weapon("shotgun"),
+            rifle: weapon("rifle"),
+            piercing_gun: weapon("piercing_gun"),
+        };
+        app.insert_resource(weapon_assets);
+        let waves = ron::from_str(WAVES).expect("Failed to parse wave definitions");
+        let waves = app
+            .world
+            .resource_mut::<Assets<WaveDefinitions>>()
+            .add(waves);
+        app.insert_resource(GameData {
+            player_names: default(),
+            waves,
+        });
+
+        let online = socket.is_some();
+        if let Some(socket) = socket {
+            app.add_event::<GamePacket>()
+                .add_system(receive_game_packets)
+                .add_plugin(DesyncPlugin)
+                .insert_resource(GameSocket(Some(socket)));
+        }
+        app.insert_resource(session)
+            .insert_resource(NextRound(Some(0)));
+        app.update();
+
+        HeadlessSimulation {
+            app,
+            kills: 0,
+            online,
+        }
+    }
+
+    pub fn set_inputs(&mut self, scripts: Vec<Vec<FvzInput>>) {
+        self.app.insert_resource(InputScripts(scripts));
+    }
+
+    /// Simulate the given number of frames or until the round is lost
+    pub fn advance(&mut self, frames: u32) {
+        let target = self.frame() + frames;
+        let mut last_progress = (self.frame(), Instant::now());
+        while self.frame() < target && !self.is_game_over() {
+            self.step();
+            if self.frame() != last_progress.0 {
+                last_progress = (self.frame(), Instant::now());
+            }
+            assert!(
+                last_progress.1.elapsed() < STALL_TIMEOUT,
+                "stuck at frame {}, waiting for the inputs of other clients",
+                self.frame()
+            );
+        }
+    }
+
+    /// Keep updating for a while without expecting progress, the other clients may have stopped already
+    fn linger(&mut self) {
+        for _ in 0..LINGER_UPDATES {
+            self.step();
+        }
+    }
+
+    fn step(&mut self) {
+        if self.online {
+            std::thread::sleep(FRAME_DURATION);
+        }
+        self.app.update();
+        // nobody listens for sounds here, but they tell which enemies died
+        let mut events = self.app.world.resource_mut::<RollbackSafeEvents>();
+        self.kills += events
+            .0
+            .drain(..)
+            .filter(|event| matches!(event.event, FvzEvent::EnemyFall))
+            .count() as u32;
+    }
+
+    /// Spawn zombies and stray bullets at random places until there are the given numbers of each
+    pub fn fill_arena(&mut self, enemies: usize, bullets: usize, rng: &mut ChaCha8Rng) {
+        let missing_enemies = enemies.saturating_sub(self.count::<With<Enemy>>());
+        let missing_bullets = bullets.saturating_sub(self.count::<With<Bullet>>());
+        let half_size = MAP_SIZE as f32 / 2.;
+        let random_position = |rng: &mut ChaCha8Rng| {
+            Vec2::new(
+                rng.gen_range(-half_size..half_size),
+                rng.gen_range(-half_size..half_size),
+            )
+        };
+        let mut queue = CommandQueue::default();
+        self.app
+            .world
+            .resource_scope(|world, mut rip: Mut<RollbackIdProvider>| {
+                let mut commands = Commands::new(&mut queue, world);
+                let zombie = &world.resource::<EnemyAssets>().zombie;
+                let zombie_data = world.resource::<Assets<EnemyData>>().get(zombie).unwrap();
+                let stats = world
+                    .resource::<Assets<WaveDefinitions>>()
+                    .get(&world.resource::<GameData>().waves)
+                    .unwrap()
+                    .stats(1);
+                for index in 0..missing_enemies {
+                    spawn_enemy(
+                        &mut commands,
+                        &mut rip,
+                        zombie,
+                        zombie_data,
+                        random_position(rng).extend(100.),
+                        &stats,
+                        if index % 2 == 0 { 1. } else { -1. },
+                    );
+                }
+                let pistol = world.resource::<WeaponAssets>().pistol.clone();
+                let weapon =
+                    Weapon::new(world.resource::<Assets<WeaponData>>().get(&pistol).unwrap());
+                for _ in 0..missing_bullets {
+                    commands
+                        .spawn(Transform::from_translation(
+                            random_position(rng).extend(200.),
+                        ))
+                        .insert(MoveDir(Vec2::from_angle(rng.gen_range(0. ..TAU))))
+                        .insert(Bullet::fire(&weapon, Entity::PLACEHOLDER))
+                        .insert(Rollback::new(rip.next_id()));
+                }
+            });
+        queue.apply(&mut self.app.world);
+    }
+
+    /// Refill the health of all players so the round can't end
+    pub fn heal_players(&mut self) {
+        let mut players = self.app.world.query_filtered::<&mut Health, With<Player>>();
+        for mut health in players.iter_mut(&mut self.app.world) {
+            health.current = health.max;
+        }
+    }
+
+    pub fn frame(&self) -> u32 {
+        self.app.world.resource::<SeedFrame>().0
+    }
+
+    pub fn wave(&self) -> u32 {
+        self.app.world.resource::<Waves>().wave
+    }
+
+    /// Enemies killed so far.
+    ///
+    /// Counted from sound events, which rollbacks repeat, so only exact [`Self::without_rollbacks`].
+    pub fn kills(&self) -> u32 {
+        self.kills
+    }
+
+    /// First frame whose checksums differed from another client's, see [`Self::online`]
+    pub fn desync(&self) -> Option<u32> {
+        self.app
+            .world
+            .get_resource::<Desync>()
+            .map(|desync| desync.frame)
+    }
+
+    pub fn score(&self) -> f64 {
+        self.app.world.resource::<Score>().0
+    }
+
+    pub fn is_game_over(&self) -> bool {
+        self.app.world.resource::<State<GameState>>().0 == GameState::Interlude
+    }
+
+    /// Number of entities matching the query filter, e.g. `count::<(With<Player>, Without<Dead>)>()`
+    pub fn count<F: ReadOnlyWorldQuery + 'static>(&mut self) -> usize {
+        self.app
+            .world
+            .query_filtered::<(), F>()
+            .iter(&self.app.world)
+            .count()
+    }
+
+    pub fn summary(&mut self) -> String {
+        format!(
+            "frame {}: wave {}, score {}, {} enemies, {} of {} players alive{}",
+            self.frame(),
+            self.wave(),
+            self.score(),
+            self.count::<With<Enemy>>(),
+            self.count::<(With<Player>, Without<Dead>)>(),
+            self.count::<With<Player>>(),
+            if self.is_game_over() {
+                " (game over)"
+            } else {
+                ""
+            }
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::{INPUT_AIM, INPUT_FIRE};
+    use rand::SeedableRng;
+    use std::process::{Child, Command, Stdio};
+
+    /// Enemies and bullets kept in the arena by [`frame_time_in_a_crowded_arena`]
+    const BENCHMARK_ENEMIES: usize = 500;
+    const BENCHMARK_BULLETS: usize = 200;
+    const BENCHMARK_FRAMES: u32 = 600;
+    /// Default address of `matchbox_server`
+    const SIGNALING_SERVER: &str = "ws://127.0.0.1:3536";
+    const ONLINE_FRAMES: u32 = 600;
+
+    /// Stops the signaling server even if the test fails
+    struct Server(Child);
+
+    impl Drop for Server {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    /// Holding fire while turning around once every 64 frames, the second player aiming the other way
+    fn sweeping_fire(num_players: usize, frames: u32) -> Vec<Vec<FvzInput>> {
+        (0..num_players)
+            .map(|player| {
+                (0..frames)
+                    .map(|frame| FvzInput {
+                        buttons: INPUT_FIRE | INPUT_AIM,
+                        aim: (frame * 4 + player as u32 * 128) as u8,
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn two_players_holding_fire_clear_the_first_wave() {
+        let mut simulation = HeadlessSimulation::without_rollbacks(2, Seed::from_u64(0));
+        simulation.set_inputs(sweeping_fire(2, 600));
+        simulation.advance(600);
+
+        assert!(!simulation.is_game_over());
+        // the first wave of `survival.waves` has 4 zombies
+        assert!(
+            simulation.kills() >= 4,
+            "only {} kills in wave {}",
+            simulation.kills(),
+            simulation.wave()
+        );
+        assert!(simulation.wave() >= 2);
+    }
+
+    #[test]
+    fn same_seed_and_inputs_give_the_same_round() {
+        let run = || {
+            let mut simulation = HeadlessSimulation::new(2, Seed::from_u64(7));
+            simulation.set_inputs(sweeping_fire(2, 900));
+            simulation.advance(900);
+            (
+                simulation.frame(),
+                simulation.score(),
+                simulation.count::<With<Enemy>>(),
+            )
+        };
+        assert_eq!(run(), run());
+    }
+
+    /// Only the simulation itself is timed; topping up the arena happens between frames.
+    ///
+    /// `cargo test --release frame_time -- --ignored --nocapture`
+    #[test]
+    #[ignore = "benchmark, see the documentation"]
+    fn frame_time_in_a_crowded_arena() {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        let mut simulation = HeadlessSimulation::without_rollbacks(1, Seed::from_u64(0));
+        simulation.set_inputs(vec![vec![
+            FvzInput::from_buttons(INPUT_FIRE);
+            BENCHMARK_FRAMES as usize
+        ]]);
+        let mut total = Duration::ZERO;
+        let mut slowest = Duration::ZERO;
+        for _ in 0..BENCHMARK_FRAMES {
+            simulation.fill_arena(BENCHMARK_ENEMIES, BENCHMARK_BULLETS, &mut rng);
+            simulation.heal_players();
+            let start = Instant::now();
+            simulation.step();
+            let elapsed = start.elapsed();
+            total += elapsed;
+            slowest = slowest.max(elapsed);
+        }
+        println!(
+            "{} frames with {} enemies and {} bullets: {:.3} ms on average, {:.3} ms at most",
+            BENCHMARK_FRAMES,
+            BENCHMARK_ENEMIES,
+            BENCHMARK_BULLETS,
+            total.as_secs_f64() * 1000. / BENCHMARK_FRAMES as f64,
+            slowest.as_secs_f64() * 1000.
+        );
+    }
+
+    /// Both players hold fire until shortly before the end, each played by its own client
+    fn play_online(room_url: String) -> (String, Option<u32>) {
+        let mut simulation = HeadlessSimulation::online(&room_url, 2, Seed::from_u64(1));
+        simulation.set_inputs(vec![
+            vec![
+                FvzInput::from_buttons(INPUT_FIRE);
+                (ONLINE_FRAMES - SETTLE_FRAMES) as usize
+            ];
+            2
+        ]);
+        simulation.advance(ONLINE_FRAMES);
+        let summary = simulation.summary();
+        simulation.linger();
+        (summary, simulation.desync())
+    }
+
+    /// Needs `matchbox_server` from `cargo install matchbox_server`, or its path in `MATCHBOX_SERVER`:
+    /// `cargo test two_clients -- --ignored`
+    #[test]
+    #[ignore = "needs matchbox_server, see the documentation"]
+    fn two_clients_play_a_match_in_sync() {
+        let server =
+            std::env::var("MATCHBOX_SERVER").unwrap_or_else(|_| "matchbox_server".to_owned());
+        let _server = Server(
+            Command::new(&server)
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn()
+                .unwrap_or_else(|error| panic!("failed to start {}: {}", server, error)),
+        );
+        std::thread::sleep(Duration::from_secs(1));
+
+        let room_url = format!("{}/fvsz_test_{}", SIGNALING_SERVER, std::process::id());
+        let clients = [(); 2].map(|_| {
+            let room_url = room_url.clone();
+            std::thread::spawn(move || play_online(room_url))
+        });
+        let [(first, first_desync), (second, second_desync)] =
+            clients.map(|client| client.join().expect("client failed"));
+
+        assert!(!first.contains("game over"), "{}", first);
+        assert_eq!(first_desync, None, "{}", first);
+        assert_eq!(second_desync, None, "{}", second);
+        assert_eq!(first, second);
+    }
+}
@@ -1,38 +1,201 @@
+use crate::controls::{Action, ActionInput};
+use crate::gamepad::{gamepad_stick, stick};
+use crate::local_coop::{HudCamera, InputDevice, PlayerCamera};
+use crate::networking::SeedFrame;
+use crate::players::{LocalPlayerIds, Player};
+use crate::replay::Playback;
+use crate::touch::TouchControls;
 use bevy::prelude::*;
+use bevy::window::PrimaryWindow;
+use bytemuck::{Pod, Zeroable};
+use std::f32::consts::TAU;
 
-const INPUT_UP: u8 = 1 << 0;
-const INPUT_DOWN: u8 = 1 << 1;
-const INPUT_LEFT: u8 = 1 << 2;
-const INPUT_RIGHT: u8 = 1 << 3;
-const INPUT_FIRE: u8 = 1 << 4;
-const INPUT_REVIVE: u8 = 1 << 5;
+pub const INPUT_UP: u8 = 1 << 0;
+pub const INPUT_DOWN: u8 = 1 << 1;
+pub const INPUT_LEFT: u8 = 1 << 2;
+pub const INPUT_RIGHT: u8 = 1 << 3;
+pub const INPUT_FIRE: u8 = 1 << 4;
+pub const INPUT_REVIVE: u8 = 1 << 5;
+pub const INPUT_RELOAD: u8 = 1 << 6;
+/// The aim angle is set; otherwise players shoot in the direction they move
+pub const INPUT_AIM: u8 = 1 << 7;
 
-pub fn game_input(_: In<ggrs::PlayerHandle>, keys: Res<Input<KeyCode>>) -> u8 {
-    let mut input = 0u8;
+/// Share of a stick's deflection along an axis needed to move along it; about sin(22.5°) for eight even sectors
+const STICK_AXIS_THRESHOLD: f32 = 0.38;
+
+/// Fixed keys of a second player sharing the keyboard; taken away from the first player's bindings
+pub const SECOND_KEYBOARD: [(KeyCode, u8); 7] = [
+    (KeyCode::Up, INPUT_UP),
+    (KeyCode::Down, INPUT_DOWN),
+    (KeyCode::Left, INPUT_LEFT),
+    (KeyCode::Right, INPUT_RIGHT),
+    (KeyCode::RControl, INPUT_FIRE),
+    (KeyCode::RShift, INPUT_REVIVE),
+    (KeyCode::Period, INPUT_RELOAD),
+];
+
+/// Input of one player in one frame
+#[repr(C)]
+#[derive(Copy, Clone, PartialEq, Eq, Pod, Zeroable, Default, Debug)]
+pub struct FvzInput {
+    /// `INPUT_*` bits
+    pub buttons: u8,
+    /// Aim angle in 1/256 of a full turn, counter clockwise from the x axis
+    pub aim: u8,
+}
+
+impl FvzInput {
+    pub const SIZE: usize = std::mem::size_of::<FvzInput>();
+
+    pub fn from_buttons(buttons: u8) -> Self {
+        FvzInput { buttons, aim: 0 }
+    }
+
+    pub fn with_aim(mut self, aim: Vec2) -> Self {
+        let turns = aim.y.atan2(aim.x) / TAU;
+        self.aim = (turns * 256.).round().rem_euclid(256.) as u8;
+        self.buttons |= INPUT_AIM;
+        self
+    }
+
+    /// Reads inputs written with [`bytemuck::cast_slice`]; missing bytes count as no input
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        if bytes.len() < Self::SIZE {
+            return FvzInput::default();
+        }
+        bytemuck::pod_read_unaligned(&bytes[..Self::SIZE])
+    }
+}
+
+/// Where the local player aims with the mouse; keyboard only players keep shooting where they walk
+#[derive(Default)]
+pub struct MouseAim {
+    active: bool,
+    last_cursor: Option<Vec2>,
+}
+
+/// Input of one local player, read from the device assigned to it
+pub fn game_input(
+    In(handle): In<ggrs::PlayerHandle>,
+    actions: ActionInput,
+    mouse: Res<Input<MouseButton>>,
+    gamepads: Res<Gamepads>,
+    axes: Res<Axis<GamepadAxis>>,
+    window: Query<&Window, With<PrimaryWindow>>,
+    camera: Query<(&Camera, &GlobalTransform), (Without<PlayerCamera>, Without<HudCamera>)>,
+    players: Query<(&Player, &Transform)>,
+    local_players: Option<Res<LocalPlayerIds>>,
+    mut mouse_aim: Local<MouseAim>,
+    touch: Res<TouchControls>,
+    playback: Option<Res<Playback>>,
+    seed_frame: Res<SeedFrame>,
+) -> FvzInput {
+    if let Some(playback) = playback {
+        return playback.input(seed_frame.0, handle);
+    }
+    let device = local_players
+        .and_then(|local_players| local_players.device(handle))
+        .unwrap_or(InputDevice::Any);
+    let second_keys = SECOND_KEYBOARD.map(|(key, _)| key);
+    let (mut input, aim_stick) = match device {
+        InputDevice::Any => {
+            let left_stick = stick(&gamepads, &axes, false).unwrap_or_default();
+            let input = FvzInput::from_buttons(
+                actions.input_bits()
+                    | stick_bits(left_stick)
+                    | stick_bits(touch.stick)
+                    | touch.buttons,
+            );
+            (input, stick(&gamepads, &axes, true))
+        }
+        InputDevice::Keyboard => (
+            FvzInput::from_buttons(actions.keyboard_bits(&second_keys)),
+            None,
+        ),
+        InputDevice::SecondKeyboard => {
+            let bits = SECOND_KEYBOARD
+                .iter()
+                .filter(|(key, _)| actions.keys().pressed(*key))
+                .fold(0, |bits, (_, bit)| bits | bit);
+            return FvzInput::from_buttons(bits);
+        }
+        InputDevice::Gamepad(gamepad) => {
+            let left_stick = gamepad_stick(gamepad, &axes, false).unwrap_or_default();
+            let input =
+                FvzInput::from_buttons(actions.gamepad_bits(gamepad) | stick_bits(left_stick));
+            return match gamepad_stick(gamepad, &axes, true) {
+                Some(aim) => input.with_aim(aim),
+                None => input,
+            };
+        }
+    };
 
-    if keys.any_pressed([KeyCode::Up, KeyCode::W]) {
+    let window = window.get_single().ok();
+    let cursor = window.and_then(Window::cursor_position);
+    // browsers turn taps into mouse clicks, which shouldn't fire or aim
+    if touch.enabled {
+        mouse_aim.active = false;
+    } else if mouse.pressed(MouseButton::Left) {
+        input.buttons |= INPUT_FIRE;
+        mouse_aim.active = true;
+    } else if cursor.is_some() && mouse_aim.last_cursor.is_some() && cursor != mouse_aim.last_cursor
+    {
+        mouse_aim.active = true;
+    }
+    if actions.just_pressed(Action::Fire) {
+        mouse_aim.active = false;
+    }
+    mouse_aim.last_cursor = cursor;
+
+    if let Some(aim) = aim_stick {
+        return input.with_aim(aim);
+    }
+    if !mouse_aim.active {
+        return input;
+    }
+    let cursor = cursor.and_then(|cursor| {
+        let (camera, camera_transform) = camera.get_single().ok()?;
+        // in split screen the cursor is relative to the window's bottom left, not the viewport's
+        let viewport_origin = match (camera.logical_viewport_rect(), window) {
+            (Some((min, max)), Some(window)) => Vec2::new(min.x, window.height() - max.y),
+            _ => Vec2::ZERO,
+        };
+        camera
+            .viewport_to_world(camera_transform, cursor - viewport_origin)
+            .map(|ray| ray.origin.truncate())
+    });
+    let player = players
+        .iter()
+        .find(|(player, _)| player.handle == handle)
+        .map(|(_, transform)| transform.translation.truncate());
+    match (cursor, player) {
+        (Some(cursor), Some(player)) if cursor != player => input.with_aim(cursor - player),
+        _ => input,
+    }
+}
+
+/// Movement bits of an analog stick, in eight even sectors
+pub fn stick_bits(stick: Vec2) -> u8 {
+    let mut input = 0u8;
+    let stick = stick.normalize_or_zero();
+    if stick.y > STICK_AXIS_THRESHOLD {
         input |= INPUT_UP;
     }
-    if keys.any_pressed([KeyCode::Down, KeyCode::S]) {
+    if stick.y < -STICK_AXIS_THRESHOLD {
         input |= INPUT_DOWN;
     }
-    if keys.any_pressed([KeyCode::Left, KeyCode::A]) {
-        input |= INPUT_LEFT
+    if stick.x < -STICK_AXIS_THRESHOLD {
+        input |= INPUT_LEFT;
     }
-    if keys.any_pressed([KeyCode::Right, KeyCode::D]) {
+    if stick.x > STICK_AXIS_THRESHOLD {
         input |= INPUT_RIGHT;
     }
-    if keys.any_pressed([KeyCode::Space, KeyCode::Return]) {
-        input |= INPUT_FIRE;
-    }
-    if keys.any_pressed([KeyCode::R]) {
-        input |= INPUT_REVIVE;
-    }
 
     input
 }
 
-pub fn direction(input: u8) -> Vec2 {
+pub fn direction(input: FvzInput) -> Vec2 {
+    let input = input.buttons;
     let mut direction = Vec2::ZERO;
     if input & INPUT_UP != 0 {
         direction.y += 1.;
@@ -52,14 +215,24 @@ pub fn direction(input: u8) -> Vec2 {
 pub trait GameInput {
     fn is_fire(&self) -> bool;
     fn is_revive(&self) -> bool;
+    fn is_reload(&self) -> bool;
+    fn aim(&self) -> Option<Vec2>;
 }
 
-impl GameInput for u8 {
+impl GameInput for FvzInput {
     fn is_fire(&self) -> bool {
-        self & INPUT_FIRE != 0
+        self.buttons & INPUT_FIRE != 0
     }
 
     fn is_revive(&self) -> bool {
-        self & INPUT_REVIVE != 0
+        self.buttons & INPUT_REVIVE != 0
+    }
+
+    fn is_reload(&self) -> bool {
+        self.buttons & INPUT_RELOAD != 0
+    }
+
+    fn aim(&self) -> Option<Vec2> {
+        (self.buttons & INPUT_AIM != 0).then(|| Vec2::from_angle(self.aim as f32 / 256. * TAU))
     }
 }
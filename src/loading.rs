@@ -1,4 +1,7 @@
-use crate::GameState;
+use crate::bosses::BossPhase;
+use crate::enemies::Behavior;
+use crate::waves::WaveDefinitions;
+use crate::{GameState, ENEMY_RADIUS};
 use bevy::prelude::*;
 use bevy::reflect::TypeUuid;
 use bevy_asset_loader::prelude::*;
@@ -13,7 +16,9 @@ pub struct LoadingPlugin;
 impl Plugin for LoadingPlugin {
     fn build(&self, app: &mut App) {
         app.add_asset::<EnemyData>()
+            .add_asset::<WeaponData>()
             .add_plugin(JsonAssetPlugin::<PlayerNames>::new(&["names"]))
+            .add_plugin(RonAssetPlugin::<WaveDefinitions>::new(&["waves"]))
             .add_plugin(RonAssetPlugin::<CustomDynamicAssetCollection>::new(&[
                 "my-assets",
             ]))
@@ -25,10 +30,15 @@ impl Plugin for LoadingPlugin {
             .add_collection_to_loading_state::<_, GameData>(GameState::AssetLoading)
             .add_collection_to_loading_state::<_, PlayerAssets>(GameState::AssetLoading)
             .add_collection_to_loading_state::<_, EnemyAssets>(GameState::AssetLoading)
+            .add_collection_to_loading_state::<_, WeaponAssets>(GameState::AssetLoading)
             .add_collection_to_loading_state::<_, AudioAssets>(GameState::AssetLoading)
             .add_dynamic_collection_to_loading_state::<_, CustomDynamicAssetCollection>(
                 GameState::AssetLoading,
                 "enemies.my-assets",
+            )
+            .add_dynamic_collection_to_loading_state::<_, CustomDynamicAssetCollection>(
+                GameState::AssetLoading,
+                "weapons.my-assets",
             );
     }
 }
@@ -55,9 +65,11 @@ pub struct AudioAssets {
 pub struct GameData {
     #[asset(path = "player.names")]
     pub player_names: Handle<PlayerNames>,
+    #[asset(path = "survival.waves")]
+    pub waves: Handle<WaveDefinitions>,
 }
 
-#[derive(AssetCollection, Resource)]
+#[derive(AssetCollection, Resource, Default)]
 pub struct ImageAssets {
     #[asset(path = "bullet.png")]
     pub bullet: Handle<Image>,
@@ -68,7 +80,7 @@ pub struct ImageAssets {
     pub grass: Handle<TextureAtlas>,
 }
 
-#[derive(AssetCollection, Resource)]
+#[derive(AssetCollection, Resource, Default)]
 pub struct PlayerAssets {
     #[asset(path = "players/marker.png")]
     pub marker: Handle<Image>,
@@ -130,18 +142,43 @@ pub struct EnemyAssets {
     pub devil: Handle<EnemyData>,
     #[asset(key = "zombie")]
     pub zombie: Handle<EnemyData>,
+    #[asset(key = "spitter")]
+    pub spitter: Handle<EnemyData>,
+    #[asset(key = "charger")]
+    pub charger: Handle<EnemyData>,
+    #[asset(key = "swarmer")]
+    pub swarmer: Handle<EnemyData>,
+    #[asset(key = "abomination")]
+    pub abomination: Handle<EnemyData>,
 }
 
 impl EnemyAssets {
-    pub fn get(&self, random_index: i32) -> &Handle<EnemyData> {
-        if random_index > 80 {
-            &self.devil
-        } else {
-            &self.zombie
+    /// Enemy by its key in `enemies.my-assets`
+    pub fn by_key(&self, key: &str) -> Option<&Handle<EnemyData>> {
+        match key {
+            "devil" => Some(&self.devil),
+            "zombie" => Some(&self.zombie),
+            "spitter" => Some(&self.spitter),
+            "charger" => Some(&self.charger),
+            "swarmer" => Some(&self.swarmer),
+            "abomination" => Some(&self.abomination),
+            _ => None,
         }
     }
 }
 
+#[derive(AssetCollection, Resource)]
+pub struct WeaponAssets {
+    #[asset(key = "pistol")]
+    pub pistol: Handle<WeaponData>,
+    #[asset(key = "shotgun")]
+    pub shotgun: Handle<WeaponData>,
+    #[asset(key = "rifle")]
+    pub rifle: Handle<WeaponData>,
+    #[asset(key = "piercing_gun")]
+    pub piercing_gun: Handle<WeaponData>,
+}
+
 #[derive(AssetCollection, Resource)]
 pub struct FontAssets {
     #[asset(path = "fonts/FiraSans-Bold.ttf")]
@@ -166,13 +203,48 @@ impl PlayerNames {
 }
 
 #[derive(serde::Deserialize, Debug, Clone)]
-enum CustomDynamicAsset {
+pub enum CustomDynamicAsset {
     Enemy {
         sprite_sheet: String,
         speed: f32,
         damage: f64,
         health: f64,
         attack_cooldown: u8,
+        /// Walking straight at the closest player if not given
+        #[serde(default)]
+        behavior: Behavior,
+    },
+    /// A large enemy with its own health bar in the HUD and attacks changing with its health
+    Boss {
+        sprite_sheet: String,
+        speed: f32,
+        damage: f64,
+        health: f64,
+        attack_cooldown: u8,
+        #[serde(default)]
+        behavior: Behavior,
+        /// Size of the hitbox; regular enemies have `ENEMY_RADIUS`
+        radius: f32,
+        phases: Vec<BossPhase>,
+    },
+    Weapon {
+        damage: f64,
+        /// Shots per second
+        fire_rate: f32,
+        /// Distance per frame
+        speed: f32,
+        /// Angle in degrees that the projectiles of one shot are fanned out over
+        spread: f32,
+        projectiles: u32,
+        /// Number of enemies a single projectile can hit
+        pierce: usize,
+        range: f32,
+        /// Shots until the weapon has to be reloaded
+        magazine: u32,
+        /// Seconds
+        reload_time: f32,
+        /// Spare ammo the weapon comes with; `None` for an unlimited supply
+        reserve: Option<u32>,
     },
 }
 
@@ -184,14 +256,109 @@ pub struct EnemyData {
     pub damage: f64,
     pub health: f64,
     pub attack_cooldown: u8,
+    pub behavior: Behavior,
+    pub radius: f32,
+    /// Only bosses have phases
+    pub phases: Vec<BossPhase>,
+}
+
+#[derive(TypeUuid, Clone)]
+#[uuid = "0b5e3a52-8d1f-4c9e-a7f4-6c2d9e1b3f80"]
+pub struct WeaponData {
+    pub damage: f64,
+    pub cooldown: u32,
+    pub speed: f32,
+    pub spread: f32,
+    pub projectiles: u32,
+    pub pierce: usize,
+    pub range: f32,
+    pub magazine: u32,
+    /// Frames
+    pub reload_time: u32,
+    pub reserve: Option<u32>,
+}
+
+impl CustomDynamicAsset {
+    pub fn weapon_data(&self) -> Option<WeaponData> {
+        match self {
+            CustomDynamicAsset::Weapon {
+                damage,
+                fire_rate,
+                speed,
+                spread,
+                projectiles,
+                pierce,
+                range,
+                magazine,
+                reload_time,
+                reserve,
+            } => Some(WeaponData {
+                damage: *damage,
+                cooldown: (60. / fire_rate) as u32,
+                speed: *speed,
+                spread: spread.to_radians(),
+                projectiles: (*projectiles).max(1),
+                pierce: *pierce,
+                range: *range,
+                magazine: (*magazine).max(1),
+                reload_time: (reload_time * 60.) as u32,
+                reserve: *reserve,
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn enemy_data(&self, texture_atlas: Handle<TextureAtlas>) -> Option<EnemyData> {
+        match self {
+            CustomDynamicAsset::Enemy {
+                speed,
+                damage,
+                health,
+                attack_cooldown,
+                behavior,
+                ..
+            } => Some(EnemyData {
+                texture_atlas,
+                speed: *speed,
+                attack_cooldown: *attack_cooldown,
+                damage: *damage,
+                health: *health,
+                behavior: *behavior,
+                radius: ENEMY_RADIUS,
+                phases: vec![],
+            }),
+            CustomDynamicAsset::Boss {
+                speed,
+                damage,
+                health,
+                attack_cooldown,
+                behavior,
+                radius,
+                phases,
+                ..
+            } => Some(EnemyData {
+                texture_atlas,
+                speed: *speed,
+                attack_cooldown: *attack_cooldown,
+                damage: *damage,
+                health: *health,
+                behavior: *behavior,
+                radius: *radius,
+                phases: phases.clone(),
+            }),
+            _ => None,
+        }
+    }
 }
 
 impl DynamicAsset for CustomDynamicAsset {
     fn load(&self, asset_server: &AssetServer) -> Vec<HandleUntyped> {
         match self {
-            CustomDynamicAsset::Enemy { sprite_sheet, .. } => {
+            CustomDynamicAsset::Enemy { sprite_sheet, .. }
+            | CustomDynamicAsset::Boss { sprite_sheet, .. } => {
                 vec![asset_server.load_untyped(sprite_sheet)]
             }
+            CustomDynamicAsset::Weapon { .. } => vec![],
         }
     }
 
@@ -201,13 +368,8 @@ impl DynamicAsset for CustomDynamicAsset {
             .get_resource::<AssetServer>()
             .expect("Failed to get asset server");
         match self {
-            CustomDynamicAsset::Enemy {
-                sprite_sheet,
-                speed,
-                damage,
-                health,
-                attack_cooldown,
-            } => {
+            CustomDynamicAsset::Enemy { sprite_sheet, .. }
+            | CustomDynamicAsset::Boss { sprite_sheet, .. } => {
                 let mut atlases = cell
                     .get_resource_mut::<Assets<TextureAtlas>>()
                     .expect("Failed to get TextureAtlas assets");
@@ -225,23 +387,26 @@ impl DynamicAsset for CustomDynamicAsset {
 
                 Ok(DynamicAssetType::Single(
                     enemies
-                        .add(EnemyData {
-                            texture_atlas: atlases.add(atlas),
-                            speed: *speed,
-                            attack_cooldown: *attack_cooldown,
-                            damage: *damage,
-                            health: *health,
-                        })
+                        .add(self.enemy_data(atlases.add(atlas)).unwrap())
                         .clone_untyped(),
                 ))
             }
+            CustomDynamicAsset::Weapon { .. } => {
+                let mut weapons = cell
+                    .get_resource_mut::<Assets<WeaponData>>()
+                    .expect("Failed to get WeaponData assets");
+
+                Ok(DynamicAssetType::Single(
+                    weapons.add(self.weapon_data().unwrap()).clone_untyped(),
+                ))
+            }
         }
     }
 }
 
 #[derive(serde::Deserialize, bevy::reflect::TypeUuid)]
 #[uuid = "18dc82eb-d5f5-4d72-b0c4-e2b234367c35"]
-pub struct CustomDynamicAssetCollection(HashMap<String, CustomDynamicAsset>);
+pub struct CustomDynamicAssetCollection(pub HashMap<String, CustomDynamicAsset>);
 
 impl DynamicAssetCollection for CustomDynamicAssetCollection {
     fn register(&self, dynamic_assets: &mut DynamicAssets) {
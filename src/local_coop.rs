@@ -0,0 +1,241 @@
+use crate::networking::SessionOnly;
+use crate::players::LocalPlayerIds;
+use crate::{cli_arg, GameState};
+use bevy::core_pipeline::clear_color::ClearColorConfig;
+use bevy::prelude::*;
+use bevy::render::camera::{ScalingMode, Viewport};
+use bevy::render::view::RenderLayers;
+use bevy::window::PrimaryWindow;
+
+pub struct LocalCoopPlugin;
+
+impl Plugin for LocalCoopPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(LocalCoop::from_args())
+            .add_system(spawn_player_cameras.in_schedule(OnEnter(GameState::InGame)))
+            .add_system(reset_main_camera.in_schedule(OnEnter(GameState::Menu)))
+            .add_system(reset_main_camera.in_schedule(OnEnter(GameState::Matchmaking)))
+            .add_system(update_viewports);
+    }
+}
+
+pub const MAX_LOCAL_PLAYERS: usize = 4;
+
+/// How many players share this machine and whether each of them gets an own part of the screen
+#[derive(Resource, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct LocalCoop {
+    pub players: usize,
+    pub split_screen: bool,
+}
+
+impl LocalCoop {
+    /// `--local-players <count>` and `--split-screen`
+    fn from_args() -> Self {
+        let players = cli_arg("--local-players")
+            .and_then(|players| match players.parse() {
+                Ok(players) => Some(players),
+                Err(_) => {
+                    warn!(
+                        "--local-players expects a number, not '{}'; playing alone",
+                        players
+                    );
+                    None
+                }
+            })
+            .unwrap_or(1);
+        LocalCoop {
+            players: players.clamp(1, MAX_LOCAL_PLAYERS),
+            split_screen: players > 1 && std::env::args().any(|arg| arg == "--split-screen"),
+        }
+    }
+
+    /// The next option in the menu: one player, then shared and split screen for every count
+    pub fn next(self) -> Self {
+        match (self.players, self.split_screen) {
+            (1, _) => LocalCoop {
+                players: 2,
+                split_screen: false,
+            },
+            (players, false) => LocalCoop {
+                players,
+                split_screen: true,
+            },
+            (players, true) if players < MAX_LOCAL_PLAYERS => LocalCoop {
+                players: players + 1,
+                split_screen: false,
+            },
+            _ => LocalCoop {
+                players: 1,
+                split_screen: false,
+            },
+        }
+    }
+
+    pub fn label(&self) -> String {
+        match (self.players, self.split_screen) {
+            (1, _) => "1 player".to_owned(),
+            (players, false) => format!("{} players, shared", players),
+            (players, true) => format!("{} players, split", players),
+        }
+    }
+}
+
+/// Where the input of a local player comes from
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum InputDevice {
+    /// Keyboard, mouse, touch and all gamepads; for the only local player
+    Any,
+    /// The configured keyboard controls and the mouse
+    Keyboard,
+    /// Arrow keys and the keys around them, see [`crate::input::SECOND_KEYBOARD`]
+    SecondKeyboard,
+    Gamepad(Gamepad),
+}
+
+/// The first local player plays with keyboard and mouse, everybody else gets a gamepad,
+/// and without enough gamepads the rest shares the keyboard.
+pub fn assign_input_devices(players: usize, gamepads: &Gamepads) -> Vec<InputDevice> {
+    if players == 1 {
+        return vec![InputDevice::Any];
+    }
+    let mut devices: Vec<InputDevice> = std::iter::once(InputDevice::Keyboard)
+        .chain(gamepads.iter().map(InputDevice::Gamepad))
+        .take(players)
+        .collect();
+    if devices.len() < players {
+        devices.push(InputDevice::SecondKeyboard);
+    }
+    if devices.len() < players {
+        warn!(
+            "{} local players, but only input devices for {}; the others share the second keyboard",
+            players,
+            devices.len()
+        );
+        devices.resize(players, InputDevice::SecondKeyboard);
+    }
+    devices
+}
+
+/// Camera of a local player in split screen, following the player with the given handle.
+///
+/// The first local player uses the main camera.
+#[derive(Component)]
+pub struct PlayerCamera(pub usize);
+
+/// Renders the UI over the whole window in split screen, where the main camera only covers one viewport
+#[derive(Component)]
+pub struct HudCamera;
+
+/// No sprite is on this layer, so the HUD camera draws nothing but the UI
+const HUD_LAYER: u8 = RenderLayers::TOTAL_LAYERS as u8 - 1;
+
+fn spawn_player_cameras(
+    mut commands: Commands,
+    local_coop: Res<LocalCoop>,
+    local_players: Option<Res<LocalPlayerIds>>,
+    cameras: Query<(), With<PlayerCamera>>,
+    main_camera: Query<Entity, (With<Camera>, Without<PlayerCamera>, Without<HudCamera>)>,
+) {
+    let Some(local_players) = local_players else {
+        return;
+    };
+    // the cameras stay for all rounds of a session
+    if !local_coop.split_screen || !cameras.is_empty() {
+        return;
+    }
+    for (index, handle) in local_players.handles().enumerate().skip(1) {
+        let mut camera_bundle = Camera2dBundle {
+            camera: Camera {
+                order: index as isize,
+                ..default()
+            },
+            ..default()
+        };
+        camera_bundle.projection.scaling_mode = ScalingMode::FixedVertical(10.);
+        // the main camera already cleared the whole window
+        camera_bundle.camera_2d.clear_color = ClearColorConfig::None;
+        commands
+            .spawn(camera_bundle)
+            .insert(UiCameraConfig { show_ui: false })
+            .insert(PlayerCamera(handle))
+            .insert(SessionOnly);
+    }
+    let mut hud_camera = Camera2dBundle {
+        camera: Camera {
+            order: MAX_LOCAL_PLAYERS as isize,
+            ..default()
+        },
+        ..default()
+    };
+    hud_camera.camera_2d.clear_color = ClearColorConfig::None;
+    commands
+        .spawn(hud_camera)
+        .insert(RenderLayers::layer(HUD_LAYER))
+        .insert(HudCamera)
+        .insert(SessionOnly);
+    for main_camera in &main_camera {
+        commands
+            .entity(main_camera)
+            .insert(UiCameraConfig { show_ui: false });
+    }
+}
+
+fn reset_main_camera(
+    mut main_camera: Query<
+        (&mut OrthographicProjection, Option<&mut UiCameraConfig>),
+        (With<Camera>, Without<PlayerCamera>, Without<HudCamera>),
+    >,
+) {
+    for (mut projection, ui_config) in &mut main_camera {
+        projection.scale = 1.;
+        // split screen hid the UI from the main camera
+        if let Some(mut ui_config) = ui_config {
+            ui_config.show_ui = true;
+        }
+    }
+}
+
+/// Split the window between the main camera and the player cameras: side by side for two, a grid for more
+fn update_viewports(
+    window: Query<&Window, With<PrimaryWindow>>,
+    mut main_camera: Query<&mut Camera, (Without<PlayerCamera>, Without<HudCamera>)>,
+    mut player_cameras: Query<&mut Camera, With<PlayerCamera>>,
+) {
+    let Ok(mut main_camera) = main_camera.get_single_mut() else {
+        return;
+    };
+    let Ok(window) = window.get_single() else {
+        return;
+    };
+    let count = player_cameras.iter().count() + 1;
+    if count == 1 {
+        if main_camera.viewport.is_some() {
+            main_camera.viewport = None;
+        }
+        return;
+    }
+    let columns = 2;
+    let rows = if count > 2 { 2 } else { 1 };
+    let size = UVec2::new(
+        window.physical_width() / columns,
+        window.physical_height() / rows,
+    );
+    let viewport = |index: u32| {
+        Some(Viewport {
+            physical_position: UVec2::new(index % columns * size.x, index / columns * size.y),
+            physical_size: size,
+            ..default()
+        })
+    };
+    if main_camera.viewport != viewport(0) {
+        main_camera.viewport = viewport(0);
+    }
+    let mut player_cameras: Vec<_> = player_cameras.iter_mut().collect();
+    player_cameras.sort_by_key(|camera| camera.order);
+    for (index, mut camera) in player_cameras.into_iter().enumerate() {
+        let viewport = viewport(index as u32 + 1);
+        if camera.viewport != viewport {
+            camera.viewport = viewport;
+        }
+    }
+}
@@ -5,14 +5,25 @@
 extern crate core;
 
 use crate::audio::AudioPlugin;
+use crate::bosses::BossesPlugin;
+use crate::connection::ConnectionPlugin;
+use crate::controls::ControlsPlugin;
+use crate::desync::DesyncPlugin;
 use crate::enemies::EnemiesPlugin;
 use crate::events::EventsPlugin;
+use crate::gamepad::GamepadPlugin;
 use crate::loading::{ImageAssets, LoadingPlugin};
+use crate::local_coop::LocalCoopPlugin;
 use crate::map::MapPlugin;
 use crate::matchmaking::MatchmakingPlugin;
 use crate::menu::MenuPlugin;
 use crate::networking::{GgrsConfig, InterludeTimer, NetworkingPlugin};
-use crate::players::{LocalPlayerId, MoveDir, Player, PlayersPlugin, Weapon};
+use crate::players::{LocalPlayerIds, MoveDir, Player, PlayersPlugin, Weapon};
+use crate::replay::ReplayPlugin;
+use crate::results::ResultsPlugin;
+use crate::spectator::SpectatorPlugin;
+use crate::synctest::{SyncTestPlugin, SyncTestSettings};
+use crate::touch::TouchPlugin;
 use crate::ui::UiPlugin;
 use bevy::prelude::*;
 use bevy::window::PrimaryWindow;
@@ -22,16 +33,35 @@ use std::io::Cursor;
 use winit::window::Icon;
 
 mod audio;
+mod bosses;
+mod checksum;
+mod connection;
+mod controls;
+mod desync;
 mod enemies;
 mod events;
+mod gamepad;
+#[cfg(test)]
+mod headless;
 mod input;
 mod loading;
+mod local_coop;
 mod map;
 mod matchmaking;
 mod menu;
 mod networking;
+mod pickups;
 mod players;
+mod replay;
+mod results;
+mod rollback;
+mod rules;
+mod spatial;
+mod spectator;
+mod synctest;
+mod touch;
 mod ui;
+mod waves;
 
 const PLAYER_RADIUS: f32 = 0.5;
 const REVIVE_DISTANCE: f32 = 1.2;
@@ -44,6 +74,7 @@ enum GameState {
     #[default]
     AssetLoading,
     Menu,
+    Controls,
     Connect,
     Matchmaking,
     InGame,
@@ -54,17 +85,22 @@ enum GameState {
 pub struct Bullet {
     damage: f64,
     max_hits: usize,
+    speed: f32,
+    /// Remaining distance before the bullet vanishes
+    range: f32,
     already_hit: Vec<Entity>,
 }
 
-#[derive(Resource)]
+#[derive(Resource, Reflect, Default)]
 pub struct Score(pub f64);
 
 impl Bullet {
-    pub fn fire(damage: f64, shooter: Entity) -> Self {
+    pub fn fire(weapon: &Weapon, shooter: Entity) -> Self {
         Bullet {
-            damage,
-            max_hits: 1,
+            damage: weapon.damage,
+            max_hits: weapon.pierce,
+            speed: weapon.speed,
+            range: weapon.range,
             already_hit: vec![shooter],
         }
     }
@@ -80,16 +116,23 @@ impl Bullet {
     pub fn is_used_up(&self) -> bool {
         self.already_hit.len() > self.max_hits
     }
+
+    /// Flew its whole range or into an obstacle, and is despawned at the end of the frame
+    pub fn is_spent(&self) -> bool {
+        self.range <= 0.
+    }
 }
 
 fn main() {
     let mut app = App::new();
+    if let Some(sync_test) = SyncTestSettings::from_args() {
+        app.insert_resource(sync_test);
+    }
 
     app.add_state::<GameState>()
         .insert_resource(ClearColor(Color::rgb(0.53, 0.53, 0.53)))
         .add_startup_system(set_window_icon)
         .init_resource::<InterludeTimer>()
-        .insert_resource(Score(0.))
         .add_plugins(DefaultPlugins.set(WindowPlugin {
             primary_window: Some(Window {
                 title: "Friends vs. Zombies".to_string(),
@@ -106,16 +149,36 @@ fn main() {
         .add_plugin(MatchmakingPlugin)
         .add_plugin(EventsPlugin)
         .add_plugin(MenuPlugin)
+        .add_plugin(GamepadPlugin)
+        .add_plugin(ControlsPlugin)
+        .add_plugin(TouchPlugin)
+        .add_plugin(LocalCoopPlugin)
         .add_plugin(UiPlugin)
         .add_plugin(MapPlugin)
         .add_plugin(EnemiesPlugin)
+        .add_plugin(BossesPlugin)
+        .add_plugin(SyncTestPlugin)
+        .add_plugin(DesyncPlugin)
+        .add_plugin(ReplayPlugin)
+        .add_plugin(SpectatorPlugin)
+        .add_plugin(ConnectionPlugin)
+        .add_plugin(ResultsPlugin)
         .run();
 }
 
+/// Value following `name` in the command line arguments
+pub fn cli_arg(name: &str) -> Option<String> {
+    let mut args = std::env::args().skip_while(|arg| arg != name);
+    args.next()?;
+    args.next()
+}
+
 #[derive(PartialEq, Eq, Debug, Resource)]
-enum GameMode {
+pub enum GameMode {
     Single,
     Multi(bool),
+    SyncTest,
+    Replay,
 }
 
 // Sets the icon on windows and X11
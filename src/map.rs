@@ -1,11 +1,18 @@
 use crate::loading::ImageAssets;
-use crate::matchmaking::Seed;
+use crate::matchmaking::{RandomStream, Seed};
+use crate::networking::SessionOnly;
 use crate::{GameState, MAP_SIZE};
 use bevy::ecs::system::SystemState;
 use bevy::prelude::*;
-use rand::{Rng, SeedableRng};
+use rand::Rng;
 use rand_chacha::ChaCha8Rng;
 
+/// Obstacles keep this far away from the center, where the players start
+const SPAWN_CLEARANCE: f32 = 5.;
+/// Free space between two obstacles, wide enough for bosses to pass
+const OBSTACLE_GAP: f32 = 3.;
+const OBSTACLE_ATTEMPTS: usize = 100;
+
 pub struct MapPlugin;
 
 impl Plugin for MapPlugin {
@@ -18,28 +25,223 @@ pub fn setup(world: &mut World) {
     let mut state: SystemState<(Res<ImageAssets>, Res<Seed>)> = SystemState::new(world);
     let (images, seed) = state.get(world);
     info!("build map");
-    let seed: [u8; 32] = [seed.0[1], seed.0[2]].repeat(16).try_into().unwrap();
-    let mut rng = ChaCha8Rng::from_seed(seed);
+    let mut rng = seed.rng(RandomStream::MapTiles);
     let texture = images.grass.clone();
+    let obstacles = Obstacles::generate(&seed);
     for row in 0..=MAP_SIZE {
         for column in 0..=MAP_SIZE {
-            world.spawn(SpriteSheetBundle {
-                transform: Transform {
-                    translation: Vec3::new(
-                        column as f32 - MAP_SIZE as f32 / 2.,
-                        row as f32 - MAP_SIZE as f32 / 2.,
-                        0.1,
-                    ),
-                    scale: Vec3::splat(0.1 / 3.1),
+            world
+                .spawn(SpriteSheetBundle {
+                    transform: Transform {
+                        translation: Vec3::new(
+                            column as f32 - MAP_SIZE as f32 / 2.,
+                            row as f32 - MAP_SIZE as f32 / 2.,
+                            0.1,
+                        ),
+                        scale: Vec3::splat(0.1 / 3.1),
+                        ..default()
+                    },
+                    sprite: TextureAtlasSprite {
+                        index: rng.gen_range(0..32),
+                        ..default()
+                    },
+                    texture_atlas: texture.clone(),
                     ..default()
-                },
-                sprite: TextureAtlasSprite {
-                    index: rng.gen_range(0..32),
+                })
+                .insert(SessionOnly);
+        }
+    }
+    for obstacle in &obstacles.0 {
+        world
+            .spawn(SpriteBundle {
+                transform: Transform::from_translation(obstacle.center.extend(50.)),
+                sprite: Sprite {
+                    color: obstacle.kind.color(),
+                    custom_size: Some(obstacle.half_size * 2.),
                     ..default()
                 },
-                texture_atlas: texture.clone(),
                 ..default()
-            });
+            })
+            .insert(SessionOnly);
+    }
+    world.insert_resource(obstacles);
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ObstacleKind {
+    Rock,
+    Fence,
+    Crate,
+}
+
+impl ObstacleKind {
+    fn color(self) -> Color {
+        match self {
+            ObstacleKind::Rock => Color::rgb(0.45, 0.45, 0.5),
+            ObstacleKind::Fence => Color::rgb(0.55, 0.35, 0.2),
+            ObstacleKind::Crate => Color::rgb(0.75, 0.55, 0.3),
+        }
+    }
+
+    /// Size of a new obstacle of this kind
+    fn random_half_size(self, rng: &mut ChaCha8Rng) -> Vec2 {
+        match self {
+            ObstacleKind::Rock => {
+                Vec2::new(rng.gen_range(1..=2) as f32, rng.gen_range(1..=2) as f32) / 2.
+            }
+            // thicker than the distance the fastest bullet flies per frame
+            ObstacleKind::Fence if rng.gen_bool(0.5) => {
+                Vec2::new(rng.gen_range(3..=6) as f32, 0.6) / 2.
+            }
+            ObstacleKind::Fence => Vec2::new(0.6, rng.gen_range(3..=6) as f32) / 2.,
+            ObstacleKind::Crate => Vec2::splat(0.4),
+        }
+    }
+}
+
+/// Rectangle in the arena that blocks players, enemies and bullets
+#[derive(Clone, Copy, Debug)]
+pub struct Obstacle {
+    pub kind: ObstacleKind,
+    pub center: Vec2,
+    pub half_size: Vec2,
+}
+
+impl Obstacle {
+    fn closest_point(&self, position: Vec2) -> Vec2 {
+        position.clamp(self.center - self.half_size, self.center + self.half_size)
+    }
+
+    fn overlaps(&self, position: Vec2, radius: f32) -> bool {
+        self.closest_point(position).distance(position) < radius
+    }
+
+    /// Offset moving a circle out of the obstacle, zero if they don't overlap
+    fn push_out(&self, position: Vec2, radius: f32) -> Vec2 {
+        let offset = position - self.closest_point(position);
+        let distance = offset.length();
+        if distance >= radius {
+            return Vec2::ZERO;
+        }
+        if distance > 0. {
+            return offset / distance * (radius - distance);
+        }
+        // the center is inside, leave through the closest side
+        let inside = position - self.center;
+        let depth = self.half_size - inside.abs();
+        if depth.x < depth.y {
+            Vec2::new((depth.x + radius) * inside.x.signum(), 0.)
+        } else {
+            Vec2::new(0., (depth.y + radius) * inside.y.signum())
+        }
+    }
+
+    /// Whether there is at least `gap` of free space between the two obstacles along some axis
+    fn is_apart(&self, other: &Obstacle, gap: f32) -> bool {
+        let space = (self.center - other.center).abs() - (self.half_size + other.half_size);
+        space.max_element() >= gap
+    }
+}
+
+/// Obstacles of the current match, generated from the seed so every peer gets the same map
+#[derive(Resource, Default)]
+pub struct Obstacles(pub Vec<Obstacle>);
+
+impl Obstacles {
+    pub fn generate(seed: &Seed) -> Self {
+        let mut rng = seed.rng(RandomStream::Obstacles);
+        let mut obstacles: Vec<Obstacle> = Vec::new();
+        for (kind, count) in [
+            (ObstacleKind::Rock, 8),
+            (ObstacleKind::Fence, 6),
+            (ObstacleKind::Crate, 10),
+        ] {
+            let mut placed = 0;
+            for _ in 0..OBSTACLE_ATTEMPTS {
+                if placed == count {
+                    break;
+                }
+                let half_size = kind.random_half_size(&mut rng);
+                // at least a tile away from the arena border
+                let limit = (MAP_SIZE as f32 / 2. - 1. - half_size.max_element()).floor() as i32;
+                let obstacle = Obstacle {
+                    kind,
+                    center: Vec2::new(
+                        rng.gen_range(-limit..=limit) as f32,
+                        rng.gen_range(-limit..=limit) as f32,
+                    ),
+                    half_size,
+                };
+                if obstacle.overlaps(Vec2::ZERO, SPAWN_CLEARANCE)
+                    || !obstacles
+                        .iter()
+                        .all(|other| obstacle.is_apart(other, OBSTACLE_GAP))
+                {
+                    continue;
+                }
+                obstacles.push(obstacle);
+                placed += 1;
+            }
+        }
+        Obstacles(obstacles)
+    }
+
+    /// Whether a circle at the given position overlaps any obstacle
+    pub fn blocks(&self, position: Vec2, radius: f32) -> bool {
+        self.0
+            .iter()
+            .any(|obstacle| obstacle.overlaps(position, radius))
+    }
+
+    /// Move a circle out of all obstacles it overlaps
+    pub fn push_out(&self, position: Vec2, radius: f32) -> Vec2 {
+        self.0.iter().fold(position, |position, obstacle| {
+            position + obstacle.push_out(position, radius)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout(obstacles: &Obstacles) -> Vec<(ObstacleKind, Vec2, Vec2)> {
+        obstacles
+            .0
+            .iter()
+            .map(|obstacle| (obstacle.kind, obstacle.center, obstacle.half_size))
+            .collect()
+    }
+
+    #[test]
+    fn the_same_seed_gives_the_same_obstacles() {
+        for seed in 0..20 {
+            let seed = Seed::from_u64(seed);
+            let obstacles = layout(&Obstacles::generate(&seed));
+
+            assert!(!obstacles.is_empty());
+            assert_eq!(obstacles, layout(&Obstacles::generate(&seed)));
+        }
+        assert_ne!(
+            layout(&Obstacles::generate(&Seed::from_u64(1))),
+            layout(&Obstacles::generate(&Seed::from_u64(2)))
+        );
+    }
+
+    #[test]
+    fn obstacles_keep_the_spawn_and_the_border_free_and_stay_apart() {
+        let half_size = MAP_SIZE as f32 / 2.;
+        for seed in 0..20 {
+            let obstacles = Obstacles::generate(&Seed::from_u64(seed));
+
+            assert!(!obstacles.blocks(Vec2::ZERO, SPAWN_CLEARANCE));
+            for (index, obstacle) in obstacles.0.iter().enumerate() {
+                let corner = obstacle.center.abs() + obstacle.half_size;
+                assert!(corner.max_element() <= half_size - 1., "{:?}", obstacle);
+                for other in &obstacles.0[index + 1..] {
+                    assert!(obstacle.is_apart(other, OBSTACLE_GAP), "{:?}", other);
+                }
+            }
         }
     }
 }
@@ -1,23 +1,46 @@
+use crate::controls::{Action, ActionInput};
 use crate::loading::{GameData, PlayerNames};
+use crate::local_coop::{assign_input_devices, LocalCoop};
 use crate::menu::GameCode;
-use crate::{GameMode, GameState, GgrsConfig, InterludeTimer, LocalPlayerId};
+use crate::networking::{leave_game, NextRound, INTERLUDE_FRAMES, MAX_PREDICTION};
+use crate::rules::MatchRules;
+use crate::{cli_arg, GameMode, GameState, GgrsConfig, InterludeTimer, LocalPlayerIds};
 use bevy::prelude::*;
 use bevy::tasks::IoTaskPool;
 use bevy_ggrs::Session;
 use ggrs::PlayerType;
-use matchbox_socket::{PeerState, WebRtcSocket};
+use matchbox_socket::{ChannelConfig, PeerId, PeerState, WebRtcSocket, WebRtcSocketBuilder};
+use rand::{thread_rng, Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
 
 pub struct MatchmakingPlugin;
 
 impl Plugin for MatchmakingPlugin {
     fn build(&self, app: &mut App) {
-        app.init_resource::<RemotePlayers>()
+        app.insert_resource(SignalingServer::from_environment())
+            .init_resource::<RemotePlayers>()
             .init_resource::<StartGame>()
+            .init_resource::<PeerLocalPlayers>()
+            .init_resource::<PlayerCounts>()
+            .add_event::<GamePacket>()
+            .add_event::<PeerChange>()
+            .add_system(receive_game_packets)
+            .add_system(update_peers.run_if(
+                in_state(GameState::Matchmaking).or_else(resource_exists::<Session<GgrsConfig>>()),
+            ))
             .add_system(start_matchbox_socket.in_schedule(OnEnter(GameState::Connect)))
             .add_system(connect_local_player.run_if(in_state(GameState::Connect)))
             .add_systems((
-                wait_for_players.run_if(in_state(GameState::Matchmaking)),
+                wait_for_players
+                    .after(update_peers)
+                    .run_if(in_state(GameState::Matchmaking)),
                 handle_packets
+                    .after(wait_for_players)
+                    .after(receive_game_packets)
+                    .run_if(in_state(GameState::Matchmaking)),
+                send_rules
                     .after(wait_for_players)
                     .run_if(in_state(GameState::Matchmaking)),
                 build_ggrs_session
@@ -27,15 +50,101 @@ impl Plugin for MatchmakingPlugin {
     }
 }
 
+/// Channel handed over to GGRS once the game starts
+pub const GGRS_CHANNEL: usize = 0;
+/// Channel for our own packets, identified by their first byte
+pub const GAME_CHANNEL: usize = 1;
+/// Channel the host's GGRS sessions use to feed spectators
+pub const SPECTATOR_CHANNEL: usize = 2;
+
 const START: u8 = 3;
+pub const CHECKSUMS: u8 = 4;
+pub const SPECTATE: u8 = 5;
+pub const REMATCH: u8 = 7;
+const LOCAL_PLAYERS: u8 = 8;
+/// The host's [`MatchRules`], sent to every peer that connects and again whenever they change
+const RULES: u8 = 9;
+/// Sent by the peer that noticed a desync first, so that everybody dumps their world of that frame
+pub const DESYNC: u8 = 10;
+
+/// A packet received on the game channel
+pub struct GamePacket {
+    pub peer: PeerId,
+    pub packet: Box<[u8]>,
+}
+
+/// A peer connected to or left the room; read from the socket in [`update_peers`] only
+pub struct PeerChange {
+    pub peer: PeerId,
+    pub state: PeerState,
+}
+
+const DEFAULT_SIGNALING_SERVER: &str = "wss://nikl-matchbox.fly.dev";
+/// Relative to the working directory
+#[cfg(not(target_arch = "wasm32"))]
+const SETTINGS_FILE: &str = "settings.ron";
 
 #[derive(Resource)]
-struct GameSocket(Option<WebRtcSocket>);
+pub struct GameSocket(pub Option<WebRtcSocket>);
+
+/// Base URL of the matchbox signaling server, rooms are created below it
+#[derive(Resource, Debug)]
+pub struct SignalingServer(pub String);
+
+impl SignalingServer {
+    /// The first of `--signaling-server <url>`, the environment variable `FVZ_SIGNALING_SERVER`,
+    /// `signaling_server` in `settings.ron` (native) or the `signaling_server` query parameter (wasm).
+    pub fn from_environment() -> Self {
+        let url = cli_arg("--signaling-server")
+            .or_else(|| std::env::var("FVZ_SIGNALING_SERVER").ok())
+            .or_else(configured_signaling_server)
+            .unwrap_or_else(|| DEFAULT_SIGNALING_SERVER.to_owned());
+        SignalingServer(url.trim_end_matches('/').to_owned())
+    }
+
+    pub fn room_url(&self, game_code: &str) -> String {
+        format!("{}/fvsz{}", self.0, game_code)
+    }
+}
 
-fn start_matchbox_socket(mut commands: Commands, game_code: Res<GameCode>) {
-    let room_url = format!("wss://nikl-matchbox.fly.dev/fvsz{}", game_code.0);
+#[cfg(not(target_arch = "wasm32"))]
+fn configured_signaling_server() -> Option<String> {
+    #[derive(serde::Deserialize)]
+    struct Settings {
+        #[serde(default)]
+        signaling_server: Option<String>,
+    }
+
+    let settings = std::fs::read_to_string(SETTINGS_FILE).ok()?;
+    match ron::from_str::<Settings>(&settings) {
+        Ok(settings) => settings.signaling_server,
+        Err(error) => {
+            warn!("Failed to read {}: {}", SETTINGS_FILE, error);
+            None
+        }
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+fn configured_signaling_server() -> Option<String> {
+    let search = web_sys::window()?.location().search().ok()?;
+    web_sys::UrlSearchParams::new_with_str(&search)
+        .ok()?
+        .get("signaling_server")
+}
+
+fn start_matchbox_socket(
+    mut commands: Commands,
+    game_code: Res<GameCode>,
+    signaling_server: Res<SignalingServer>,
+) {
+    let room_url = signaling_server.room_url(&game_code.0);
     info!("connecting to matchbox server: {:?}", room_url);
-    let (socket, message_loop) = WebRtcSocket::new_reliable(room_url);
+    let (socket, message_loop) = WebRtcSocketBuilder::new(room_url)
+        .add_channel(ChannelConfig::reliable())
+        .add_channel(ChannelConfig::reliable())
+        .add_channel(ChannelConfig::reliable())
+        .build();
 
     // The message loop needs to be awaited, or nothing will happen.
     // We do this here using bevy's task system.
@@ -65,6 +174,28 @@ fn connect_local_player(
     }
 }
 
+/// Skip connecting to the matchbox server for game modes that run without any network
+pub fn enter_offline_lobby(
+    commands: &mut Commands,
+    players: &mut RemotePlayers,
+    state: &mut NextState<GameState>,
+    game_mode: GameMode,
+) {
+    let local_player = SocketPlayer {
+        id: "offline".to_owned(),
+        name: format!("{:?}", game_mode),
+    };
+    commands.insert_resource(LocalPlayer(local_player.clone()));
+    players.0.push(local_player);
+    commands.insert_resource(game_mode);
+    commands.insert_resource(GameSocket(None));
+    state.set(GameState::Matchmaking);
+}
+
+/// The peer hosting the room, known to the other players from the first RULES packet it sends them
+#[derive(Resource)]
+pub struct Host(pub PeerId);
+
 #[derive(Default, Debug, Resource)]
 pub struct RemotePlayers(pub Vec<SocketPlayer>);
 
@@ -74,50 +205,144 @@ pub struct LocalPlayer(pub SocketPlayer);
 #[derive(Default, Resource)]
 pub struct StartGame(pub bool);
 
+/// Number of local players each peer announced with a LOCAL_PLAYERS packet
+#[derive(Default, Resource)]
+pub struct PeerLocalPlayers(pub HashMap<PeerId, usize>);
+
+/// Local players of every socket player in the order of the socket, as sent by the host in the START packet
+/// after the seed and the [`MatchRules`]
+#[derive(Default, Resource)]
+pub struct PlayerCounts(pub Vec<usize>);
+
 #[derive(Debug, Clone)]
 pub struct SocketPlayer {
     pub id: String,
     pub name: String,
 }
 
-fn handle_packets(
-    mut socket: ResMut<GameSocket>,
-    mut start_game: ResMut<StartGame>,
-    mut commands: Commands,
+/// Forward everything received on the game channel as [`GamePacket`] events
+pub fn receive_game_packets(
+    socket: Option<ResMut<GameSocket>>,
+    mut events: EventWriter<GamePacket>,
 ) {
+    let Some(mut socket) = socket else {
+        return;
+    };
+    let GameSocket(Some(socket)) = socket.as_mut() else {
+        return;
+    };
+    events.send_batch(
+        socket
+            .channel(GAME_CHANNEL)
+            .receive()
+            .into_iter()
+            .filter(|(_, packet)| !packet.is_empty())
+            .map(|(peer, packet)| GamePacket { peer, packet }),
+    );
+}
+
+/// The only place asking the socket for peer changes, which it hands out just once
+fn update_peers(socket: Option<ResMut<GameSocket>>, mut events: EventWriter<PeerChange>) {
+    let Some(mut socket) = socket else {
+        return;
+    };
     let GameSocket(Some(socket)) = socket.as_mut() else {
         return;
     };
-    let mut packets = socket.receive();
-    packets
-        .drain(..)
-        .for_each(|(_, packet)| match packet.first().unwrap() {
+    events.send_batch(
+        socket
+            .update_peers()
+            .into_iter()
+            .map(|(peer, state)| PeerChange { peer, state }),
+    );
+}
+
+fn handle_packets(
+    mut packets: EventReader<GamePacket>,
+    mut start_game: ResMut<StartGame>,
+    mut peer_local_players: ResMut<PeerLocalPlayers>,
+    mut player_counts: ResMut<PlayerCounts>,
+    host: Option<Res<Host>>,
+    game_mode: Res<GameMode>,
+    mut commands: Commands,
+) {
+    let mut host = host.map(|host| host.0);
+    packets.iter().for_each(
+        |GamePacket { peer, packet }| match packet.first().unwrap() {
+            &START | &RULES if *game_mode == GameMode::Multi(true) => {
+                warn!(
+                    "Ignoring START or RULES packet from {}, this peer is the host",
+                    peer.0
+                );
+            }
+            &START if host != Some(*peer) => {
+                warn!(
+                    "Ignoring START packet from {}, which isn't the host",
+                    peer.0
+                );
+            }
             &START => {
-                let seed = Seed([
-                    *packet.get(1).unwrap_or(&0),
-                    *packet.get(2).unwrap_or(&0),
-                    *packet.get(3).unwrap_or(&0),
-                ]);
-                info!("let's go! {:?}", seed);
+                let Some(seed) = packet.get(1..33) else {
+                    warn!("Ignoring START packet without seed");
+                    return;
+                };
+                let seed = Seed(seed.try_into().unwrap());
+                let Some(rules) = MatchRules::from_bytes(&packet[33..]) else {
+                    warn!("Ignoring START packet without valid rules");
+                    return;
+                };
+                info!("let's go! seed {}, {:?}", seed, rules);
+                player_counts.0 = packet[33 + MatchRules::SIZE..]
+                    .iter()
+                    .map(|count| *count as usize)
+                    .collect();
                 commands.insert_resource(seed);
+                commands.insert_resource(rules);
                 start_game.0 = true;
             }
+            &LOCAL_PLAYERS => {
+                let Some(count) = packet.get(1) else {
+                    warn!("Ignoring LOCAL_PLAYERS packet without count");
+                    return;
+                };
+                peer_local_players.0.insert(*peer, *count as usize);
+            }
+            &RULES if host.map_or(false, |host| host != *peer) => {
+                warn!(
+                    "Ignoring RULES packet from {}, which isn't the host",
+                    peer.0
+                );
+            }
+            &RULES => {
+                let Some(rules) = MatchRules::from_bytes(&packet[1..]) else {
+                    warn!("Ignoring RULES packet without valid rules");
+                    return;
+                };
+                commands.insert_resource(rules);
+                if host.is_none() {
+                    host = Some(*peer);
+                    commands.insert_resource(Host(*peer));
+                }
+            }
             _ => (),
-        });
+        },
+    );
 }
 
 fn wait_for_players(
+    mut peer_changes: EventReader<PeerChange>,
     mut socket: ResMut<GameSocket>,
     mut players: ResMut<RemotePlayers>,
     game_data: Res<GameData>,
     player_names: Res<Assets<PlayerNames>>,
+    local_coop: Res<LocalCoop>,
+    game_mode: Res<GameMode>,
+    rules: Res<MatchRules>,
 ) {
     let GameSocket(Some(socket)) = socket.as_mut() else {
         return;
     };
 
-    // Check for new connections
-    let mut joint_or_left_players = socket.update_peers();
     let socket_players = socket.players();
     let local_id = socket.id().clone().expect("Player doesn't have an ID yet");
     players.0.retain(|player| {
@@ -134,13 +359,25 @@ fn wait_for_players(
                 .is_some()
     });
 
-    for (player, state) in joint_or_left_players.drain(..) {
+    for &PeerChange {
+        peer: player,
+        state,
+    } in peer_changes.iter()
+    {
         let id = player.0.to_string();
         if state == PeerState::Disconnected {
-            info!("Player {} disconnected; unhandled!", id);
+            info!("Player {} disconnected", id);
             continue;
         }
         info!("Player {} connected", id);
+        socket
+            .channel(GAME_CHANNEL)
+            .send(Box::new([LOCAL_PLAYERS, local_coop.players as u8]), player);
+        if *game_mode == GameMode::Multi(true) {
+            socket
+                .channel(GAME_CHANNEL)
+                .send(rules_packet(&rules), player);
+        }
         let player_names = player_names.get(&game_data.player_names).unwrap();
         let new_player = SocketPlayer {
             name: player_names.get_name_from_id(&id),
@@ -150,19 +387,126 @@ fn wait_for_players(
     }
 }
 
-#[derive(Debug, Resource)]
-pub struct Seed(pub [u8; 3]);
+fn rules_packet(rules: &MatchRules) -> Box<[u8]> {
+    let mut packet = vec![RULES];
+    packet.extend_from_slice(&rules.to_bytes());
+    packet.into_boxed_slice()
+}
+
+/// The host tells all connected peers about every change of the rules
+fn send_rules(mut socket: ResMut<GameSocket>, game_mode: Res<GameMode>, rules: Res<MatchRules>) {
+    if *game_mode != GameMode::Multi(true) || !rules.is_changed() {
+        return;
+    }
+    let GameSocket(Some(socket)) = socket.as_mut() else {
+        return;
+    };
+    let packet = rules_packet(&rules);
+    for player in socket.players() {
+        if let PlayerType::Remote(id) = player {
+            socket.channel(GAME_CHANNEL).send(packet.clone(), id);
+        }
+    }
+}
+
+/// Seeds all randomness of the simulation; chosen by the host and sent to everyone in the START packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Resource)]
+pub struct Seed(pub [u8; 32]);
+
+impl Seed {
+    /// `--seed <number or 64 hex digits>` forces a seed for reproducible runs
+    pub fn from_args() -> Option<Self> {
+        let seed = cli_arg("--seed")?;
+        Some(Seed::parse(&seed).expect("--seed expects a number or 64 hex digits"))
+    }
+
+    pub fn random() -> Self {
+        Seed(thread_rng().gen())
+    }
+
+    pub fn from_u64(seed: u64) -> Self {
+        Seed(ChaCha8Rng::seed_from_u64(seed).gen())
+    }
+
+    pub fn parse(seed: &str) -> Option<Self> {
+        if seed.len() != 64 {
+            return seed.parse().ok().map(Seed::from_u64);
+        }
+        let mut bytes = [0; 32];
+        for (index, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(seed.get(2 * index..2 * index + 2)?, 16).ok()?;
+        }
+        Some(Seed(bytes))
+    }
+
+    /// Independent random number generator for every stream, each depending on all bytes of the seed
+    pub fn rng(&self, stream: RandomStream) -> ChaCha8Rng {
+        let mut rng = ChaCha8Rng::from_seed(self.0);
+        rng.set_stream(stream.id());
+        rng
+    }
+}
+
+impl Display for Seed {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.0.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+    }
+}
+
+/// What a random number generator derived from the [`Seed`] is used for
+#[derive(Clone, Copy, Debug)]
+pub enum RandomStream {
+    /// Regular enemy spawn in a frame
+    Spawn(u32),
+    /// Spawn order of a wave
+    Wave(u32),
+    /// Boss spawns in a frame
+    Boss(u32),
+    MapTiles,
+    Obstacles,
+    /// Pickup dropped by an enemy dying at a hashed position in a frame
+    Drop {
+        position: u32,
+        frame: u32,
+    },
+}
+
+impl RandomStream {
+    /// Drops use the upper half of all ids, the other kinds get `2^32` ids each in the lower half.
+    ///
+    /// The lowest bit of the position hash of drops makes room for the bit marking the upper half.
+    fn id(self) -> u64 {
+        let (kind, value): (u64, u32) = match self {
+            RandomStream::Spawn(frame) => (0, frame),
+            RandomStream::Wave(wave) => (1, wave),
+            RandomStream::Boss(frame) => (2, frame),
+            RandomStream::MapTiles => (3, 0),
+            RandomStream::Obstacles => (4, 0),
+            RandomStream::Drop { position, frame } => {
+                return (1 << 63) | ((u64::from(position) >> 1) << 32) | u64::from(frame);
+            }
+        };
+        (kind << 32) | u64::from(value)
+    }
+}
 
 fn build_ggrs_session(
     mut commands: Commands,
     mut socket: ResMut<GameSocket>,
     mut state: ResMut<NextState<GameState>>,
     mut interlude_timer: ResMut<InterludeTimer>,
+    mut next_round: ResMut<NextRound>,
     game_mode: Res<GameMode>,
-    input: Res<Input<KeyCode>>,
-    start_game: Res<StartGame>,
+    actions: ActionInput,
+    mut start_game: ResMut<StartGame>,
+    session: Option<Res<Session<GgrsConfig>>>,
+    local_coop: Res<LocalCoop>,
+    peer_local_players: Res<PeerLocalPlayers>,
+    mut player_counts: ResMut<PlayerCounts>,
+    gamepads: Res<Gamepads>,
+    rules: Res<MatchRules>,
 ) {
-    if socket.0.is_none() {
+    if socket.0.is_none() || session.is_some() {
         return;
     }
 
@@ -170,58 +514,146 @@ fn build_ggrs_session(
         return;
     }
     if *game_mode == GameMode::Multi(true) {
-        if !input.pressed(KeyCode::Return) && !start_game.0 {
+        if !actions.pressed(Action::StartGame) && !start_game.0 {
             return; // wait for more players
         } else {
-            let seed = Seed([3, 4, 5]);
-            let packet = Box::new([START, seed.0[0], seed.0[1], seed.0[2]]);
-            commands.insert_resource(seed);
+            // keep starting until every peer has announced its local players
+            start_game.0 = true;
             let socket_players = socket.0.as_ref().as_ref().unwrap().players();
+            let Some(counts) = socket_players
+                .iter()
+                .map(|player| match player {
+                    PlayerType::Remote(id) => peer_local_players.0.get(id).copied(),
+                    _ => Some(local_coop.players),
+                })
+                .collect::<Option<Vec<usize>>>()
+            else {
+                return;
+            };
+            player_counts.0 = counts;
+            let seed = Seed::from_args().unwrap_or_else(Seed::random);
+            info!("starting with seed {}, {:?}", seed, *rules);
+            let mut packet = vec![START];
+            packet.extend_from_slice(&seed.0);
+            packet.extend_from_slice(&rules.to_bytes());
+            packet.extend(player_counts.0.iter().map(|count| *count as u8));
+            let packet = packet.into_boxed_slice();
+            commands.insert_resource(seed);
             for player in socket_players {
                 if let PlayerType::Remote(id) = player {
-                    socket.0.as_mut().as_mut().unwrap().send(packet.clone(), id);
+                    socket
+                        .0
+                        .as_mut()
+                        .unwrap()
+                        .channel(GAME_CHANNEL)
+                        .send(packet.clone(), id);
                 }
             }
         }
     }
     if *game_mode == GameMode::Single {
-        let seed = Seed([3, 4, 5]);
+        let seed = Seed::from_args().unwrap_or_else(Seed::random);
+        info!("starting with seed {}", seed);
         commands.insert_resource(seed);
+        player_counts.0 = vec![local_coop.players];
     }
     let socket_players = socket.0.as_ref().as_ref().unwrap().players();
     let input_delay = if *game_mode == GameMode::Single { 0 } else { 2 };
+    if player_counts.0.len() != socket_players.len() {
+        error!(
+            "Got local player counts for {} players, but {} are connected, returning to the lobby",
+            player_counts.0.len(),
+            socket_players.len()
+        );
+        commands.add(leave_game);
+        state.set(GameState::Connect);
+        return;
+    }
+    let num_players = player_counts.0.iter().sum();
 
     info!(
-        "going in-game in {:?} mode with {} player(s)",
+        "going in-game in {:?} mode with {} player(s) on {} machine(s)",
         *game_mode,
+        num_players,
         socket_players.len()
     );
 
     // create a GGRS P2P session
     let mut session_builder = ggrs::SessionBuilder::<GgrsConfig>::new()
-        .with_num_players(socket_players.len())
+        .with_num_players(num_players)
+        .with_max_prediction_window(MAX_PREDICTION)
         .with_input_delay(input_delay);
 
-    for (i, player) in socket_players.into_iter().enumerate() {
+    // every socket player gets as many consecutive handles as it has local players
+    let mut handle = 0;
+    for (player, count) in socket_players.into_iter().zip(player_counts.0.iter()) {
         if player == PlayerType::Local {
-            commands.insert_resource(LocalPlayerId(i));
+            let devices = assign_input_devices(*count, &gamepads);
+            commands.insert_resource(LocalPlayerIds(
+                (handle..handle + count).zip(devices).collect(),
+            ));
+        }
+        for _ in 0..*count {
+            session_builder = session_builder
+                .add_player(player.clone(), handle)
+                .expect("failed to add player");
+            handle += 1;
         }
-
-        session_builder = session_builder
-            .add_player(player.clone(), i)
-            .expect("failed to add player");
     }
 
-    // move the socket out of the resource (required because GGRS takes ownership of it)
-    let socket = socket.0.take().unwrap().take_channel(0).unwrap();
+    // move the channel out of the socket (required because GGRS takes ownership of it)
+    let channel = socket
+        .0
+        .as_mut()
+        .unwrap()
+        .take_channel(GGRS_CHANNEL)
+        .unwrap();
 
     // start the GGRS session
     let session = session_builder
-        .start_p2p_session(socket)
+        .start_p2p_session(channel)
         .expect("failed to start session");
 
     commands.insert_resource(Session::P2PSession(session));
 
+    // every peer starts counting session frames from zero now
+    next_round.0 = Some(INTERLUDE_FRAMES);
     interlude_timer.0 = 3;
     state.set(GameState::Interlude);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seeds_print_as_hex_digits_that_parse_back() {
+        let seed = Seed(std::array::from_fn(|index| index as u8 * 8));
+        let printed = seed.to_string();
+
+        assert_eq!(printed.len(), 64);
+        assert!(printed.starts_with("0008101820"));
+        assert_eq!(Seed::parse(&printed), Some(seed));
+        assert_eq!(Seed::parse(&printed.to_uppercase()), Some(seed));
+    }
+
+    #[test]
+    fn numbers_parse_as_seeds_derived_from_them() {
+        assert_eq!(Seed::parse("1"), Some(Seed::from_u64(1)));
+        assert_eq!(
+            Seed::parse("18446744073709551615"),
+            Some(Seed::from_u64(u64::MAX))
+        );
+        assert_ne!(Seed::from_u64(1), Seed::from_u64(2));
+    }
+
+    #[test]
+    fn invalid_seeds_are_rejected() {
+        assert_eq!(Seed::parse(""), None);
+        assert_eq!(Seed::parse("-1"), None);
+        assert_eq!(Seed::parse("seed"), None);
+        assert_eq!(Seed::parse(&"g".repeat(64)), None);
+        // 64 bytes, but not all of them ASCII
+        assert_eq!(Seed::parse(&format!("{}ü", "0".repeat(62))), None);
+    }
+}
@@ -1,4 +1,9 @@
+use crate::gamepad::any_just_pressed;
 use crate::loading::FontAssets;
+use crate::local_coop::LocalCoop;
+use crate::matchmaking::{enter_offline_lobby, RemotePlayers};
+use crate::replay::{LastReplay, Playback};
+use crate::touch::TouchOnly;
 use crate::{GameMode, GameState};
 use bevy::prelude::*;
 use bevy::render::camera::ScalingMode;
@@ -14,7 +19,13 @@ impl Plugin for MenuPlugin {
             .add_systems((
                 click_singleplayer_button.run_if(in_state(GameState::Menu)),
                 click_create_game_button.run_if(in_state(GameState::Menu)),
+                click_replay_button.run_if(in_state(GameState::Menu)),
+                click_controls_button.run_if(in_state(GameState::Menu)),
+                click_local_players_button.run_if(in_state(GameState::Menu)),
                 listen_for_game_code.run_if(in_state(GameState::Menu)),
+                click_letter_button
+                    .before(listen_for_game_code)
+                    .run_if(in_state(GameState::Menu)),
                 click_join_game_button
                     .after(listen_for_game_code)
                     .run_if(in_state(GameState::Menu)),
@@ -46,6 +57,18 @@ struct SingleplayerButton;
 #[derive(Component)]
 struct CreateGameButton;
 
+#[derive(Component)]
+struct ReplayButton;
+
+#[derive(Component)]
+struct ControlsButton;
+
+#[derive(Component)]
+struct LocalPlayersButton;
+
+#[derive(Component)]
+struct LocalPlayersText;
+
 #[derive(Component)]
 struct JoinGameButton;
 
@@ -55,6 +78,10 @@ struct MenuUi;
 #[derive(Component)]
 struct CodeDisplay;
 
+/// On-screen letter of the game code, or delete for `None`
+#[derive(Component)]
+struct LetterButton(Option<&'static str>);
+
 #[derive(Resource)]
 pub struct GameCode(pub(crate) String);
 
@@ -62,10 +89,16 @@ fn setup_menu(
     mut commands: Commands,
     font_assets: Res<FontAssets>,
     button_colors: Res<ButtonColors>,
+    last_replay: Option<Res<LastReplay>>,
+    local_coop: Res<LocalCoop>,
+    camera: Query<(), With<Camera>>,
 ) {
-    let mut camera_bundle = Camera2dBundle::default();
-    camera_bundle.projection.scaling_mode = ScalingMode::FixedVertical(10.);
-    commands.spawn(camera_bundle);
+    // the camera is kept when coming back from a game
+    if camera.is_empty() {
+        let mut camera_bundle = Camera2dBundle::default();
+        camera_bundle.projection.scaling_mode = ScalingMode::FixedVertical(10.);
+        commands.spawn(camera_bundle);
+    }
     commands
         .spawn(NodeBundle {
             style: Style {
@@ -115,6 +148,103 @@ fn setup_menu(
                     });
                 });
 
+            if last_replay.is_some() {
+                parent
+                    .spawn(ButtonBundle {
+                        style: Style {
+                            size: Size::new(Val::Px(250.0), Val::Px(50.0)),
+                            margin: UiRect::all(Val::Auto),
+                            justify_content: JustifyContent::Center,
+                            align_items: AlignItems::Center,
+                            ..Default::default()
+                        },
+                        background_color: button_colors.normal.into(),
+                        ..Default::default()
+                    })
+                    .insert(ReplayButton)
+                    .with_children(|parent| {
+                        parent.spawn(TextBundle {
+                            text: Text {
+                                sections: vec![TextSection {
+                                    value: "Watch replay".to_string(),
+                                    style: TextStyle {
+                                        font: font_assets.fira_sans.clone(),
+                                        font_size: 40.0,
+                                        color: Color::rgb(0.9, 0.9, 0.9),
+                                    },
+                                }],
+                                alignment: TextAlignment::Center,
+                                ..default()
+                            },
+                            ..Default::default()
+                        });
+                    });
+            }
+
+            parent
+                .spawn(ButtonBundle {
+                    style: Style {
+                        size: Size::new(Val::Px(250.0), Val::Px(50.0)),
+                        margin: UiRect::all(Val::Auto),
+                        justify_content: JustifyContent::Center,
+                        align_items: AlignItems::Center,
+                        ..Default::default()
+                    },
+                    background_color: button_colors.normal.into(),
+                    ..Default::default()
+                })
+                .insert(ControlsButton)
+                .with_children(|parent| {
+                    parent.spawn(TextBundle {
+                        text: Text {
+                            sections: vec![TextSection {
+                                value: "Controls".to_string(),
+                                style: TextStyle {
+                                    font: font_assets.fira_sans.clone(),
+                                    font_size: 40.0,
+                                    color: Color::rgb(0.9, 0.9, 0.9),
+                                },
+                            }],
+                            alignment: TextAlignment::Center,
+                            ..default()
+                        },
+                        ..Default::default()
+                    });
+                });
+
+            parent
+                .spawn(ButtonBundle {
+                    style: Style {
+                        size: Size::new(Val::Px(250.0), Val::Px(50.0)),
+                        margin: UiRect::all(Val::Auto),
+                        justify_content: JustifyContent::Center,
+                        align_items: AlignItems::Center,
+                        ..Default::default()
+                    },
+                    background_color: button_colors.normal.into(),
+                    ..Default::default()
+                })
+                .insert(LocalPlayersButton)
+                .with_children(|parent| {
+                    parent
+                        .spawn(TextBundle {
+                            text: Text {
+                                sections: vec![TextSection {
+                                    value: local_coop.label(),
+                                    style: TextStyle {
+                                        font: font_assets.fira_sans.clone(),
+                                        font_size: 30.0,
+                                        color: Color::rgb(0.9, 0.9, 0.9),
+                                    },
+                                }],
+                                alignment: TextAlignment::Center,
+                                ..default()
+                            },
+                            ..Default::default()
+                        })
+                        .insert(LocalPlayersText);
+                });
+
             parent
                 .spawn(NodeBundle {
                     style: Style {
@@ -205,6 +335,52 @@ fn setup_menu(
                         })
                         .insert(CodeDisplay);
                 });
+            parent
+                .spawn(NodeBundle {
+                    style: Style {
+                        size: Size::new(Val::Px(7. * 45.), Val::Auto),
+                        margin: UiRect::all(Val::Auto),
+                        flex_wrap: FlexWrap::Wrap,
+                        justify_content: JustifyContent::Center,
+                        display: Display::None,
+                        ..Default::default()
+                    },
+                    background_color: BackgroundColor(Color::NONE),
+                    visibility: Visibility::Hidden,
+                    ..Default::default()
+                })
+                .insert(TouchOnly)
+                .with_children(|parent| {
+                    let letters = KEY_CODES.iter().map(|(_, letter)| Some(*letter));
+                    for letter in letters.chain([None]) {
+                        parent
+                            .spawn(ButtonBundle {
+                                style: Style {
+                                    size: Size::new(Val::Px(40.0), Val::Px(40.0)),
+                                    margin: UiRect::all(Val::Px(2.5)),
+                                    justify_content: JustifyContent::Center,
+                                    align_items: AlignItems::Center,
+                                    ..Default::default()
+                                },
+                                background_color: button_colors.normal.into(),
+                                ..Default::default()
+                            })
+                            .insert(LetterButton(letter))
+                            .with_children(|parent| {
+                                parent.spawn(TextBundle {
+                                    text: Text::from_section(
+                                        letter.unwrap_or("<"),
+                                        TextStyle {
+                                            font: font_assets.fira_sans.clone(),
+                                            font_size: 30.0,
+                                            color: Color::rgb(0.9, 0.9, 0.9),
+                                        },
+                                    ),
+                                    ..Default::default()
+                                });
+                            });
+                    }
+                });
             parent
                 .spawn(ButtonBundle {
                     style: Style {
@@ -290,6 +466,87 @@ fn click_create_game_button(
     }
 }
 
+fn click_replay_button(
+    mut commands: Commands,
+    button_colors: Res<ButtonColors>,
+    last_replay: Option<Res<LastReplay>>,
+    mut players: ResMut<RemotePlayers>,
+    mut state: ResMut<NextState<GameState>>,
+    mut interaction_query: Query<
+        (&Interaction, &mut BackgroundColor),
+        (Changed<Interaction>, With<ReplayButton>),
+    >,
+) {
+    for (interaction, mut color) in &mut interaction_query {
+        match *interaction {
+            Interaction::Clicked => {
+                let Some(last_replay) = last_replay.as_ref() else {
+                    continue;
+                };
+                commands.insert_resource(Playback::new(last_replay.0.clone()));
+                enter_offline_lobby(&mut commands, &mut players, &mut state, GameMode::Replay);
+            }
+            Interaction::Hovered => {
+                *color = button_colors.hovered.into();
+            }
+            Interaction::None => {
+                *color = button_colors.normal.into();
+            }
+        }
+    }
+}
+
+fn click_controls_button(
+    button_colors: Res<ButtonColors>,
+    mut state: ResMut<NextState<GameState>>,
+    mut interaction_query: Query<
+        (&Interaction, &mut BackgroundColor),
+        (Changed<Interaction>, With<ControlsButton>),
+    >,
+) {
+    for (interaction, mut color) in &mut interaction_query {
+        match *interaction {
+            Interaction::Clicked => {
+                state.set(GameState::Controls);
+            }
+            Interaction::Hovered => {
+                *color = button_colors.hovered.into();
+            }
+            Interaction::None => {
+                *color = button_colors.normal.into();
+            }
+        }
+    }
+}
+
+/// Cycle through the number of players on this machine and shared or split screen
+fn click_local_players_button(
+    button_colors: Res<ButtonColors>,
+    mut local_coop: ResMut<LocalCoop>,
+    mut text: Query<&mut Text, With<LocalPlayersText>>,
+    mut interaction_query: Query<
+        (&Interaction, &mut BackgroundColor),
+        (Changed<Interaction>, With<LocalPlayersButton>),
+    >,
+) {
+    for (interaction, mut color) in &mut interaction_query {
+        match *interaction {
+            Interaction::Clicked => {
+                *local_coop = local_coop.next();
+                if let Ok(mut text) = text.get_single_mut() {
+                    text.sections[0].value = local_coop.label();
+                }
+            }
+            Interaction::Hovered => {
+                *color = button_colors.hovered.into();
+            }
+            Interaction::None => {
+                *color = button_colors.normal.into();
+            }
+        }
+    }
+}
+
 fn build_game_code() -> String {
     let mut code = "".to_owned();
     let mut random = thread_rng();
@@ -329,14 +586,46 @@ const KEY_CODES: [KeyCodeOptions; 21] = [
 ];
 type KeyCodeOptions = (KeyCode, &'static str);
 
+fn click_letter_button(
+    button_colors: Res<ButtonColors>,
+    mut game_code: ResMut<GameCode>,
+    mut interaction_query: Query<
+        (&Interaction, &mut BackgroundColor, &LetterButton),
+        Changed<Interaction>,
+    >,
+) {
+    for (interaction, mut color, LetterButton(letter)) in &mut interaction_query {
+        match *interaction {
+            Interaction::Clicked => match letter {
+                Some(letter) if game_code.0.len() < 6 => game_code.0.push_str(letter),
+                Some(_) => (),
+                None => {
+                    game_code.0.pop();
+                }
+            },
+            Interaction::Hovered => {
+                *color = button_colors.hovered.into();
+            }
+            Interaction::None => {
+                *color = button_colors.normal.into();
+            }
+        }
+    }
+}
+
+/// Gamepads pick the next letter with the D-pad left and right, add it with north and remove one with east
 fn listen_for_game_code(
     mut code: Query<&mut Text, With<CodeDisplay>>,
     input: Res<Input<KeyCode>>,
+    gamepads: Res<Gamepads>,
+    gamepad_buttons: Res<Input<GamepadButton>>,
+    mut gamepad_letter: Local<Option<usize>>,
     mut game_code: ResMut<GameCode>,
     button_colors: Res<ButtonColors>,
     mut join_button: Query<&mut BackgroundColor, With<JoinGameButton>>,
 ) {
-    if input.just_pressed(KeyCode::Back) {
+    let gamepad_pressed = |button_type| any_just_pressed(&gamepads, &gamepad_buttons, button_type);
+    if input.just_pressed(KeyCode::Back) || gamepad_pressed(GamepadButtonType::East) {
         game_code.0.pop();
     }
     input.get_just_pressed().for_each(|key_code| {
@@ -346,8 +635,31 @@ fn listen_for_game_code(
             }
         }
     });
-    code.single_mut().sections[0].value =
-        format!("Code: {}{}", game_code.0, "_".repeat(6 - game_code.0.len()));
+    if gamepad_pressed(GamepadButtonType::DPadRight) {
+        *gamepad_letter = Some(gamepad_letter.map_or(0, |index| (index + 1) % KEY_CODES.len()));
+    }
+    if gamepad_pressed(GamepadButtonType::DPadLeft) {
+        *gamepad_letter = Some(gamepad_letter.map_or(KEY_CODES.len() - 1, |index| {
+            (index + KEY_CODES.len() - 1) % KEY_CODES.len()
+        }));
+    }
+    if gamepad_pressed(GamepadButtonType::North) {
+        if let Some(index) = *gamepad_letter {
+            if game_code.0.len() < 6 {
+                game_code.0.push_str(KEY_CODES[index].1);
+            }
+        }
+    }
+    let pending = match *gamepad_letter {
+        Some(index) if game_code.0.len() < 6 => KEY_CODES[index].1,
+        _ => "",
+    };
+    code.single_mut().sections[0].value = format!(
+        "Code: {}{}{}",
+        game_code.0,
+        pending,
+        "_".repeat(6 - game_code.0.len() - pending.len())
+    );
     if game_code.0.len() == 6 {
         *join_button.single_mut() = button_colors.normal.into();
     }
@@ -1,88 +1,322 @@
-use crate::enemies::{kill_enemies, move_enemies, Enemy, FvzEvent, RollbackSafeEvents, SafeEvent};
-use crate::input::GameInput;
-use crate::loading::{EnemyAssets, EnemyData, PlayerAssets};
-use crate::matchmaking::Seed;
-use crate::players::{AnimationTimer, Health};
+use crate::bosses::{boss_attacks, Boss};
+use crate::desync::Desync;
+use crate::enemies::{
+    kill_enemies, move_enemies, move_spit, separate_enemies, spit_at_players, Enemy, FvzEvent,
+    RollbackSafeEvents, SafeEvent, Spit,
+};
+use crate::input::{FvzInput, GameInput};
+use crate::loading::{PlayerAssets, WeaponAssets, WeaponData};
+use crate::map::Obstacles;
+use crate::matchmaking::{
+    GameSocket, Host, PeerLocalPlayers, PlayerCounts, RemotePlayers, StartGame,
+};
+use crate::pickups::{collect_pickups, Boosts, Pickup};
+use crate::players::{AnimationTimer, Health, LocalPlayerIds};
+use crate::replay::Playback;
+#[cfg(debug_assertions)]
+use crate::rollback::assert_rollback_safe;
+use crate::rollback::RollbackRegistry;
+use crate::rules::{FriendlyFire, MatchRules, REDUCED_FRIENDLY_FIRE};
+use crate::spatial::{GridEntry, SpatialGrid};
+use crate::spectator::Spectating;
+use crate::synctest::{synthetic_input, SyncTestSettings};
 use crate::ui::PlayerMarker;
+use crate::waves::{direct_waves, Waves};
 use crate::{
     direction, game_input, Bullet, GameState, ImageAssets, MoveDir, Player, Score, Weapon,
     BULLET_RADIUS, MAP_SIZE, PLAYER_RADIUS, REVIVE_DISTANCE,
 };
+use bevy::ecs::schedule::apply_state_transition;
+use bevy::hierarchy::despawn_with_children_recursive;
 use bevy::math::Vec3Swizzles;
 use bevy::prelude::*;
 use bevy_ggrs::{GGRSPlugin, GGRSSchedule, PlayerInputs, Rollback, RollbackIdProvider, Session};
+use ggrs::{InputStatus, PlayerHandle};
 use matchbox_socket::PeerId;
-use rand::{Rng, SeedableRng};
-use rand_chacha::ChaCha8Rng;
 use std::f32::consts::PI;
-use std::ops::Deref;
-use std::time::Duration;
+
+/// Frames GGRS may run ahead of the last confirmed frame
+pub const MAX_PREDICTION: usize = 8;
 
 pub struct NetworkingPlugin;
 
 impl Plugin for NetworkingPlugin {
     fn build(&self, app: &mut App) {
-        app.init_resource::<EnemyTimer>()
-            .init_resource::<SeedFrame>();
-        GGRSPlugin::<GgrsConfig>::new()
-            .with_input_system(game_input)
-            .register_rollback_resource::<SeedFrame>()
-            .register_rollback_component::<Transform>()
-            .register_rollback_component::<Weapon>()
-            .register_rollback_component::<Bullet>()
-            .register_rollback_component::<MoveDir>()
-            .register_rollback_component::<EnemyTimer>()
-            .register_rollback_component::<Dead>()
-            .register_rollback_component::<PlayerMarker>()
-            .build(app);
+        if app.world.contains_resource::<SyncTestSettings>() {
+            add_simulation(app, synthetic_input);
+        } else {
+            add_simulation(app, game_input);
+        }
         app.add_system(reset_interlude_timer.in_schedule(OnEnter(GameState::Interlude)))
-            .add_systems((remove_entities, reset_score).in_schedule(OnExit(GameState::Interlude)))
-            .add_system(interlude_timer.run_if(in_state(GameState::Interlude)))
-            .add_system(spawn_players.in_schedule(OnEnter(GameState::InGame)))
             .add_systems(
-                (
-                    advance_seed_frame.run_if(in_state(GameState::InGame)),
-                    spawn_enemies.run_if(in_state(GameState::InGame)),
-                    move_players.run_if(in_state(GameState::InGame)),
-                    move_bullet.run_if(in_state(GameState::InGame)),
-                    move_enemies.run_if(in_state(GameState::InGame)),
-                    fire_bullets.run_if(in_state(GameState::InGame)),
-                    kill_enemies.run_if(in_state(GameState::InGame)),
-                    bullets_hitting_players.run_if(in_state(GameState::InGame)),
-                    kill_players.run_if(in_state(GameState::InGame)),
-                    revive_players.run_if(in_state(GameState::InGame)),
-                    end_game.run_if(in_state(GameState::InGame)),
-                )
-                    .chain()
-                    .in_schedule(GGRSSchedule),
+                (remove_entities, reset_score, reset_waves)
+                    .in_schedule(OnExit(GameState::Interlude)),
+            )
+            .add_system(
+                interlude_timer
+                    .run_if(not(resource_exists::<RoundOver>()))
+                    .run_if(in_state(GameState::Interlude)),
             );
     }
 }
 
+/// Registers all rollback state and the `GGRSSchedule` systems.
+///
+/// The game reads local inputs with [`game_input`], while the headless simulation plugs in scripted inputs.
+pub fn add_simulation<Params>(
+    app: &mut App,
+    input_system: impl IntoSystem<PlayerHandle, FvzInput, Params>,
+) {
+    app.init_resource::<Waves>()
+        .init_resource::<MatchRules>()
+        .init_resource::<Obstacles>()
+        .init_resource::<SeedFrame>()
+        .init_resource::<SessionFrame>()
+        .init_resource::<NextRound>()
+        .init_resource::<RoundEnd>()
+        .init_resource::<Score>()
+        .init_resource::<RollbackSafeEvents>();
+    RollbackRegistry::new(GGRSPlugin::<GgrsConfig>::new().with_input_system(input_system))
+        .resource::<SeedFrame>()
+        .resource::<SessionFrame>()
+        .resource::<RoundEnd>()
+        .resource::<Waves>()
+        .resource::<Score>()
+        .component::<Transform>()
+        .component::<Weapon>()
+        .component::<Bullet>()
+        .component::<MoveDir>()
+        .component::<Health>()
+        .component::<Enemy>()
+        .component::<Spit>()
+        .component::<Boss>()
+        .component::<AnimationTimer>()
+        .component::<Dead>()
+        .component::<Pickup>()
+        .component::<Boosts>()
+        .component::<PlayerMarker>()
+        // deduplicated before they reach the audio system
+        .allow_unregistered::<RollbackSafeEvents>()
+        // only hands out ids, the ids themselves are stored in the rolled back `Rollback` components
+        .allow_unregistered::<RollbackIdProvider>()
+        // only read in `GGRSSchedule`, peers agree on it before the frame it names is simulated
+        .allow_unregistered::<NextRound>()
+        .build(app);
+    #[cfg(debug_assertions)]
+    app.add_startup_system(check_rollback_safety);
+    app.add_system(spawn_players.in_schedule(OnEnter(GameState::InGame)))
+        .add_system(end_round.run_if(in_state(GameState::InGame)))
+        .add_systems(
+            // more than 15 systems don't fit into one tuple
+            (
+                (
+                    start_next_round,
+                    advance_seed_frame.run_if(in_round),
+                    direct_waves.run_if(in_round),
+                    move_players.run_if(in_round),
+                    collect_pickups.run_if(in_round),
+                    move_bullet.run_if(in_round),
+                    move_enemies.run_if(in_round),
+                    separate_enemies.run_if(in_round),
+                    spit_at_players.run_if(in_round),
+                    move_spit.run_if(in_round),
+                    boss_attacks.run_if(in_round),
+                )
+                    .chain(),
+                (
+                    reload_weapons.run_if(in_round),
+                    fire_bullets.run_if(in_round),
+                    kill_enemies.run_if(in_round),
+                    bullets_hitting_players.run_if(in_round),
+                    disconnect_players.run_if(in_round),
+                    kill_players.run_if(in_round),
+                    revive_players.run_if(in_round),
+                    end_game.run_if(in_round),
+                    advance_session_frame,
+                )
+                    .chain(),
+            )
+                .chain()
+                .in_schedule(GGRSSchedule),
+        );
+}
+
+/// Keep in sync with the systems added to `GGRSSchedule` in [`add_simulation`]
+#[cfg(debug_assertions)]
+fn check_rollback_safety(world: &mut World) {
+    assert_rollback_safe(world, start_next_round);
+    assert_rollback_safe(world, advance_seed_frame);
+    assert_rollback_safe(world, direct_waves);
+    assert_rollback_safe(world, move_players);
+    assert_rollback_safe(world, collect_pickups);
+    assert_rollback_safe(world, move_bullet);
+    assert_rollback_safe(world, move_enemies);
+    assert_rollback_safe(world, separate_enemies);
+    assert_rollback_safe(world, spit_at_players);
+    assert_rollback_safe(world, move_spit);
+    assert_rollback_safe(world, boss_attacks);
+    assert_rollback_safe(world, reload_weapons);
+    assert_rollback_safe(world, fire_bullets);
+    assert_rollback_safe(world, kill_enemies);
+    assert_rollback_safe(world, bullets_hitting_players);
+    assert_rollback_safe(world, disconnect_players);
+    assert_rollback_safe(world, kill_players);
+    assert_rollback_safe(world, revive_players);
+    assert_rollback_safe(world, end_game);
+    assert_rollback_safe(world, advance_session_frame);
+}
+
 pub struct GgrsConfig;
 
 impl ggrs::Config for GgrsConfig {
-    // 4-directions + fire fits easily in a single byte
-    type Input = u8;
+    // button bits plus the quantized aim direction, see `FvzInput`
+    type Input = FvzInput;
     type State = u8;
     type Address = PeerId;
 }
 
+/// Frames between the end of a round and the start of the next one
+pub const INTERLUDE_FRAMES: u32 = 60 * 3;
+
 #[derive(Default, Resource)]
 pub struct InterludeTimer(pub usize);
 
+/// [`SessionFrame`] in which the next round starts, or the current one started.
+///
+/// Peers have to agree on it before they simulate that frame,
+/// so the host announces it to everybody with the rematch.
+#[derive(Default, Resource)]
+pub struct NextRound(pub Option<u32>);
+
+/// [`SessionFrame`] in which the last player of the current round died.
+///
+/// Rolled back, so a rollback that keeps a player alive takes it back again;
+/// [`end_round`] only leaves the round once the frame is confirmed.
+#[derive(Reflect, Default, Resource)]
+pub struct RoundEnd(pub Option<u32>);
+
+/// The results of the last round are shown; the next round waits until the players decide to play again
+#[derive(Resource)]
+pub struct RoundOver;
+
 fn reset_interlude_timer(mut timer: ResMut<InterludeTimer>) {
-    timer.0 = 60 * 3;
+    timer.0 = INTERLUDE_FRAMES as usize;
+}
+
+/// Schedules the next round, unless the host has already announced it.
+///
+/// Only sessions without remote peers get here without an announced round,
+/// all others start their first round at a fixed frame and rematches in the frame sent with them.
+fn interlude_timer(
+    timer: Res<InterludeTimer>,
+    mut next_round: ResMut<NextRound>,
+    session_frame: Res<SessionFrame>,
+) {
+    if next_round.0.map_or(false, |frame| frame >= session_frame.0) {
+        return;
+    }
+    next_round.0 = Some(session_frame.0.wrapping_add(timer.0 as u32));
 }
 
-fn interlude_timer(mut timer: ResMut<InterludeTimer>, mut state: ResMut<NextState<GameState>>) {
-    if timer.0 == 0 {
-        state.set(GameState::InGame);
+/// Starts the round in the frame named by [`NextRound`], so all peers start it in the same frame.
+///
+/// The transition runs right away instead of between frames. It isn't rolled back,
+/// so re-simulating the first frame of the round after a rollback only repeats the setup of the round.
+fn start_next_round(world: &mut World) {
+    if world.resource::<NextRound>().0 != Some(world.resource::<SessionFrame>().0) {
+        return;
+    }
+    world.resource_mut::<RoundEnd>().0 = None;
+    if world.resource::<State<GameState>>().0 == GameState::InGame {
+        world.try_run_schedule(OnExit(GameState::Interlude)).ok();
+        world.try_run_schedule(OnEnter(GameState::InGame)).ok();
     } else {
-        timer.0 -= 1;
+        world
+            .resource_mut::<NextState<GameState>>()
+            .set(GameState::InGame);
+        apply_state_transition::<GameState>(world);
     }
 }
 
+/// Like `in_state(GameState::InGame)`, but also false when re-simulating frames from before the start of the round
+/// and after the frame in which the round ended
+pub fn in_round(
+    state: Res<State<GameState>>,
+    next_round: Res<NextRound>,
+    round_end: Res<RoundEnd>,
+    session_frame: Res<SessionFrame>,
+) -> bool {
+    state.0 == GameState::InGame
+        && next_round.0.map_or(true, |start| session_frame.0 >= start)
+        && round_end.0.map_or(true, |end| session_frame.0 <= end)
+}
+
+/// Leaves the round once GGRS has confirmed the frame in which it ended, so no rollback can undo the end anymore
+fn end_round(
+    round_end: Res<RoundEnd>,
+    next_round: Res<NextRound>,
+    session: Option<Res<Session<GgrsConfig>>>,
+    mut state: ResMut<NextState<GameState>>,
+) {
+    let Some(end) = round_end.0 else {
+        return;
+    };
+    // rolled back to the end of the last round while re-simulating frames before the start of this one
+    if next_round.0.map_or(false, |start| end < start) {
+        return;
+    }
+    let confirmed = match session.as_deref() {
+        Some(Session::P2PSession(session)) => {
+            i64::from(session.confirmed_frame()) >= i64::from(end)
+        }
+        // sync tests and spectators only ever see confirmed inputs
+        Some(_) => true,
+        None => false,
+    };
+    if confirmed {
+        state.set(GameState::Interlude);
+    }
+}
+
+/// Entities belonging to the current game session, despawned by [`leave_game`]
+#[derive(Component)]
+pub struct SessionOnly;
+
+/// Drop the running session together with everything spawned for it.
+///
+/// The caller decides which state to continue in.
+pub fn leave_game(world: &mut World) {
+    let entities: Vec<Entity> = world
+        .query_filtered::<Entity, Or<(With<Rollback>, With<SessionOnly>)>>()
+        .iter(world)
+        .collect();
+    for entity in entities {
+        if world.get_entity(entity).is_some() {
+            despawn_with_children_recursive(world, entity);
+        }
+    }
+    world.remove_resource::<Session<GgrsConfig>>();
+    world.remove_resource::<GameSocket>();
+    world.remove_resource::<Host>();
+    world.remove_resource::<LocalPlayerIds>();
+    world.remove_resource::<Desync>();
+    world.remove_resource::<Playback>();
+    world.remove_resource::<Spectating>();
+    world.insert_resource(SeedFrame::default());
+    world.insert_resource(SessionFrame::default());
+    world.insert_resource(NextRound::default());
+    world.insert_resource(RoundEnd::default());
+    world.insert_resource(Score::default());
+    world.insert_resource(Waves::default());
+    world.insert_resource(RemotePlayers::default());
+    world.insert_resource(StartGame::default());
+    world.insert_resource(PeerLocalPlayers::default());
+    world.insert_resource(PlayerCounts::default());
+    let mut time = world.resource_mut::<Time>();
+    time.unpause();
+    time.set_relative_speed(1.);
+}
+
 #[derive(Component)]
 pub struct HealthBar(pub(crate) Entity);
 
@@ -93,12 +327,13 @@ pub fn spawn_players(
     mut commands: Commands,
     mut rollback_id_provider: ResMut<RollbackIdProvider>,
     player_assets: Res<PlayerAssets>,
-    session: Res<Session<GgrsConfig>>,
+    weapon_assets: Res<WeaponAssets>,
+    weapon_data: Res<Assets<WeaponData>>,
+    // rounds start in `GGRSSchedule`, which has the inputs of all players but not the session
+    inputs: Res<PlayerInputs<GgrsConfig>>,
+    rules: Res<MatchRules>,
 ) {
-    let Session::P2PSession(session) = session.deref() else {
-        panic!("wrong session type");
-    };
-    for player in 0..session.num_players() {
+    for player in 0..inputs.len() {
         let mut player_commands = commands.spawn(SpriteSheetBundle {
             transform: Transform {
                 translation: Vec3::new(0., 0., 100.),
@@ -117,9 +352,10 @@ pub fn spawn_players(
                 4,
             ))
             .insert(Player { handle: player })
-            .insert(Weapon::new())
+            .insert(Weapon::new(weapon_data.get(&weapon_assets.pistol).unwrap()))
             .insert(MoveDir(-Vec2::X))
-            .insert(Health::new(510.))
+            .insert(Boosts::default())
+            .insert(Health::new(rules.max_health))
             .insert(Rollback::new(rollback_id_provider.next_id()))
             .with_children(|parent| {
                 parent
@@ -165,7 +401,16 @@ pub fn spawn_players(
                 visibility: Visibility::Visible,
                 ..default()
             })
-            .insert(PlayerMarker(player_id));
+            .insert(PlayerMarker(player_id))
+            .insert(SessionOnly);
+    }
+}
+
+pub fn num_players(session: &Session<GgrsConfig>) -> usize {
+    match session {
+        Session::SyncTestSession(session) => session.num_players(),
+        Session::P2PSession(session) => session.num_players(),
+        Session::SpectatorSession(session) => session.num_players(),
     }
 }
 
@@ -173,21 +418,30 @@ fn reset_score(mut score: ResMut<Score>) {
     score.0 = 0.;
 }
 
+fn reset_waves(mut waves: ResMut<Waves>) {
+    *waves = Waves::default();
+}
+
 fn revive_players(
     mut commands: Commands,
     inputs: Res<PlayerInputs<GgrsConfig>>,
-    mut dead_players: Query<(Entity, &mut Transform, &mut Health), (With<Dead>, With<Player>)>,
+    mut dead_players: Query<(Entity, &Player, &mut Transform, &mut Health), With<Dead>>,
     alive_players: Query<(&Player, &Transform), Without<Dead>>,
-    mut health_bars: Query<(&Parent, &mut Visibility), (With<HealthBarParent>, Without<Player>)>,
     mut rollback_safe_events: ResMut<RollbackSafeEvents>,
+    rules: Res<MatchRules>,
 ) {
+    if !rules.revive {
+        return;
+    }
     for (player, transform) in alive_players.iter() {
         let (input, _) = inputs[player.handle];
         if input.is_revive() {
-            if let Some((dead_player, mut dead_transform, mut health)) =
-                dead_players.iter_mut().reduce(|current, closest| {
-                    if transform.translation.distance(current.1.translation)
-                        < transform.translation.distance(closest.1.translation)
+            if let Some((dead_player, _, mut dead_transform, mut health)) = dead_players
+                .iter_mut()
+                .filter(|(_, dead, _, _)| inputs[dead.handle].1 != InputStatus::Disconnected)
+                .reduce(|current, closest| {
+                    if transform.translation.distance(current.2.translation)
+                        < transform.translation.distance(closest.2.translation)
                     {
                         current
                     } else {
@@ -204,28 +458,24 @@ fn revive_players(
                 ));
                 commands.entity(dead_player).remove::<Dead>();
                 dead_transform.rotation = Quat::from_rotation_z(0.);
-                health.current = health.max * 0.8;
-                if let Some((_, mut visibility)) = health_bars
-                    .iter_mut()
-                    .find(|(parent, _)| parent.get() == dead_player)
-                {
-                    *visibility = Visibility::Visible;
-                }
+                health.current = health.max * rules.revive_health;
             }
         }
     }
 }
 
-fn end_game(
+/// The round ends in the frame the last player dies, see [`end_round`]
+pub fn end_game(
     alive_players: Query<&Player, Without<Dead>>,
-    mut state: ResMut<NextState<GameState>>,
+    session_frame: Res<SessionFrame>,
+    mut round_end: ResMut<RoundEnd>,
     mut rollback_safe_events: ResMut<RollbackSafeEvents>,
 ) {
     if alive_players.is_empty() {
         rollback_safe_events
             .0
             .push(SafeEvent::new(FvzEvent::Lost, 0));
-        state.set(GameState::Interlude);
+        round_end.0 = Some(session_frame.0);
     }
 }
 
@@ -235,6 +485,8 @@ fn remove_entities(
     bullet_query: Query<Entity, With<Bullet>>,
     enemy_query: Query<Entity, With<Enemy>>,
     marker_query: Query<Entity, With<PlayerMarker>>,
+    pickup_query: Query<Entity, With<Pickup>>,
+    spit_query: Query<Entity, With<Spit>>,
 ) {
     for player in player_query.iter() {
         commands.entity(player).despawn_recursive();
@@ -248,32 +500,58 @@ fn remove_entities(
     for marker in marker_query.iter() {
         commands.entity(marker).despawn_recursive();
     }
+    for pickup in pickup_query.iter() {
+        commands.entity(pickup).despawn_recursive();
+    }
+    for spit in spit_query.iter() {
+        commands.entity(spit).despawn_recursive();
+    }
 }
 
 fn bullets_hitting_players(
     mut commands: Commands,
     mut player_query: Query<
-        (Entity, &Transform, &mut Health),
-        (With<Player>, Without<Bullet>, Without<Dead>),
+        (Entity, &Player, &Transform, &mut Health),
+        (Without<Bullet>, Without<Dead>),
     >,
-    mut bullet_query: Query<(Entity, &Transform, &mut Bullet)>,
+    mut bullet_query: Query<(Entity, &Rollback, &Transform, &mut Bullet)>,
     mut rollback_safe_events: ResMut<RollbackSafeEvents>,
+    rules: Res<MatchRules>,
+    mut grid: Local<SpatialGrid>,
 ) {
-    'bullets: for (bullet_entity, bullet_transform, mut bullet) in bullet_query.iter_mut() {
-        if bullet.is_used_up() {
+    let damage_factor = match rules.friendly_fire {
+        FriendlyFire::Off => return,
+        FriendlyFire::Reduced => REDUCED_FRIENDLY_FIRE,
+        FriendlyFire::Full => 1.,
+    };
+    grid.rebuild(
+        player_query
+            .iter()
+            .map(|(entity, player, transform, _)| GridEntry {
+                id: player.handle as u32,
+                entity,
+                position: transform.translation.xy(),
+                radius: PLAYER_RADIUS,
+            }),
+    );
+    let mut bullets: Vec<_> = bullet_query.iter_mut().collect();
+    bullets.sort_unstable_by_key(|(_, rollback, ..)| rollback.id());
+
+    'bullets: for (bullet_entity, _, bullet_transform, mut bullet) in bullets {
+        if bullet.is_used_up() || bullet.is_spent() {
             continue;
         }
-        for (player, player_transform, mut health) in player_query.iter_mut() {
-            let distance = Vec2::distance(
-                player_transform.translation.xy(),
-                bullet_transform.translation.xy(),
-            );
-            if distance < PLAYER_RADIUS + BULLET_RADIUS && bullet.hit(player) {
+        let position = bullet_transform.translation.xy();
+        for index in grid.near(position, BULLET_RADIUS) {
+            let entry = grid.get(index);
+            let distance = entry.position.distance(position);
+            if distance < PLAYER_RADIUS + BULLET_RADIUS && bullet.hit(entry.entity) {
                 rollback_safe_events.0.push(SafeEvent::new(
                     FvzEvent::PlayerHitBullet,
-                    (3 * bullet_entity.index()).wrapping_add(player.index()),
+                    (3 * bullet_entity.index()).wrapping_add(entry.entity.index()),
                 ));
-                health.current -= bullet.damage;
+                let (.., mut health) = player_query.get_mut(entry.entity).unwrap();
+                health.current -= bullet.damage * damage_factor;
                 if bullet.is_used_up() {
                     commands.entity(bullet_entity).despawn_recursive();
                     continue 'bullets;
@@ -283,24 +561,26 @@ fn bullets_hitting_players(
     }
 }
 
+/// GGRS agrees on the frame a peer disconnected, so all remaining peers kill its player in the same frame
+fn disconnect_players(
+    inputs: Res<PlayerInputs<GgrsConfig>>,
+    mut player_query: Query<(&Player, &mut Health), Without<Dead>>,
+) {
+    for (player, mut health) in player_query.iter_mut() {
+        if inputs[player.handle].1 == InputStatus::Disconnected {
+            health.current = 0.;
+        }
+    }
+}
+
 fn kill_players(
     mut commands: Commands,
-    mut player_query: Query<
-        (Entity, &mut Transform, &mut Health),
-        (With<Player>, Without<Dead>, Without<HealthBarParent>),
-    >,
-    mut health_bars: Query<(&Parent, &mut Visibility), With<HealthBarParent>>,
+    mut player_query: Query<(Entity, &mut Transform, &mut Health), (With<Player>, Without<Dead>)>,
 ) {
     for (player, mut player_transform, mut health) in player_query.iter_mut() {
         if health.current <= 0. {
             health.current = 0.;
             commands.entity(player).insert(Dead);
-            if let Some((_, mut visibility)) = health_bars
-                .iter_mut()
-                .find(|(parent, _)| parent.get() == player)
-            {
-                *visibility = Visibility::Hidden;
-            }
             player_transform.rotation = Quat::from_rotation_z(PI / 2.);
         }
     }
@@ -319,6 +599,7 @@ fn move_players(
         &mut AnimationTimer,
     )>,
     dead: Query<&Dead>,
+    obstacles: Res<Obstacles>,
 ) {
     for (player_entity, mut transform, mut move_direction, player, mut animation_timer) in
         player_query.iter_mut()
@@ -346,130 +627,50 @@ fn move_players(
         let old_pos = transform.translation.xy();
         let limit = Vec2::splat(MAP_SIZE as f32 / 2. - 0.5);
         let new_pos = (old_pos + move_delta).clamp(-limit, limit);
+        let new_pos = obstacles.push_out(new_pos, PLAYER_RADIUS);
 
         transform.translation.x = new_pos.x;
         transform.translation.y = new_pos.y;
     }
 }
 
-fn advance_seed_frame(mut frame: ResMut<SeedFrame>) {
+pub fn advance_seed_frame(mut frame: ResMut<SeedFrame>) {
     frame.0 = frame.0.wrapping_add(1);
 }
 
 #[derive(Reflect, Default, Resource)]
 pub struct SeedFrame(pub(crate) u32);
 
-#[derive(Reflect, Component, Resource)]
-struct EnemyTimer(Timer);
-
-impl Default for EnemyTimer {
-    fn default() -> Self {
-        Self(Timer::new(
-            Duration::from_secs_f32(5.),
-            TimerMode::Repeating,
-        ))
-    }
+/// Runs last, also between rounds, so the frame stays in step with the session
+pub fn advance_session_frame(mut frame: ResMut<SessionFrame>) {
+    frame.0 = frame.0.wrapping_add(1);
 }
 
-fn spawn_enemies(
-    mut commands: Commands,
-    seed: Res<Seed>,
-    enemy_assets: Res<EnemyAssets>,
-    enemy_data: Res<Assets<EnemyData>>,
+/// Number of the GGRS frame being simulated, counted from the start of the session.
+///
+/// Unlike [`SeedFrame`] it keeps advancing between rounds.
+#[derive(Reflect, Default, Resource)]
+pub struct SessionFrame(pub(crate) u32);
+
+/// Reload on input or when trying to fire with an empty magazine
+/// Players drop an empty weapon for the pistol, which never runs out of spare ammo
+fn reload_weapons(
+    inputs: Res<PlayerInputs<GgrsConfig>>,
     seed_frame: Res<SeedFrame>,
-    mut enemy_timer: ResMut<EnemyTimer>,
-    mut rollback_id_provider: ResMut<RollbackIdProvider>,
+    weapon_assets: Res<WeaponAssets>,
+    weapon_data: Res<Assets<WeaponData>>,
+    mut player_query: Query<(&Player, &mut Weapon), Without<Dead>>,
 ) {
-    enemy_timer.0.tick(Duration::from_secs_f32(1. / 60.));
-    if !enemy_timer.0.just_finished() {
-        return;
+    for (player, mut weapon) in player_query.iter_mut() {
+        weapon.finish_reload(&seed_frame);
+        if weapon.is_empty() {
+            weapon.swap(weapon_data.get(&weapon_assets.pistol).unwrap());
+        }
+        let (input, _) = inputs[player.handle];
+        if input.is_reload() || (input.is_fire() && weapon.ammo == 0) {
+            weapon.reload(&seed_frame);
+        }
     }
-    let seed: [u8; 32] = [
-        (seed_frame.0 >> 8) as u8,
-        seed_frame.0 as u8,
-        seed.0[1],
-        seed.0[2],
-        (seed_frame.0 >> 16) as u8,
-        (seed_frame.0 >> 24) as u8,
-        seed.0[1],
-        seed.0[2],
-    ]
-    .repeat(4)
-    .try_into()
-    .unwrap();
-    let mut rng = ChaCha8Rng::from_seed(seed);
-    let translation = Vec3::new(
-        rng.gen_range(0..MAP_SIZE) as f32 - MAP_SIZE as f32 / 2.,
-        rng.gen_range(0..MAP_SIZE) as f32 - MAP_SIZE as f32 / 2.,
-        100.,
-    );
-    let enemy_index = rng.gen_range(0..100);
-    info!("Spawning enemy at {:?}", translation);
-    spawn_enemy(
-        &mut commands,
-        &enemy_assets,
-        &mut rollback_id_provider,
-        &enemy_data,
-        translation,
-        enemy_index,
-    );
-}
-
-fn spawn_enemy(
-    commands: &mut Commands,
-    enemy_assets: &EnemyAssets,
-    rollback_id_provider: &mut RollbackIdProvider,
-    enemy_data: &Assets<EnemyData>,
-    translation: Vec3,
-    enemy_index: i32,
-) {
-    let enemy = enemy_data.get(enemy_assets.get(enemy_index)).unwrap();
-    let mut enemy_commands = commands.spawn(SpriteSheetBundle {
-        transform: Transform {
-            translation,
-            scale: Vec3::splat(0.01),
-            ..Default::default()
-        },
-        sprite: TextureAtlasSprite::new(0),
-        texture_atlas: enemy.texture_atlas.clone(),
-        ..Default::default()
-    });
-    let enemy_id = enemy_commands.id();
-    enemy_commands
-        .insert(Health::new(enemy.health))
-        .insert(Enemy {
-            damage: enemy.damage,
-            speed: enemy.speed,
-            attack_cooldown: enemy.attack_cooldown as u32,
-            last_attack: 0,
-        })
-        .insert(AnimationTimer(
-            Timer::from_seconds(0.1, TimerMode::Repeating),
-            4,
-        ))
-        .insert(Rollback::new(rollback_id_provider.next_id()))
-        .with_children(|parent| {
-            parent.spawn(SpriteBundle {
-                sprite: Sprite {
-                    color: Color::DARK_GRAY,
-                    custom_size: Some(Vec2::new(100., 5.1)),
-                    ..default()
-                },
-                transform: Transform::from_translation(Vec3::new(0., 50., 1.)),
-                ..default()
-            });
-            parent
-                .spawn(SpriteBundle {
-                    sprite: Sprite {
-                        color: Color::RED,
-                        custom_size: Some(Vec2::new(100., 5.1)),
-                        ..default()
-                    },
-                    transform: Transform::from_translation(Vec3::new(0., 50., 2.)),
-                    ..default()
-                })
-                .insert(HealthBar(enemy_id));
-        });
 }
 
 fn fire_bullets(
@@ -477,38 +678,64 @@ fn fire_bullets(
     inputs: Res<PlayerInputs<GgrsConfig>>,
     images: Res<ImageAssets>,
     seed_frame: Res<SeedFrame>,
-    mut player_query: Query<(Entity, &Transform, &Player, &mut Weapon, &MoveDir), Without<Dead>>,
+    mut player_query: Query<
+        (Entity, &Transform, &Player, &mut Weapon, &MoveDir, &Boosts),
+        Without<Dead>,
+    >,
     mut rip: ResMut<RollbackIdProvider>,
     mut rollback_safe_events: ResMut<RollbackSafeEvents>,
 ) {
-    for (entity, transform, player, mut weapon, move_dir) in player_query.iter_mut() {
+    for (entity, transform, player, mut weapon, move_dir, boosts) in player_query.iter_mut() {
         let (input, _) = inputs[player.handle];
-        if input.is_fire() && weapon.shoot(&seed_frame) {
+        if input.is_fire() && weapon.shoot(&seed_frame, boosts) {
+            let damage_factor = boosts.damage_factor(&seed_frame);
             rollback_safe_events.0.push(SafeEvent::new(
                 FvzEvent::Pew,
                 (2 * entity.index()).wrapping_add(seed_frame.0),
             ));
-            commands
-                .spawn(SpriteBundle {
-                    transform: Transform::from_translation(transform.translation.xy().extend(200.))
-                        .with_rotation(Quat::from_rotation_arc_2d(Vec2::X, move_dir.0)),
-                    texture: images.bullet.clone(),
-                    sprite: Sprite {
-                        custom_size: Some(Vec2::new(0.3, 0.1)),
+            let aim = input.aim().unwrap_or(move_dir.0);
+            for direction in weapon.projectile_directions(aim) {
+                commands
+                    .spawn(SpriteBundle {
+                        transform: Transform::from_translation(
+                            transform.translation.xy().extend(200.),
+                        )
+                        .with_rotation(Quat::from_rotation_arc_2d(Vec2::X, direction)),
+                        texture: images.bullet.clone(),
+                        sprite: Sprite {
+                            custom_size: Some(Vec2::new(0.3, 0.1)),
+                            ..default()
+                        },
                         ..default()
-                    },
-                    ..default()
-                })
-                .insert(*move_dir)
-                .insert(Bullet::fire(50., entity))
-                .insert(Rollback::new(rip.next_id()));
+                    })
+                    .insert(MoveDir(direction))
+                    .insert(Bullet {
+                        damage: weapon.damage * damage_factor,
+                        ..Bullet::fire(&weapon, entity)
+                    })
+                    .insert(Rollback::new(rip.next_id()));
+            }
         }
     }
 }
 
-fn move_bullet(mut query: Query<(&mut Transform, &MoveDir), With<Bullet>>) {
-    for (mut transform, dir) in query.iter_mut() {
-        let delta = (dir.0 * 0.35).extend(0.);
+fn move_bullet(
+    mut commands: Commands,
+    mut query: Query<(Entity, &mut Transform, &MoveDir, &mut Bullet)>,
+    obstacles: Res<Obstacles>,
+) {
+    for (entity, mut transform, dir, mut bullet) in query.iter_mut() {
+        if bullet.is_spent() {
+            commands.entity(entity).despawn_recursive();
+            continue;
+        }
+        let delta = (dir.0 * bullet.speed).extend(0.);
         transform.translation += delta;
+        bullet.range -= bullet.speed;
+        if obstacles.blocks(transform.translation.xy(), BULLET_RADIUS) {
+            // the despawn is deferred, so the hit systems of this frame have to skip it
+            bullet.range = 0.;
+            commands.entity(entity).despawn_recursive();
+        }
     }
 }
@@ -0,0 +1,190 @@
+use crate::enemies::{FvzEvent, RollbackSafeEvents, SafeEvent};
+use crate::loading::{WeaponAssets, WeaponData};
+use crate::matchmaking::{RandomStream, Seed};
+use crate::networking::{Dead, SeedFrame};
+use crate::players::{Health, Player, Weapon};
+use crate::PLAYER_RADIUS;
+use bevy::math::Vec3Swizzles;
+use bevy::prelude::*;
+use bevy_ggrs::{Rollback, RollbackIdProvider};
+use rand::Rng;
+
+const PICKUP_RADIUS: f32 = 0.3;
+/// Chance of a killed enemy to drop something
+const DROP_CHANCE: f64 = 0.25;
+/// Frames until an uncollected pickup vanishes
+const PICKUP_LIFETIME: u32 = 15 * 60;
+const BOOST_DURATION: u32 = 10 * 60;
+const HEAL_FRACTION: f64 = 0.3;
+const AMMO_PER_PICKUP: u32 = 30;
+
+/// Kinds of pickups with their relative drop weight
+const DROPS: [(PickupKind, u32); 7] = [
+    (PickupKind::Health, 30),
+    (PickupKind::Ammo, 30),
+    (PickupKind::DamageBoost, 10),
+    (PickupKind::FireRateBoost, 10),
+    (PickupKind::Shotgun, 7),
+    (PickupKind::Rifle, 7),
+    (PickupKind::PiercingGun, 6),
+];
+
+#[derive(Reflect, Default, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum PickupKind {
+    #[default]
+    Health,
+    Ammo,
+    DamageBoost,
+    FireRateBoost,
+    Shotgun,
+    Rifle,
+    PiercingGun,
+}
+
+impl PickupKind {
+    fn color(&self) -> Color {
+        match self {
+            PickupKind::Health => Color::rgb(0.9, 0.1, 0.1),
+            PickupKind::Ammo => Color::rgb(0.9, 0.8, 0.1),
+            PickupKind::DamageBoost => Color::rgb(0.9, 0.4, 0.),
+            PickupKind::FireRateBoost => Color::rgb(0.1, 0.6, 0.9),
+            PickupKind::Shotgun | PickupKind::Rifle | PickupKind::PiercingGun => {
+                Color::rgb(0.2, 0.2, 0.2)
+            }
+        }
+    }
+}
+
+#[derive(Component, Reflect, Default)]
+pub struct Pickup {
+    pub kind: PickupKind,
+    pub expires: u32,
+}
+
+/// Temporary boosts of a player, active until the given frame
+#[derive(Component, Reflect, Default)]
+pub struct Boosts {
+    pub damage_until: u32,
+    pub fire_rate_until: u32,
+}
+
+impl Boosts {
+    pub fn damage_factor(&self, seed_frame: &SeedFrame) -> f64 {
+        if self.damage_until > seed_frame.0 {
+            2.
+        } else {
+            1.
+        }
+    }
+
+    pub fn cooldown_factor(&self, seed_frame: &SeedFrame) -> f32 {
+        if self.fire_rate_until > seed_frame.0 {
+            0.5
+        } else {
+            1.
+        }
+    }
+}
+
+/// Maybe drop a pickup where an enemy died.
+///
+/// The random stream depends on the position instead of the order in which enemies die,
+/// so re-simulations after a rollback drop the same pickups.
+pub fn drop_pickup(
+    commands: &mut Commands,
+    rollback_id_provider: &mut RollbackIdProvider,
+    seed: &Seed,
+    seed_frame: &SeedFrame,
+    position: Vec2,
+) {
+    let position_hash = position.x.to_bits().rotate_left(16) ^ position.y.to_bits();
+    let mut rng = seed.rng(RandomStream::Drop {
+        position: position_hash,
+        frame: seed_frame.0,
+    });
+    if !rng.gen_bool(DROP_CHANCE) {
+        return;
+    }
+    let mut roll = rng.gen_range(0..DROPS.iter().map(|(_, weight)| weight).sum::<u32>());
+    let kind = DROPS
+        .iter()
+        .find(|(_, weight)| {
+            let found = roll < *weight;
+            roll = roll.saturating_sub(*weight);
+            found
+        })
+        .map(|(kind, _)| *kind)
+        .unwrap();
+
+    commands
+        .spawn(SpriteBundle {
+            transform: Transform::from_translation(position.extend(50.))
+                .with_rotation(Quat::from_rotation_z(std::f32::consts::FRAC_PI_4)),
+            sprite: Sprite {
+                color: kind.color(),
+                custom_size: Some(Vec2::splat(PICKUP_RADIUS)),
+                ..default()
+            },
+            ..default()
+        })
+        .insert(Pickup {
+            kind,
+            expires: seed_frame.0 + PICKUP_LIFETIME,
+        })
+        .insert(Rollback::new(rollback_id_provider.next_id()));
+}
+
+/// The closest living player collects a pickup by walking over it
+pub fn collect_pickups(
+    mut commands: Commands,
+    pickups: Query<(Entity, &Transform, &Pickup)>,
+    mut players: Query<(&Player, &Transform, &mut Health, &mut Weapon, &mut Boosts), Without<Dead>>,
+    seed_frame: Res<SeedFrame>,
+    weapon_assets: Res<WeaponAssets>,
+    weapon_data: Res<Assets<WeaponData>>,
+    mut rollback_safe_events: ResMut<RollbackSafeEvents>,
+) {
+    // the order matters when one player collects several pickups at once
+    let mut pickups: Vec<_> = pickups.iter().collect();
+    pickups.sort_by(|(_, a, _), (_, b, _)| {
+        (a.translation.x.total_cmp(&b.translation.x))
+            .then(a.translation.y.total_cmp(&b.translation.y))
+    });
+    for (entity, transform, pickup) in pickups {
+        if pickup.expires <= seed_frame.0 {
+            commands.entity(entity).despawn_recursive();
+            continue;
+        }
+        let position = transform.translation.xy();
+        let Some((player, _, mut health, mut weapon, mut boosts)) = players
+            .iter_mut()
+            .map(|player| (player.1.translation.xy().distance(position), player))
+            .filter(|(distance, _)| *distance < PLAYER_RADIUS + PICKUP_RADIUS)
+            .min_by(|(a, first), (b, second)| {
+                a.total_cmp(b).then(first.0.handle.cmp(&second.0.handle))
+            })
+            .map(|(_, player)| player)
+        else {
+            continue;
+        };
+
+        match pickup.kind {
+            PickupKind::Health => {
+                health.current = (health.current + health.max * HEAL_FRACTION).min(health.max)
+            }
+            PickupKind::Ammo => weapon.reserve_ammo += AMMO_PER_PICKUP,
+            PickupKind::DamageBoost => boosts.damage_until = seed_frame.0 + BOOST_DURATION,
+            PickupKind::FireRateBoost => boosts.fire_rate_until = seed_frame.0 + BOOST_DURATION,
+            PickupKind::Shotgun => weapon.swap(weapon_data.get(&weapon_assets.shotgun).unwrap()),
+            PickupKind::Rifle => weapon.swap(weapon_data.get(&weapon_assets.rifle).unwrap()),
+            PickupKind::PiercingGun => {
+                weapon.swap(weapon_data.get(&weapon_assets.piercing_gun).unwrap())
+            }
+        }
+        rollback_safe_events.0.push(SafeEvent::new(
+            FvzEvent::Pickup,
+            (7 * entity.index()).wrapping_add(player.handle as u32),
+        ));
+        commands.entity(entity).despawn_recursive();
+    }
+}
@@ -1,4 +1,7 @@
+use crate::loading::WeaponData;
+use crate::local_coop::{HudCamera, InputDevice, PlayerCamera};
 use crate::networking::SeedFrame;
+use crate::pickups::Boosts;
 use crate::GameState;
 use bevy::prelude::*;
 
@@ -13,15 +16,33 @@ impl Plugin for PlayersPlugin {
     }
 }
 
+/// Handles of the players on this machine and where each of them gets input from
 #[derive(Resource)]
-pub struct LocalPlayerId(pub usize);
+pub struct LocalPlayerIds(pub Vec<(usize, InputDevice)>);
+
+impl LocalPlayerIds {
+    pub fn single(handle: usize) -> Self {
+        LocalPlayerIds(vec![(handle, InputDevice::Any)])
+    }
+
+    pub fn handles(&self) -> impl Iterator<Item = usize> + '_ {
+        self.0.iter().map(|(handle, _)| *handle)
+    }
+
+    pub fn device(&self, handle: usize) -> Option<InputDevice> {
+        self.0
+            .iter()
+            .find(|(local, _)| *local == handle)
+            .map(|(_, device)| *device)
+    }
+}
 
 #[derive(Component)]
 pub struct Player {
     pub handle: usize,
 }
 
-#[derive(Component)]
+#[derive(Component, Reflect, Default)]
 pub struct Health {
     pub max: f64,
     pub current: f64,
@@ -36,23 +57,114 @@ impl Health {
     }
 }
 
+/// The stats of the equipped weapon are copied into the component, so they are rolled back with it
 #[derive(Component, Reflect, Default)]
 pub struct Weapon {
-    fire_frame: u32,
-    frame_cooldown: u32,
+    pub fire_frame: u32,
+    pub frame_cooldown: u32,
+    pub damage: f64,
+    pub speed: f32,
+    pub spread: f32,
+    pub projectiles: u32,
+    pub pierce: usize,
+    pub range: f32,
+    /// Shots left in the magazine
+    pub ammo: u32,
+    pub magazine: u32,
+    /// Frames a reload takes
+    pub reload_time: u32,
+    /// The reload finishes in this frame; 0 while not reloading
+    pub reload_frame: u32,
+    /// Spare ammo to refill the magazine from
+    pub reserve_ammo: u32,
+    pub unlimited_reserve: bool,
 }
 
 impl Weapon {
-    pub fn new() -> Self {
+    pub fn new(data: &WeaponData) -> Self {
         Weapon {
             fire_frame: 0,
-            frame_cooldown: 30,
+            frame_cooldown: data.cooldown,
+            damage: data.damage,
+            speed: data.speed,
+            spread: data.spread,
+            projectiles: data.projectiles,
+            pierce: data.pierce,
+            range: data.range,
+            ammo: data.magazine,
+            magazine: data.magazine,
+            reload_time: data.reload_time,
+            reload_frame: 0,
+            reserve_ammo: data.reserve.unwrap_or(0),
+            unlimited_reserve: data.reserve.is_none(),
+        }
+    }
+
+    /// Switch to another weapon with its own magazine and spare ammo
+    pub fn swap(&mut self, data: &WeaponData) {
+        *self = Weapon {
+            fire_frame: self.fire_frame,
+            ..Weapon::new(data)
+        };
+    }
+
+    /// Out of shots with nothing left to reload
+    pub fn is_empty(&self) -> bool {
+        self.ammo == 0 && !self.is_reloading() && !self.unlimited_reserve && self.reserve_ammo == 0
+    }
+
+    pub fn is_reloading(&self) -> bool {
+        self.reload_frame != 0
+    }
+
+    /// Start reloading if the magazine isn't full and there is ammo to reload
+    pub fn reload(&mut self, seed_frame: &SeedFrame) {
+        if self.is_reloading()
+            || self.ammo >= self.magazine
+            || (!self.unlimited_reserve && self.reserve_ammo == 0)
+        {
+            return;
+        }
+        self.reload_frame = seed_frame.0 + self.reload_time.max(1);
+    }
+
+    /// Refill the magazine once a running reload is done
+    pub fn finish_reload(&mut self, seed_frame: &SeedFrame) {
+        if !self.is_reloading() || self.reload_frame > seed_frame.0 {
+            return;
         }
+        self.reload_frame = 0;
+        let missing = self.magazine.saturating_sub(self.ammo);
+        let refill = if self.unlimited_reserve {
+            missing
+        } else {
+            missing.min(self.reserve_ammo)
+        };
+        if !self.unlimited_reserve {
+            self.reserve_ammo -= refill;
+        }
+        self.ammo += refill;
+    }
+
+    /// Directions of the projectiles of one shot, evenly fanned out over the spread
+    pub fn projectile_directions(&self, aim: Vec2) -> impl Iterator<Item = Vec2> + '_ {
+        (0..self.projectiles).map(move |index| {
+            if self.projectiles == 1 {
+                return aim;
+            }
+            let angle = self.spread * (index as f32 / (self.projectiles - 1) as f32 - 0.5);
+            Vec2::from_angle(angle).rotate(aim)
+        })
     }
 
-    pub fn shoot(&mut self, seed_frame: &SeedFrame) -> bool {
-        if self.fire_frame.wrapping_add(self.frame_cooldown) < seed_frame.0 {
+    pub fn shoot(&mut self, seed_frame: &SeedFrame, boosts: &Boosts) -> bool {
+        let cooldown = (self.frame_cooldown as f32 * boosts.cooldown_factor(seed_frame)) as u32;
+        if self.ammo > 0
+            && !self.is_reloading()
+            && self.fire_frame.wrapping_add(cooldown) < seed_frame.0
+        {
             self.fire_frame = seed_frame.0;
+            self.ammo -= 1;
             true
         } else {
             false
@@ -63,28 +175,72 @@ impl Weapon {
 #[derive(Component, Reflect, Default, Clone, Copy)]
 pub struct MoveDir(pub Vec2);
 
+/// Room kept around the local players when the shared camera zooms out
+const CAMERA_MARGIN: f32 = 3.;
+
+/// In split screen every camera follows its own player, otherwise the camera frames all local players
 fn camera_follow(
-    player_handle: Option<Res<LocalPlayerId>>,
+    local_players: Option<Res<LocalPlayerIds>>,
     player_query: Query<(&Player, &Transform)>,
-    mut camera_query: Query<&mut Transform, (With<Camera>, Without<Player>)>,
+    mut main_camera: Query<
+        (&mut Transform, &mut OrthographicProjection),
+        (
+            With<Camera>,
+            Without<Player>,
+            Without<PlayerCamera>,
+            Without<HudCamera>,
+        ),
+    >,
+    mut player_cameras: Query<(&mut Transform, &PlayerCamera), Without<Player>>,
 ) {
-    let player_handle = match player_handle {
-        Some(handle) => handle.0,
-        None => return, // Session hasn't started yet
+    let Some(local_players) = local_players else {
+        return; // Session hasn't started yet
+    };
+    let position = |handle: usize| {
+        player_query
+            .iter()
+            .find(|(player, _)| player.handle == handle)
+            .map(|(_, transform)| transform.translation.truncate())
     };
 
-    for (player, player_transform) in player_query.iter() {
-        if player.handle != player_handle {
-            continue;
-        }
-
-        let pos = player_transform.translation;
-
-        for mut transform in camera_query.iter_mut() {
+    for (mut transform, PlayerCamera(handle)) in &mut player_cameras {
+        if let Some(pos) = position(*handle) {
             transform.translation.x = pos.x;
             transform.translation.y = pos.y;
         }
     }
+
+    let Ok((mut transform, mut projection)) = main_camera.get_single_mut() else {
+        return;
+    };
+    let followed: Vec<Vec2> = if player_cameras.is_empty() {
+        local_players.handles().filter_map(position).collect()
+    } else {
+        local_players
+            .handles()
+            .take(1)
+            .filter_map(position)
+            .collect()
+    };
+    if followed.is_empty() {
+        return;
+    }
+    let min = followed.iter().copied().reduce(Vec2::min).unwrap();
+    let max = followed.iter().copied().reduce(Vec2::max).unwrap();
+    let center = (min + max) / 2.;
+    transform.translation.x = center.x;
+    transform.translation.y = center.y;
+
+    // the area is the visible part of the world at scale 1
+    let view = projection.area.size() / projection.scale;
+    if view.min_element() <= 0. {
+        return;
+    }
+    let needed = max - min + Vec2::splat(2. * CAMERA_MARGIN);
+    let scale = (needed / view).max_element().max(1.);
+    if projection.scale != scale {
+        projection.scale = scale;
+    }
 }
 
 #[derive(Component, Reflect, Default)]
@@ -105,3 +261,69 @@ fn animate_sprites(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loading::CustomDynamicAssetCollection;
+
+    fn weapon_data(key: &str) -> WeaponData {
+        let weapons: CustomDynamicAssetCollection =
+            ron::from_str(include_str!("../assets/weapons.my-assets")).unwrap();
+        weapons.0[key].weapon_data().unwrap()
+    }
+
+    #[test]
+    fn weapon_stats_are_converted_to_frames_and_radians() {
+        let shotgun = weapon_data("shotgun");
+
+        assert_eq!(weapon_data("pistol").cooldown, 30);
+        assert_eq!(shotgun.reload_time, 120);
+        assert!((shotgun.spread - 40f32.to_radians()).abs() < 1e-6);
+        assert_eq!(shotgun.projectiles, 6);
+        assert_eq!(shotgun.reserve, Some(24));
+        assert_eq!(weapon_data("pistol").reserve, None);
+        assert_eq!(weapon_data("piercing_gun").pierce, 5);
+    }
+
+    #[test]
+    fn new_weapons_start_with_a_full_magazine() {
+        let rifle = Weapon::new(&weapon_data("rifle"));
+
+        assert_eq!(rifle.frame_cooldown, 10);
+        assert_eq!(rifle.ammo, 30);
+        assert_eq!(rifle.magazine, 30);
+        assert_eq!(rifle.reserve_ammo, 90);
+        assert!(!rifle.unlimited_reserve);
+        assert!(Weapon::new(&weapon_data("pistol")).unlimited_reserve);
+    }
+
+    #[test]
+    fn projectiles_fan_out_evenly_around_the_aim() {
+        let shotgun = Weapon::new(&weapon_data("shotgun"));
+        let directions: Vec<Vec2> = shotgun.projectile_directions(Vec2::X).collect();
+
+        assert_eq!(directions.len(), 6);
+        let half_spread = shotgun.spread / 2.;
+        assert!((Vec2::X.angle_between(directions[0]) + half_spread).abs() < 1e-5);
+        assert!((Vec2::X.angle_between(directions[5]) - half_spread).abs() < 1e-5);
+        for (first, last) in directions.iter().zip(directions.iter().rev()) {
+            assert!((first.y + last.y).abs() < 1e-5);
+        }
+
+        let pistol = Weapon::new(&weapon_data("pistol"));
+        let aim = Vec2::new(0.6, 0.8);
+        assert_eq!(pistol.projectile_directions(aim).collect::<Vec<_>>(), [aim]);
+    }
+
+    #[test]
+    fn shots_wait_for_the_cooldown() {
+        let mut pistol = Weapon::new(&weapon_data("pistol"));
+        let boosts = Boosts::default();
+
+        assert!(pistol.shoot(&SeedFrame(31), &boosts));
+        assert!(!pistol.shoot(&SeedFrame(61), &boosts));
+        assert!(pistol.shoot(&SeedFrame(62), &boosts));
+        assert_eq!(pistol.ammo, 10);
+    }
+}
@@ -0,0 +1,497 @@
+use crate::input::FvzInput;
+use crate::loading::FontAssets;
+use crate::matchmaking::Seed;
+use crate::networking::{
+    advance_session_frame, num_players, GgrsConfig, InterludeTimer, SeedFrame, SessionFrame,
+    SessionOnly,
+};
+use crate::players::LocalPlayerIds;
+use crate::rules::MatchRules;
+use crate::GameState;
+use bevy::prelude::*;
+use bevy_ggrs::{GGRSSchedule, PlayerInputs, Session};
+use ggrs::{PlayerHandle, SessionBuilder};
+
+const MAGIC: &[u8; 4] = b"FVZR";
+/// Bumped whenever the file layout or the simulation of a replay changes within a game version.
+///
+/// 2: random streams derived from the seed changed, which also moved map tiles and obstacles
+const FORMAT_VERSION: u8 = 2;
+#[cfg(not(target_arch = "wasm32"))]
+const REPLAY_FILE: &str = "last_round.fvzreplay";
+const FAST_FORWARD_SPEED: f32 = 4.;
+
+pub struct ReplayPlugin;
+
+impl Plugin for ReplayPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ReplayRecorder>();
+        #[cfg(not(target_arch = "wasm32"))]
+        app.add_startup_system(load_last_replay);
+        app.add_system(start_recording.in_schedule(OnEnter(GameState::InGame)))
+            .add_system(
+                record_inputs
+                    .before(advance_session_frame)
+                    .run_if(not(resource_exists::<Playback>()))
+                    .in_schedule(GGRSSchedule),
+            )
+            .add_system(finish_recording.in_schedule(OnEnter(GameState::Interlude)))
+            .add_systems(
+                (confirm_inputs, save_replay)
+                    .chain()
+                    .run_if(resource_exists::<Session<GgrsConfig>>())
+                    .run_if(not(resource_exists::<Playback>())),
+            )
+            .add_system(
+                build_playback_session
+                    .run_if(resource_exists::<Playback>())
+                    .run_if(in_state(GameState::Matchmaking)),
+            )
+            .add_system(
+                prepare_playback_ui
+                    .run_if(resource_exists::<Playback>())
+                    .in_schedule(OnExit(GameState::Matchmaking)),
+            )
+            .add_system(
+                rewind_playback
+                    .run_if(resource_exists::<Playback>())
+                    .in_schedule(OnExit(GameState::Interlude)),
+            )
+            .add_system(
+                control_playback
+                    .run_if(resource_exists::<Playback>())
+                    .run_if(in_state(GameState::InGame)),
+            );
+    }
+}
+
+/// Confirmed inputs of all players for one round, enough to re-simulate it deterministically
+#[derive(Clone)]
+pub struct Replay {
+    version: String,
+    seed: [u8; 32],
+    start_frame: u32,
+    num_players: usize,
+    rules: MatchRules,
+    /// Inputs of all players, one entry per frame
+    inputs: Vec<Vec<FvzInput>>,
+}
+
+impl Replay {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(FORMAT_VERSION);
+        bytes.push(self.version.len() as u8);
+        bytes.extend_from_slice(self.version.as_bytes());
+        bytes.extend_from_slice(&self.seed);
+        bytes.extend_from_slice(&self.start_frame.to_le_bytes());
+        bytes.push(self.num_players as u8);
+        bytes.extend_from_slice(&self.rules.to_bytes());
+        bytes.extend_from_slice(&(self.inputs.len() as u32).to_le_bytes());
+        for frame in &self.inputs {
+            bytes.extend_from_slice(bytemuck::cast_slice(frame));
+        }
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let bytes = bytes.strip_prefix(MAGIC)?;
+        let (&format, bytes) = bytes.split_first()?;
+        if format != FORMAT_VERSION {
+            return None;
+        }
+        let (&version_length, bytes) = bytes.split_first()?;
+        let (version, bytes) = split_checked(bytes, version_length as usize)?;
+        let (seed, bytes) = split_checked(bytes, 32)?;
+        let (start_frame, bytes) = split_checked(bytes, 4)?;
+        let (&num_players, bytes) = bytes.split_first()?;
+        let (rules, bytes) = split_checked(bytes, MatchRules::SIZE)?;
+        let (frames, bytes) = split_checked(bytes, 4)?;
+        let frames = u32::from_le_bytes(frames.try_into().ok()?) as usize;
+        let num_players = num_players as usize;
+        if num_players == 0 || bytes.len() != frames * num_players * FvzInput::SIZE {
+            return None;
+        }
+
+        Some(Replay {
+            version: String::from_utf8(version.to_vec()).ok()?,
+            seed: seed.try_into().ok()?,
+            start_frame: u32::from_le_bytes(start_frame.try_into().ok()?),
+            num_players,
+            rules: MatchRules::from_bytes(rules)?,
+            inputs: bytes
+                .chunks_exact(num_players * FvzInput::SIZE)
+                .map(|frame| {
+                    frame
+                        .chunks_exact(FvzInput::SIZE)
+                        .map(FvzInput::from_bytes)
+                        .collect()
+                })
+                .collect(),
+        })
+    }
+}
+
+fn split_checked(bytes: &[u8], at: usize) -> Option<(&[u8], &[u8])> {
+    (bytes.len() >= at).then(|| bytes.split_at(at))
+}
+
+/// The replay of the last played round
+#[derive(Resource)]
+pub struct LastReplay(pub Replay);
+
+/// Currently re-simulating a replay instead of reading local inputs
+#[derive(Resource)]
+pub struct Playback {
+    replay: Replay,
+    fast_forward: bool,
+    /// Frame at which a single step was requested while paused
+    step_from: Option<u32>,
+}
+
+impl Playback {
+    pub fn new(replay: Replay) -> Self {
+        Playback {
+            replay,
+            fast_forward: false,
+            step_from: None,
+        }
+    }
+
+    pub fn input(&self, seed_frame: u32, handle: PlayerHandle) -> FvzInput {
+        self.replay
+            .inputs
+            .get(seed_frame.wrapping_sub(self.replay.start_frame) as usize)
+            .and_then(|frame| frame.get(handle))
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+/// Inputs of the current round, one entry per frame starting at `start_frame`
+#[derive(Default, Resource)]
+pub struct ReplayRecorder {
+    pub start_frame: u32,
+    /// [`SessionFrame`] of the first frame of the round
+    start_session_frame: u32,
+    pub inputs: Vec<Vec<FvzInput>>,
+    /// Leading entries of `inputs` that GGRS has confirmed and won't re-simulate anymore
+    pub confirmed: usize,
+    /// Number of frames the round lasted, once it is over
+    length: Option<usize>,
+    /// Cleared once the replay of the round is saved
+    recording: bool,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn load_last_replay(mut commands: Commands) {
+    if let Ok(bytes) = std::fs::read(REPLAY_FILE) {
+        // files from before the format version have the length of the game version in its place
+        let format = bytes.strip_prefix(MAGIC).and_then(|bytes| bytes.first());
+        if format.map_or(false, |format| *format != FORMAT_VERSION) {
+            warn!(
+                "Ignoring replay in another format, this game reads format {}",
+                FORMAT_VERSION
+            );
+            return;
+        }
+        match Replay::from_bytes(&bytes) {
+            Some(replay) if replay.version == env!("CARGO_PKG_VERSION") => {
+                commands.insert_resource(LastReplay(replay))
+            }
+            Some(replay) => warn!(
+                "Ignoring replay of game version {}, this is {}",
+                replay.version,
+                env!("CARGO_PKG_VERSION")
+            ),
+            None => warn!("Failed to read replay file {}", REPLAY_FILE),
+        }
+    }
+}
+
+fn start_recording(
+    mut recorder: ResMut<ReplayRecorder>,
+    seed_frame: Res<SeedFrame>,
+    session_frame: Res<SessionFrame>,
+) {
+    *recorder = ReplayRecorder {
+        start_frame: seed_frame.0,
+        start_session_frame: session_frame.0,
+        recording: true,
+        ..default()
+    };
+}
+
+/// Re-simulated frames overwrite their earlier, possibly predicted, inputs.
+///
+/// Keeps going after the round is over, because GGRS may still correct its last frames.
+fn record_inputs(
+    mut recorder: ResMut<ReplayRecorder>,
+    inputs: Res<PlayerInputs<GgrsConfig>>,
+    session_frame: Res<SessionFrame>,
+) {
+    if !recorder.recording {
+        return;
+    }
+    let Some(index) = session_frame.0.checked_sub(recorder.start_session_frame) else {
+        return;
+    };
+    let index = index as usize;
+    if recorder.length.map_or(false, |length| index >= length) {
+        return;
+    }
+    let frame = inputs.iter().map(|(input, _)| *input).collect();
+    recorder.inputs.resize(index, Vec::new());
+    recorder.inputs.push(frame);
+}
+
+/// Every frame of the round advanced the seed frame once
+fn finish_recording(mut recorder: ResMut<ReplayRecorder>, seed_frame: Res<SeedFrame>) {
+    if recorder.recording && recorder.length.is_none() {
+        let length = seed_frame.0.wrapping_sub(recorder.start_frame) as usize;
+        recorder.inputs.truncate(length);
+        recorder.length = Some(length);
+    }
+}
+
+/// Frames up to the confirmed frame of the session won't be re-simulated, so their inputs are final
+fn confirm_inputs(mut recorder: ResMut<ReplayRecorder>, session: Res<Session<GgrsConfig>>) {
+    if !recorder.recording {
+        return;
+    }
+    let confirmed = match &*session {
+        Session::P2PSession(session) => usize::try_from(
+            i64::from(session.confirmed_frame()) + 1 - i64::from(recorder.start_session_frame),
+        )
+        .unwrap_or(0),
+        // sync tests and spectators only ever see confirmed inputs
+        _ => usize::MAX,
+    };
+    recorder.confirmed = confirmed.min(recorder.inputs.len());
+}
+
+/// Waits until the last frames of the round are confirmed
+fn save_replay(
+    mut commands: Commands,
+    mut recorder: ResMut<ReplayRecorder>,
+    seed: Res<Seed>,
+    rules: Res<MatchRules>,
+    session: Res<Session<GgrsConfig>>,
+) {
+    let Some(length) = recorder.length else {
+        return;
+    };
+    if !recorder.recording || recorder.confirmed < length {
+        return;
+    }
+    recorder.recording = false;
+    if length == 0 {
+        return;
+    }
+    let replay = Replay {
+        version: env!("CARGO_PKG_VERSION").to_owned(),
+        seed: seed.0,
+        start_frame: recorder.start_frame,
+        num_players: num_players(&session),
+        rules: *rules,
+        inputs: recorder.inputs[..length].to_vec(),
+    };
+    #[cfg(not(target_arch = "wasm32"))]
+    if let Err(error) = std::fs::write(REPLAY_FILE, replay.to_bytes()) {
+        warn!("Failed to save replay to {}: {}", REPLAY_FILE, error);
+    }
+    commands.insert_resource(LastReplay(replay));
+}
+
+fn build_playback_session(
+    mut commands: Commands,
+    playback: Res<Playback>,
+    mut state: ResMut<NextState<GameState>>,
+    mut interlude_timer: ResMut<InterludeTimer>,
+) {
+    let replay = &playback.replay;
+    info!(
+        "watching replay of {} frames with {} player(s)",
+        replay.inputs.len(),
+        replay.num_players
+    );
+    let session = SessionBuilder::<GgrsConfig>::new()
+        .with_num_players(replay.num_players)
+        .with_check_distance(0)
+        .start_synctest_session()
+        .expect("failed to start replay session");
+
+    commands.insert_resource(Seed(replay.seed));
+    commands.insert_resource(replay.rules);
+    commands.insert_resource(LocalPlayerIds::single(0));
+    commands.insert_resource(Session::SyncTestSession(session));
+
+    interlude_timer.0 = 3;
+    state.set(GameState::Interlude);
+}
+
+/// Every round starts the replay from the beginning
+fn rewind_playback(mut playback: ResMut<Playback>, mut seed_frame: ResMut<SeedFrame>) {
+    seed_frame.0 = playback.replay.start_frame;
+    playback.step_from = None;
+}
+
+#[derive(Component)]
+struct PlaybackText;
+
+fn prepare_playback_ui(mut commands: Commands, font_assets: Res<FontAssets>) {
+    commands
+        .spawn(TextBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                // the ammo of the replayed player is shown in the bottom left
+                position: UiRect {
+                    bottom: Val::Px(15.),
+                    right: Val::Px(15.),
+                    ..default()
+                },
+                ..default()
+            },
+            text: Text {
+                sections: vec![TextSection {
+                    value: "".to_owned(),
+                    style: TextStyle {
+                        font: font_assets.fira_sans.clone(),
+                        font_size: 25.0,
+                        color: Color::rgb(0.9, 0.9, 0.9),
+                    },
+                }],
+                alignment: TextAlignment::Right,
+                ..default()
+            },
+            ..Default::default()
+        })
+        .insert(PlaybackText)
+        .insert(SessionOnly);
+}
+
+/// Space pauses, F toggles fast forward and N steps a single frame while paused
+fn control_playback(
+    input: Res<Input<KeyCode>>,
+    mut time: ResMut<Time>,
+    mut playback: ResMut<Playback>,
+    seed_frame: Res<SeedFrame>,
+    mut text: Query<&mut Text, With<PlaybackText>>,
+) {
+    if let Some(step_from) = playback.step_from {
+        if step_from != seed_frame.0 {
+            playback.step_from = None;
+            time.pause();
+        }
+    }
+    if input.just_pressed(KeyCode::Space) {
+        playback.step_from = None;
+        if time.is_paused() {
+            time.unpause();
+        } else {
+            time.pause();
+        }
+    }
+    if input.just_pressed(KeyCode::F) {
+        playback.fast_forward = !playback.fast_forward;
+        time.set_relative_speed(if playback.fast_forward {
+            FAST_FORWARD_SPEED
+        } else {
+            1.
+        });
+    }
+    if input.just_pressed(KeyCode::N) && time.is_paused() {
+        playback.step_from = Some(seed_frame.0);
+        time.unpause();
+    }
+
+    if let Ok(mut text) = text.get_single_mut() {
+        let status = if time.is_paused() {
+            "paused"
+        } else if playback.fast_forward {
+            "fast forward"
+        } else {
+            "playing"
+        };
+        text.sections[0].value = format!(
+            "Replay frame {}/{} ({})\n[Space] pause  [F] fast forward  [N] next frame",
+            seed_frame.0.wrapping_sub(playback.replay.start_frame),
+            playback.replay.inputs.len(),
+            status
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replay() -> Replay {
+        Replay {
+            version: "0.1.0".to_owned(),
+            seed: [7; 32],
+            start_frame: 1234,
+            num_players: 2,
+            rules: MatchRules {
+                revive: false,
+                max_health: 760.,
+                ..default()
+            },
+            inputs: (0..3u8)
+                .map(|frame| {
+                    vec![
+                        FvzInput::from_buttons(frame),
+                        FvzInput {
+                            buttons: 0xff,
+                            aim: frame * 10,
+                        },
+                    ]
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn replays_read_back_what_was_written() {
+        let replay = replay();
+        let read = Replay::from_bytes(&replay.to_bytes()).unwrap();
+
+        assert_eq!(read.version, replay.version);
+        assert_eq!(read.seed, replay.seed);
+        assert_eq!(read.start_frame, replay.start_frame);
+        assert_eq!(read.num_players, replay.num_players);
+        assert_eq!(read.rules, replay.rules);
+        assert_eq!(read.inputs, replay.inputs);
+    }
+
+    #[test]
+    fn header_starts_with_magic_format_and_game_version() {
+        let bytes = replay().to_bytes();
+
+        assert_eq!(&bytes[..4], MAGIC);
+        assert_eq!(bytes[4], FORMAT_VERSION);
+        assert_eq!(bytes[5], 5);
+        assert_eq!(&bytes[6..11], b"0.1.0");
+    }
+
+    #[test]
+    fn replays_of_other_formats_are_rejected() {
+        let mut bytes = replay().to_bytes();
+        bytes[4] = FORMAT_VERSION + 1;
+        assert!(Replay::from_bytes(&bytes).is_none());
+
+        // before the format version, the length of the game version followed the magic
+        let mut old = MAGIC.to_vec();
+        old.extend_from_slice(&replay().to_bytes()[5..]);
+        assert!(Replay::from_bytes(&old).is_none());
+    }
+
+    #[test]
+    fn truncated_and_foreign_files_are_rejected() {
+        let bytes = replay().to_bytes();
+
+        assert!(Replay::from_bytes(&bytes[..bytes.len() - 1]).is_none());
+        assert!(Replay::from_bytes(b"not a replay").is_none());
+        assert!(Replay::from_bytes(&[]).is_none());
+    }
+}
@@ -0,0 +1,236 @@
+use crate::loading::FontAssets;
+use crate::matchmaking::{receive_game_packets, GamePacket, GameSocket, GAME_CHANNEL, REMATCH};
+use crate::menu::{ButtonColors, GameCode};
+use crate::networking::{
+    leave_game, NextRound, RoundOver, SessionFrame, SessionOnly, INTERLUDE_FRAMES,
+};
+use crate::spectator::Spectating;
+use crate::{GameMode, GameState, Score};
+use bevy::prelude::*;
+use ggrs::PlayerType;
+
+pub struct ResultsPlugin;
+
+impl Plugin for ResultsPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system(finish_round.in_schedule(OnExit(GameState::InGame)))
+            .add_system(show_results.in_schedule(OnEnter(GameState::Interlude)))
+            .add_system(forget_round.in_schedule(OnEnter(GameState::Matchmaking)))
+            .add_systems(
+                (
+                    click_results_button,
+                    receive_rematch.after(receive_game_packets),
+                )
+                    .run_if(resource_exists::<RoundOver>())
+                    .run_if(in_state(GameState::Interlude)),
+            );
+    }
+}
+
+#[derive(Component, Clone, Copy, PartialEq, Eq)]
+enum ResultsButton {
+    PlayAgain,
+    BackToLobby,
+    QuitToMenu,
+}
+
+#[derive(Component)]
+struct ResultsUi;
+
+/// Sync tests and spectators follow the rounds without asking anybody
+fn finish_round(
+    mut commands: Commands,
+    game_mode: Res<GameMode>,
+    spectating: Option<Res<Spectating>>,
+) {
+    if *game_mode != GameMode::SyncTest && spectating.is_none() {
+        commands.insert_resource(RoundOver);
+    }
+}
+
+/// A round flag left over from leaving mid-game must not show results before the next first round
+fn forget_round(mut commands: Commands) {
+    commands.remove_resource::<RoundOver>();
+}
+
+fn show_results(
+    mut commands: Commands,
+    round_over: Option<Res<RoundOver>>,
+    font_assets: Res<FontAssets>,
+    button_colors: Res<ButtonColors>,
+    game_mode: Res<GameMode>,
+    score: Res<Score>,
+) {
+    if round_over.is_none() {
+        return;
+    }
+    let text_style = TextStyle {
+        font: font_assets.fira_sans.clone(),
+        font_size: 40.0,
+        color: Color::rgb(0.9, 0.9, 0.9),
+    };
+    let mut buttons = vec![(ResultsButton::PlayAgain, "Play again")];
+    match *game_mode {
+        GameMode::Multi(false) => buttons[0].1 = "Waiting for host",
+        GameMode::Replay => buttons[0].1 = "Watch again",
+        _ => (),
+    }
+    if *game_mode != GameMode::Replay {
+        buttons.push((ResultsButton::BackToLobby, "Back to lobby"));
+    }
+    buttons.push((ResultsButton::QuitToMenu, "Quit to menu"));
+
+    commands
+        .spawn(NodeBundle {
+            style: Style {
+                size: Size {
+                    width: Val::Percent(100.),
+                    height: Val::Percent(100.),
+                },
+                flex_direction: FlexDirection::Column,
+                justify_content: JustifyContent::Center,
+                align_items: AlignItems::Center,
+                ..default()
+            },
+            background_color: BackgroundColor(Color::rgba(0., 0., 0., 0.6)),
+            ..default()
+        })
+        .insert(ResultsUi)
+        .insert(SessionOnly)
+        .with_children(|parent| {
+            parent.spawn(TextBundle {
+                style: Style {
+                    margin: UiRect::all(Val::Px(15.)),
+                    ..default()
+                },
+                text: Text::from_section(
+                    format!("Game over\nScore: {}", score.0),
+                    text_style.clone(),
+                )
+                .with_alignment(TextAlignment::Center),
+                ..default()
+            });
+            for (button, label) in buttons {
+                parent
+                    .spawn(ButtonBundle {
+                        style: Style {
+                            size: Size::new(Val::Px(300.0), Val::Px(50.0)),
+                            margin: UiRect::all(Val::Px(10.)),
+                            justify_content: JustifyContent::Center,
+                            align_items: AlignItems::Center,
+                            ..Default::default()
+                        },
+                        background_color: button_colors.normal.into(),
+                        ..Default::default()
+                    })
+                    .insert(button)
+                    .with_children(|parent| {
+                        parent.spawn(TextBundle {
+                            text: Text::from_section(label, text_style.clone())
+                                .with_alignment(TextAlignment::Center),
+                            ..Default::default()
+                        });
+                    });
+            }
+        });
+}
+
+fn click_results_button(
+    mut commands: Commands,
+    button_colors: Res<ButtonColors>,
+    game_mode: Res<GameMode>,
+    mut socket: Option<ResMut<GameSocket>>,
+    mut state: ResMut<NextState<GameState>>,
+    mut next_round: ResMut<NextRound>,
+    session_frame: Res<SessionFrame>,
+    mut interaction_query: Query<
+        (&Interaction, &mut BackgroundColor, &ResultsButton),
+        Changed<Interaction>,
+    >,
+    results_ui: Query<Entity, With<ResultsUi>>,
+) {
+    for (interaction, mut color, button) in &mut interaction_query {
+        match *interaction {
+            Interaction::Clicked => match button {
+                ResultsButton::PlayAgain => {
+                    if *game_mode == GameMode::Multi(false) {
+                        continue;
+                    }
+                    // far enough ahead for the packet to arrive before anybody simulates the frame
+                    let start = session_frame.0 + INTERLUDE_FRAMES;
+                    if let Some(GameSocket(Some(socket))) = socket.as_deref_mut() {
+                        let mut packet = vec![REMATCH];
+                        packet.extend_from_slice(&start.to_le_bytes());
+                        for player in socket.players() {
+                            if let PlayerType::Remote(peer) = player {
+                                socket
+                                    .channel(GAME_CHANNEL)
+                                    .send(packet.clone().into_boxed_slice(), peer);
+                            }
+                        }
+                    }
+                    play_again(&mut commands, &results_ui, &mut next_round, start);
+                }
+                ResultsButton::BackToLobby => {
+                    commands.add(leave_game);
+                    state.set(GameState::Connect);
+                }
+                ResultsButton::QuitToMenu => {
+                    commands.add(leave_game);
+                    commands.insert_resource(GameCode("".to_owned()));
+                    state.set(GameState::Menu);
+                }
+            },
+            Interaction::Hovered => {
+                *color = button_colors.hovered.into();
+            }
+            Interaction::None => {
+                *color = button_colors.normal.into();
+            }
+        }
+    }
+}
+
+/// The host decides in which frame the next round starts.
+///
+/// A rematch that arrives after its first frame was simulated here can't be joined anymore
+/// without desyncing, so this peer goes back to the lobby instead.
+fn receive_rematch(
+    mut commands: Commands,
+    mut packets: EventReader<GamePacket>,
+    mut next_round: ResMut<NextRound>,
+    mut state: ResMut<NextState<GameState>>,
+    session_frame: Res<SessionFrame>,
+    results_ui: Query<Entity, With<ResultsUi>>,
+) {
+    for GamePacket { packet, .. } in packets.iter() {
+        if packet.first() != Some(&REMATCH) || packet.len() < 5 {
+            continue;
+        }
+        let start = u32::from_le_bytes(packet[1..5].try_into().unwrap());
+        if start < session_frame.0 {
+            error!(
+                "The rematch started in frame {}, but this is already frame {}; returning to the lobby",
+                start, session_frame.0
+            );
+            commands.add(leave_game);
+            state.set(GameState::Connect);
+            return;
+        }
+        play_again(&mut commands, &results_ui, &mut next_round, start);
+    }
+}
+
+/// Start the next round of the same session in the given session frame
+fn play_again(
+    commands: &mut Commands,
+    results_ui: &Query<Entity, With<ResultsUi>>,
+    next_round: &mut NextRound,
+    start: u32,
+) {
+    next_round.0 = Some(start);
+    commands.remove_resource::<RoundOver>();
+    for entity in results_ui {
+        commands.entity(entity).despawn_recursive();
+    }
+}
@@ -0,0 +1,81 @@
+use crate::networking::GgrsConfig;
+use bevy::ecs::system::System;
+use bevy::prelude::*;
+use bevy::reflect::GetTypeRegistration;
+use bevy_ggrs::GGRSPlugin;
+use std::any::TypeId;
+
+/// All types that GGRS snapshots and restores on rollback,
+/// plus the few that may be written in `GGRSSchedule` without being snapshotted
+#[derive(Default, Resource)]
+pub struct RollbackTypes(Vec<TypeId>);
+
+impl RollbackTypes {
+    pub fn contains(&self, type_id: TypeId) -> bool {
+        self.0.contains(&type_id)
+    }
+}
+
+/// Wraps the [`GGRSPlugin`] builder to keep track of every registered rollback type
+pub struct RollbackRegistry {
+    plugin: GGRSPlugin<GgrsConfig>,
+    types: RollbackTypes,
+}
+
+impl RollbackRegistry {
+    pub fn new(plugin: GGRSPlugin<GgrsConfig>) -> Self {
+        RollbackRegistry {
+            plugin,
+            types: RollbackTypes::default(),
+        }
+    }
+
+    pub fn component<T: Component + Reflect + GetTypeRegistration + Default>(mut self) -> Self {
+        self.plugin = self.plugin.register_rollback_component::<T>();
+        self.types.0.push(TypeId::of::<T>());
+        self
+    }
+
+    pub fn resource<T: Resource + Reflect + GetTypeRegistration + Default>(mut self) -> Self {
+        self.plugin = self.plugin.register_rollback_resource::<T>();
+        self.types.0.push(TypeId::of::<T>());
+        self
+    }
+
+    /// Allow writing `T` in `GGRSSchedule` even though it is not rolled back.
+    ///
+    /// Only use this for state that is rollback-safe by construction (e.g. deduplicated events).
+    pub fn allow_unregistered<T: 'static>(mut self) -> Self {
+        self.types.0.push(TypeId::of::<T>());
+        self
+    }
+
+    pub fn build(self, app: &mut App) {
+        app.insert_resource(self.types);
+        self.plugin.build(app);
+    }
+}
+
+/// Panics if the given system writes a component or resource that is not rolled back
+#[cfg(debug_assertions)]
+pub fn assert_rollback_safe<Marker>(world: &mut World, system: impl IntoSystem<(), (), Marker>) {
+    let mut system = IntoSystem::into_system(system);
+    system.initialize(world);
+    let rollback_types = world.resource::<RollbackTypes>();
+    for component_id in system.component_access().writes() {
+        let info = world
+            .components()
+            .get_info(component_id)
+            .expect("System accesses an unknown component");
+        let registered = info
+            .type_id()
+            .map_or(false, |type_id| rollback_types.contains(type_id));
+        if !registered {
+            panic!(
+                "System '{}' in GGRSSchedule writes '{}', which is not registered for rollback",
+                system.name(),
+                info.name()
+            );
+        }
+    }
+}
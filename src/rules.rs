@@ -0,0 +1,209 @@
+use bevy::prelude::*;
+
+/// Share of the damage teammates take with [`FriendlyFire::Reduced`]
+pub const REDUCED_FRIENDLY_FIRE: f64 = 0.25;
+
+const REVIVE_HEALTH_OPTIONS: [f64; 3] = [0.5, 0.8, 1.];
+const MAX_HEALTH_OPTIONS: [f64; 4] = [255., 510., 760., 1020.];
+const DIFFICULTY_OPTIONS: [(f64, &str); 4] = [
+    (0.75, "easy"),
+    (1., "normal"),
+    (1.5, "hard"),
+    (2., "nightmare"),
+];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FriendlyFire {
+    Off,
+    Reduced,
+    Full,
+}
+
+/// Rules of a match, picked by the host in the lobby and sent to everyone in the START packet
+#[derive(Resource, Clone, Copy, PartialEq, Debug)]
+pub struct MatchRules {
+    pub friendly_fire: FriendlyFire,
+    pub revive: bool,
+    /// Share of the max health revived players get back
+    pub revive_health: f64,
+    pub max_health: f64,
+    /// Scales health and damage of enemies
+    pub difficulty: f64,
+}
+
+impl Default for MatchRules {
+    fn default() -> Self {
+        MatchRules {
+            friendly_fire: FriendlyFire::Full,
+            revive: true,
+            revive_health: 0.8,
+            max_health: 510.,
+            difficulty: 1.,
+        }
+    }
+}
+
+/// A setting of [`MatchRules`] that can be changed in the lobby
+#[derive(Component, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Rule {
+    FriendlyFire,
+    Revive,
+    MaxHealth,
+    Difficulty,
+}
+
+impl Rule {
+    pub const ALL: [Rule; 4] = [
+        Rule::FriendlyFire,
+        Rule::Revive,
+        Rule::MaxHealth,
+        Rule::Difficulty,
+    ];
+}
+
+impl MatchRules {
+    pub const SIZE: usize = 7;
+
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let friendly_fire = match self.friendly_fire {
+            FriendlyFire::Off => 0,
+            FriendlyFire::Reduced => 1,
+            FriendlyFire::Full => 2,
+        };
+        let max_health = (self.max_health as u16).to_le_bytes();
+        let difficulty = ((self.difficulty * 100.).round() as u16).to_le_bytes();
+        [
+            friendly_fire,
+            self.revive as u8,
+            (self.revive_health * 100.).round() as u8,
+            max_health[0],
+            max_health[1],
+            difficulty[0],
+            difficulty[1],
+        ]
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let bytes = bytes.get(..Self::SIZE)?;
+        let friendly_fire = match bytes[0] {
+            0 => FriendlyFire::Off,
+            1 => FriendlyFire::Reduced,
+            2 => FriendlyFire::Full,
+            _ => return None,
+        };
+        Some(MatchRules {
+            friendly_fire,
+            revive: bytes[1] != 0,
+            revive_health: bytes[2] as f64 / 100.,
+            max_health: u16::from_le_bytes([bytes[3], bytes[4]]) as f64,
+            difficulty: u16::from_le_bytes([bytes[5], bytes[6]]) as f64 / 100.,
+        })
+    }
+
+    /// Switch the given setting to its next option
+    pub fn cycle(&mut self, rule: Rule) {
+        match rule {
+            Rule::FriendlyFire => {
+                self.friendly_fire = match self.friendly_fire {
+                    FriendlyFire::Full => FriendlyFire::Reduced,
+                    FriendlyFire::Reduced => FriendlyFire::Off,
+                    FriendlyFire::Off => FriendlyFire::Full,
+                }
+            }
+            // every health share, then no reviving at all
+            Rule::Revive => {
+                if !self.revive {
+                    self.revive = true;
+                    self.revive_health = REVIVE_HEALTH_OPTIONS[0];
+                } else if let Some(next) = next_option(&REVIVE_HEALTH_OPTIONS, self.revive_health) {
+                    self.revive_health = next;
+                } else {
+                    self.revive = false;
+                }
+            }
+            Rule::MaxHealth => {
+                self.max_health = next_option(&MAX_HEALTH_OPTIONS, self.max_health)
+                    .unwrap_or(MAX_HEALTH_OPTIONS[0]);
+            }
+            Rule::Difficulty => {
+                let factors = DIFFICULTY_OPTIONS.map(|(factor, _)| factor);
+                self.difficulty = next_option(&factors, self.difficulty).unwrap_or(factors[0]);
+            }
+        }
+    }
+
+    pub fn label(&self, rule: Rule) -> String {
+        match rule {
+            Rule::FriendlyFire => match self.friendly_fire {
+                FriendlyFire::Off => "Friendly fire: off".to_owned(),
+                FriendlyFire::Reduced => "Friendly fire: reduced".to_owned(),
+                FriendlyFire::Full => "Friendly fire: full".to_owned(),
+            },
+            Rule::Revive if self.revive => {
+                format!("Revive: {:.0}% health", self.revive_health * 100.)
+            }
+            Rule::Revive => "Revive: off".to_owned(),
+            Rule::MaxHealth => format!("Health: {}", self.max_health),
+            Rule::Difficulty => {
+                let name = DIFFICULTY_OPTIONS
+                    .iter()
+                    .find(|(factor, _)| *factor == self.difficulty)
+                    .map(|(_, name)| name.to_string())
+                    .unwrap_or_else(|| format!("{:.0}%", self.difficulty * 100.));
+                format!("Difficulty: {}", name)
+            }
+        }
+    }
+}
+
+/// The option after the current one, `None` after the last
+fn next_option(options: &[f64], current: f64) -> Option<f64> {
+    options.iter().copied().find(|option| *option > current)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_selectable_rule_survives_the_packet() {
+        let mut rules = MatchRules::default();
+        for rule in Rule::ALL {
+            // a few more than the options of any rule, to wrap around
+            for _ in 0..6 {
+                rules.cycle(rule);
+                assert_eq!(MatchRules::from_bytes(&rules.to_bytes()), Some(rules));
+            }
+        }
+    }
+
+    #[test]
+    fn rules_take_seven_bytes() {
+        let rules = MatchRules {
+            friendly_fire: FriendlyFire::Reduced,
+            revive: false,
+            revive_health: 0.5,
+            max_health: 1020.,
+            difficulty: 1.5,
+        };
+
+        assert_eq!(rules.to_bytes(), [1, 0, 50, 0xfc, 0x03, 150, 0]);
+    }
+
+    #[test]
+    fn trailing_bytes_are_ignored_and_missing_ones_rejected() {
+        let mut bytes = MatchRules::default().to_bytes().to_vec();
+        bytes.extend_from_slice(&[1, 2, 3]);
+        assert_eq!(MatchRules::from_bytes(&bytes), Some(MatchRules::default()));
+
+        assert_eq!(MatchRules::from_bytes(&bytes[..MatchRules::SIZE - 1]), None);
+    }
+
+    #[test]
+    fn unknown_friendly_fire_is_rejected() {
+        let mut bytes = MatchRules::default().to_bytes();
+        bytes[0] = 3;
+
+        assert_eq!(MatchRules::from_bytes(&bytes), None);
+    }
+}
@@ -0,0 +1,196 @@
+use crate::MAP_SIZE;
+use bevy::prelude::*;
+use std::ops::RangeInclusive;
+
+/// Side length of a grid cell
+const CELL_SIZE: f32 = 2.;
+/// Cells per row and column, covering the whole arena
+const COLUMNS: usize = MAP_SIZE as usize / CELL_SIZE as usize + 1;
+
+/// Something with a position and a round hitbox, stored in a [`SpatialGrid`]
+#[derive(Clone, Copy, Debug)]
+pub struct GridEntry {
+    /// Orders the entries; has to be the same on all peers, e.g. a rollback id or player handle
+    pub id: u32,
+    pub entity: Entity,
+    pub position: Vec2,
+    pub radius: f32,
+}
+
+/// Uniform grid over the arena to find entities close to a point without checking all of them.
+///
+/// GGRS systems keep one in a `Local` and rebuild it from the rolled back transforms every frame,
+/// so it never holds state from before a rollback. Lookups return entries ordered by id,
+/// which keeps the systems using it independent of query order.
+#[derive(Default)]
+pub struct SpatialGrid {
+    entries: Vec<GridEntry>,
+    /// Indices into `entries` per cell, row by row
+    cells: Vec<Vec<usize>>,
+}
+
+impl SpatialGrid {
+    /// Replace the content of the grid, reusing its allocations
+    pub fn rebuild(&mut self, entries: impl Iterator<Item = GridEntry>) {
+        self.entries.clear();
+        self.entries.extend(entries);
+        self.entries.sort_unstable_by_key(|entry| entry.id);
+        self.cells.resize_with(COLUMNS * COLUMNS, Vec::new);
+        self.cells.iter_mut().for_each(Vec::clear);
+        for (index, entry) in self.entries.iter().enumerate() {
+            let (columns, rows) = cell_range(entry.position, entry.radius);
+            for row in rows {
+                for column in columns.clone() {
+                    self.cells[row * COLUMNS + column].push(index);
+                }
+            }
+        }
+    }
+
+    /// All entries ordered by id
+    pub fn entries(&self) -> &[GridEntry] {
+        &self.entries
+    }
+
+    pub fn index_of(&self, id: u32) -> Option<usize> {
+        self.entries
+            .binary_search_by_key(&id, |entry| entry.id)
+            .ok()
+    }
+
+    /// Indices of the entries that might overlap the given circle, ordered by id.
+    ///
+    /// Entries in the same cells are included even if they are a bit further away,
+    /// callers still have to check the distance.
+    pub fn near(&self, position: Vec2, radius: f32) -> impl Iterator<Item = usize> + '_ {
+        let (columns, rows) = cell_range(position, radius);
+        let mut last = None;
+        // merges the sorted cells without allocating; entries larger than a cell are in several of them
+        std::iter::from_fn(move || {
+            let next = rows
+                .clone()
+                .flat_map(|row| columns.clone().map(move |column| row * COLUMNS + column))
+                .filter_map(|cell| {
+                    let cell = &self.cells[cell];
+                    let first = last.map_or(0, |last| cell.partition_point(|index| *index <= last));
+                    cell.get(first).copied()
+                })
+                .min()?;
+            last = Some(next);
+            Some(next)
+        })
+    }
+
+    pub fn get(&self, index: usize) -> &GridEntry {
+        &self.entries[index]
+    }
+}
+
+/// Columns and rows of the cells touched by the bounding box of a circle.
+///
+/// Anything outside of the arena counts as being in the closest border cell.
+fn cell_range(position: Vec2, radius: f32) -> (RangeInclusive<usize>, RangeInclusive<usize>) {
+    let cell = |coordinate: f32| {
+        let cell = ((coordinate + MAP_SIZE as f32 / 2.) / CELL_SIZE).floor();
+        cell.clamp(0., (COLUMNS - 1) as f32) as usize
+    };
+    (
+        cell(position.x - radius)..=cell(position.x + radius),
+        cell(position.y - radius)..=cell(position.y + radius),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grid(entries: &[(Vec2, f32)]) -> SpatialGrid {
+        let mut grid = SpatialGrid::default();
+        grid.rebuild(
+            entries
+                .iter()
+                .enumerate()
+                .map(|(id, (position, radius))| GridEntry {
+                    id: id as u32,
+                    entity: Entity::from_raw(id as u32),
+                    position: *position,
+                    radius: *radius,
+                }),
+        );
+        grid
+    }
+
+    fn near(grid: &SpatialGrid, position: Vec2, radius: f32) -> Vec<usize> {
+        grid.near(position, radius).collect()
+    }
+
+    #[test]
+    fn entries_on_a_cell_border_are_found_once_from_both_sides() {
+        // cells start at -MAP_SIZE / 2, so x = -0.5 is the border between two of them
+        let grid = grid(&[(Vec2::new(-0.5, 0.), 0.3)]);
+
+        assert_eq!(near(&grid, Vec2::new(-0.9, 0.), 0.1), [0]);
+        assert_eq!(near(&grid, Vec2::new(-0.1, 0.), 0.1), [0]);
+        assert_eq!(near(&grid, Vec2::new(-0.5, 0.), 3.), [0]);
+    }
+
+    #[test]
+    fn entries_outside_of_the_arena_are_in_the_border_cells() {
+        let half_size = MAP_SIZE as f32 / 2.;
+        let grid = grid(&[
+            (Vec2::new(half_size + 10., half_size + 10.), 0.5),
+            (Vec2::new(half_size - 0.5, half_size - 0.5), 0.5),
+            (Vec2::new(-half_size - 10., 0.), 0.5),
+        ]);
+
+        assert_eq!(
+            near(&grid, Vec2::new(half_size + 20., half_size), 0.5),
+            [0, 1]
+        );
+        assert_eq!(near(&grid, Vec2::new(-half_size - 3., 0.), 0.5), [2]);
+        assert_eq!(near(&grid, Vec2::ZERO, 0.5), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn near_finds_every_overlapping_entry_in_id_order() {
+        // a fixed pseudo random scatter over the arena and a bit beyond it
+        let mut state = 1u32;
+        let mut random = |range: f32| {
+            state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            (state >> 8) as f32 / (1 << 24) as f32 * range - range / 2.
+        };
+        let range = MAP_SIZE as f32 + 6.;
+        let entries: Vec<(Vec2, f32)> = (0..200)
+            .map(|_| {
+                (
+                    Vec2::new(random(range), random(range)),
+                    random(3.).abs() + 0.1,
+                )
+            })
+            .collect();
+        let grid = grid(&entries);
+
+        for _ in 0..200 {
+            let position = Vec2::new(random(range), random(range));
+            let radius = random(2.).abs();
+            let found = near(&grid, position, radius);
+            assert!(
+                found.windows(2).all(|pair| pair[0] < pair[1]),
+                "{:?}",
+                found
+            );
+            for (index, (entry, entry_radius)) in entries.iter().enumerate() {
+                if entry.distance(position) < entry_radius + radius {
+                    assert!(
+                        found.contains(&index),
+                        "{:?} with radius {} not found near {:?} with radius {}",
+                        entry,
+                        entry_radius,
+                        position,
+                        radius
+                    );
+                }
+            }
+        }
+    }
+}
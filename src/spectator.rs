@@ -0,0 +1,403 @@
+use crate::controls::ActionInput;
+use crate::input::{direction, FvzInput};
+use crate::loading::FontAssets;
+use crate::local_coop::HudCamera;
+use crate::matchmaking::{
+    receive_game_packets, GamePacket, GameSocket, PeerChange, Seed, GAME_CHANNEL, SPECTATE,
+    SPECTATOR_CHANNEL,
+};
+use crate::networking::{
+    num_players, GgrsConfig, NextRound, RoundEnd, SeedFrame, SessionFrame, SessionOnly,
+};
+use crate::players::Player;
+use crate::replay::ReplayRecorder;
+use crate::rules::MatchRules;
+use crate::{GameMode, GameState};
+use bevy::prelude::*;
+use bevy_ggrs::Session;
+use ggrs::{
+    Message, NonBlockingSocket, P2PSession, PlayerHandle, PlayerType, SessionBuilder, SessionState,
+};
+use matchbox_socket::{PeerId, PeerState, WebRtcChannel};
+use std::sync::{Arc, Mutex};
+
+/// Confirmed frames the host hands to a spectator per update.
+///
+/// GGRS spectators only buffer a second of inputs, so late spectators catch up at this speed.
+const FRAMES_PER_UPDATE: usize = 4;
+/// Spectators catch up once they are more than this many frames behind the host
+const MAX_FRAMES_BEHIND: usize = 10;
+/// Frames spectators advance per update while catching up, besides the regular one
+const CATCH_UP_SPEED: usize = 4;
+const FREE_CAMERA_SPEED: f32 = 10.;
+
+pub struct SpectatorPlugin;
+
+impl Plugin for SpectatorPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Spectators>()
+            .add_system(forget_spectators.in_schedule(OnEnter(GameState::Matchmaking)))
+            .add_systems(
+                (welcome_spectators, stream_inputs)
+                    .chain()
+                    .run_if(resource_exists::<Session<GgrsConfig>>()),
+            )
+            .add_system(
+                join_as_spectator.after(receive_game_packets).run_if(
+                    in_state(GameState::Matchmaking).or_else(resource_exists::<Spectating>()),
+                ),
+            )
+            .add_system(
+                prepare_spectator_ui
+                    .run_if(resource_exists::<Spectating>())
+                    .in_schedule(OnExit(GameState::Matchmaking)),
+            )
+            .add_system(
+                move_spectator_camera
+                    .run_if(resource_exists::<Spectating>())
+                    .run_if(in_state(GameState::InGame)),
+            );
+    }
+}
+
+/// Peers that joined the host after the game started and the sessions feeding them the current round
+#[derive(Default, Resource)]
+struct Spectators {
+    peers: Vec<PeerId>,
+    relays: Vec<Relay>,
+    /// Taken from the socket once the first spectator is fed
+    channel: Option<SharedChannel>,
+}
+
+/// GGRS only accepts spectators before a session starts, so every spectator gets a session of its own
+/// for each round, in which the host adds the confirmed inputs of all players as local inputs.
+struct Relay {
+    peer: PeerId,
+    /// [`SeedFrame`] in which the fed round started
+    start_frame: u32,
+    session: P2PSession<GgrsConfig>,
+    /// Frames of the round already added to the session
+    fed: usize,
+}
+
+/// Watching the rounds of a running game through a GGRS spectator session fed by the host
+#[derive(Resource)]
+pub struct Spectating {
+    num_players: usize,
+    channel: SharedChannel,
+}
+
+/// Player the spectator camera follows; moves freely with the movement keys if `None`
+#[derive(Default, Resource)]
+struct SpectatorCamera(Option<PlayerHandle>);
+
+/// [`SPECTATOR_CHANNEL`] shared by the GGRS sessions of all spectators
+#[derive(Clone)]
+struct SharedChannel(Arc<Mutex<SharedChannelState>>);
+
+struct SharedChannelState {
+    channel: WebRtcChannel,
+    /// Received messages that the session of their peer hasn't asked for yet
+    received: Vec<(PeerId, Message)>,
+}
+
+impl SharedChannel {
+    fn new(channel: WebRtcChannel) -> Self {
+        SharedChannel(Arc::new(Mutex::new(SharedChannelState {
+            channel,
+            received: Vec::new(),
+        })))
+    }
+
+    fn socket(&self, peer: PeerId) -> PeerSocket {
+        PeerSocket {
+            channel: self.clone(),
+            peer,
+        }
+    }
+
+    fn forget(&self, peer: PeerId) {
+        let mut state = self.0.lock().unwrap();
+        state.received.retain(|(from, _)| *from != peer);
+    }
+}
+
+/// The part of a [`SharedChannel`] talking to one peer
+struct PeerSocket {
+    channel: SharedChannel,
+    peer: PeerId,
+}
+
+impl NonBlockingSocket<PeerId> for PeerSocket {
+    fn send_to(&mut self, msg: &Message, addr: &PeerId) {
+        self.channel.0.lock().unwrap().channel.send_to(msg, addr);
+    }
+
+    fn receive_all_messages(&mut self) -> Vec<(PeerId, Message)> {
+        let mut state = self.channel.0.lock().unwrap();
+        let received = state.channel.receive_all_messages();
+        state.received.extend(received);
+        let (own, others) = std::mem::take(&mut state.received)
+            .into_iter()
+            .partition(|(from, _)| *from == self.peer);
+        state.received = others;
+        own
+    }
+}
+
+fn forget_spectators(mut spectators: ResMut<Spectators>) {
+    *spectators = Spectators::default();
+}
+
+/// Everybody connecting to the host after the game started is a spectator
+fn welcome_spectators(
+    mut peer_changes: EventReader<PeerChange>,
+    mut spectators: ResMut<Spectators>,
+    game_mode: Res<GameMode>,
+) {
+    for &PeerChange { peer, state } in peer_changes.iter() {
+        if *game_mode != GameMode::Multi(true) {
+            continue;
+        }
+        if state == PeerState::Disconnected {
+            spectators.peers.retain(|spectator| *spectator != peer);
+            spectators.relays.retain(|relay| relay.peer != peer);
+            if let Some(channel) = &spectators.channel {
+                channel.forget(peer);
+            }
+            continue;
+        }
+        info!("Player {} joined as spectator", peer.0);
+        spectators.peers.push(peer);
+    }
+}
+
+/// Starts a relay for every spectator at the start of each round, or right away for spectators joining during one,
+/// and feeds the relays the confirmed inputs of the round
+fn stream_inputs(
+    mut socket: ResMut<GameSocket>,
+    mut spectators: ResMut<Spectators>,
+    recorder: Res<ReplayRecorder>,
+    seed: Res<Seed>,
+    rules: Res<MatchRules>,
+    session: Res<Session<GgrsConfig>>,
+    state: Res<State<GameState>>,
+) {
+    let GameSocket(Some(socket)) = socket.as_mut() else {
+        return;
+    };
+    let spectators = spectators.as_mut();
+    let num_players = num_players(&session);
+    for peer in &spectators.peers {
+        if state.0 != GameState::InGame
+            || spectators
+                .relays
+                .iter()
+                .any(|relay| relay.peer == *peer && relay.start_frame == recorder.start_frame)
+        {
+            continue;
+        }
+        let channel = spectators.channel.get_or_insert_with(|| {
+            SharedChannel::new(socket.take_channel(SPECTATOR_CHANNEL).unwrap())
+        });
+        let mut packet = vec![SPECTATE];
+        packet.extend_from_slice(&seed.0);
+        packet.extend_from_slice(&recorder.start_frame.to_le_bytes());
+        packet.push(num_players as u8);
+        packet.extend_from_slice(&rules.to_bytes());
+        socket
+            .channel(GAME_CHANNEL)
+            .send(packet.into_boxed_slice(), *peer);
+
+        let mut session_builder = SessionBuilder::<GgrsConfig>::new().with_num_players(num_players);
+        for handle in 0..num_players {
+            session_builder = session_builder
+                .add_player(PlayerType::Local, handle)
+                .expect("failed to add player");
+        }
+        let session = session_builder
+            .add_player(PlayerType::Spectator(*peer), num_players)
+            .expect("failed to add spectator")
+            .start_p2p_session(channel.socket(*peer))
+            .expect("failed to start spectator relay");
+        spectators.relays.retain(|relay| relay.peer != *peer);
+        spectators.relays.push(Relay {
+            peer: *peer,
+            start_frame: recorder.start_frame,
+            session,
+            fed: 0,
+        });
+    }
+
+    for relay in &mut spectators.relays {
+        if relay.start_frame != recorder.start_frame {
+            continue;
+        }
+        relay.session.poll_remote_clients();
+        relay.session.events().for_each(drop);
+        if relay.session.current_state() != SessionState::Running {
+            continue;
+        }
+        let confirmed = recorder.confirmed.min(relay.fed + FRAMES_PER_UPDATE);
+        while relay.fed < confirmed {
+            for (handle, input) in recorder.inputs[relay.fed].iter().enumerate() {
+                relay
+                    .session
+                    .add_local_input(handle, *input)
+                    .expect("failed to add input");
+            }
+            if let Err(error) = relay.session.advance_frame() {
+                warn!("Failed to feed spectator {}: {}", relay.peer.0, error);
+                break;
+            }
+            relay.fed += 1;
+        }
+    }
+}
+
+/// Every SPECTATE packet starts watching a round from its first frame in a new spectator session
+fn join_as_spectator(
+    mut commands: Commands,
+    mut packets: EventReader<GamePacket>,
+    mut socket: ResMut<GameSocket>,
+    spectating: Option<Res<Spectating>>,
+    mut seed_frame: ResMut<SeedFrame>,
+    mut session_frame: ResMut<SessionFrame>,
+    mut next_round: ResMut<NextRound>,
+    mut round_end: ResMut<RoundEnd>,
+    state: Res<State<GameState>>,
+    mut next_state: ResMut<NextState<GameState>>,
+) {
+    for GamePacket { peer, packet } in packets.iter() {
+        if packet.first() != Some(&SPECTATE) || packet.len() < 38 {
+            continue;
+        }
+        let seed = Seed(packet[1..33].try_into().unwrap());
+        let start_frame = u32::from_le_bytes(packet[33..37].try_into().unwrap());
+        let num_players = packet[37] as usize;
+        let Some(rules) = MatchRules::from_bytes(&packet[38..]) else {
+            warn!("Ignoring SPECTATE packet without valid rules");
+            continue;
+        };
+        let channel = match &spectating {
+            Some(spectating) => spectating.channel.clone(),
+            None => {
+                let GameSocket(Some(socket)) = socket.as_mut() else {
+                    return;
+                };
+                SharedChannel::new(socket.take_channel(SPECTATOR_CHANNEL).unwrap())
+            }
+        };
+        info!(
+            "Spectating a round of {} player(s) with seed {}",
+            num_players, seed
+        );
+
+        let session = SessionBuilder::<GgrsConfig>::new()
+            .with_num_players(num_players)
+            .with_max_frames_behind(MAX_FRAMES_BEHIND)
+            .and_then(|builder| builder.with_catchup_speed(CATCH_UP_SPEED))
+            .expect("invalid spectator settings")
+            .start_spectator_session(*peer, channel.socket(*peer));
+        commands.insert_resource(Session::SpectatorSession(session));
+        commands.insert_resource(seed);
+        commands.insert_resource(rules);
+        commands.insert_resource(Spectating {
+            num_players,
+            channel,
+        });
+        commands.init_resource::<SpectatorCamera>();
+
+        // the spectator session counts the frames of the round from zero
+        seed_frame.0 = start_frame;
+        session_frame.0 = 0;
+        next_round.0 = Some(0);
+        round_end.0 = None;
+        if state.0 == GameState::Matchmaking {
+            next_state.set(GameState::Interlude);
+        }
+        return;
+    }
+}
+
+/// Tab cycles through the players and the free camera
+fn move_spectator_camera(
+    keys: Res<Input<KeyCode>>,
+    actions: ActionInput,
+    time: Res<Time>,
+    mut spectator_camera: ResMut<SpectatorCamera>,
+    spectating: Res<Spectating>,
+    player_query: Query<(&Player, &Transform)>,
+    mut camera_query: Query<&mut Transform, (With<Camera>, Without<Player>, Without<HudCamera>)>,
+) {
+    if keys.just_pressed(KeyCode::Tab) {
+        spectator_camera.0 = match spectator_camera.0 {
+            None => Some(0),
+            Some(handle) if handle + 1 < spectating.num_players => Some(handle + 1),
+            Some(_) => None,
+        };
+    }
+    let Ok(mut camera) = camera_query.get_single_mut() else {
+        return;
+    };
+    match spectator_camera.0 {
+        Some(handle) => {
+            if let Some((_, player_transform)) = player_query
+                .iter()
+                .find(|(player, _)| player.handle == handle)
+            {
+                camera.translation.x = player_transform.translation.x;
+                camera.translation.y = player_transform.translation.y;
+            }
+        }
+        None => {
+            let delta = direction(FvzInput::from_buttons(actions.input_bits()))
+                * FREE_CAMERA_SPEED
+                * time.raw_delta_seconds();
+            camera.translation.x += delta.x;
+            camera.translation.y += delta.y;
+        }
+    }
+}
+
+#[derive(Component)]
+struct SpectatorText;
+
+/// Centered at the bottom, away from the corners used by the game and replay HUD
+fn prepare_spectator_ui(mut commands: Commands, font_assets: Res<FontAssets>) {
+    commands
+        .spawn(NodeBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: UiRect {
+                    bottom: Val::Px(15.),
+                    left: Val::Px(0.),
+                    ..default()
+                },
+                size: Size::new(Val::Percent(100.), Val::Auto),
+                justify_content: JustifyContent::Center,
+                ..default()
+            },
+            ..default()
+        })
+        .insert(SessionOnly)
+        .with_children(|parent| {
+            parent
+                .spawn(TextBundle {
+                    text: Text {
+                        sections: vec![TextSection {
+                            value: "Spectating - [Tab] switch camera".to_owned(),
+                            style: TextStyle {
+                                font: font_assets.fira_sans.clone(),
+                                font_size: 25.0,
+                                color: Color::rgb(0.9, 0.9, 0.9),
+                            },
+                        }],
+                        alignment: TextAlignment::Center,
+                        ..default()
+                    },
+                    ..Default::default()
+                })
+                .insert(SpectatorText);
+        });
+}
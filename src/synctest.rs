@@ -0,0 +1,130 @@
+use crate::checksum::world_checksums;
+use crate::input::{
+    FvzInput, INPUT_AIM, INPUT_DOWN, INPUT_FIRE, INPUT_LEFT, INPUT_REVIVE, INPUT_RIGHT, INPUT_UP,
+};
+use crate::matchmaking::{enter_offline_lobby, RemotePlayers, Seed};
+use crate::networking::{end_game, in_round, GgrsConfig, InterludeTimer, SeedFrame, SessionFrame};
+use crate::players::LocalPlayerIds;
+use crate::{cli_arg, GameMode, GameState};
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use bevy_ggrs::{GGRSSchedule, Session};
+use ggrs::{PlayerHandle, SessionBuilder};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+/// Number of frames to keep checksums for
+const CHECKSUM_HISTORY: u32 = 128;
+
+pub struct SyncTestPlugin;
+
+impl Plugin for SyncTestPlugin {
+    fn build(&self, app: &mut App) {
+        if !app.world.contains_resource::<SyncTestSettings>() {
+            return;
+        }
+        app.init_resource::<ChecksumHistory>()
+            .add_system(start_sync_test.in_schedule(OnEnter(GameState::Menu)))
+            .add_system(build_sync_test_session.run_if(in_state(GameState::Matchmaking)))
+            .add_system(
+                compare_checksums
+                    .after(end_game)
+                    .run_if(in_round)
+                    .in_schedule(GGRSSchedule),
+            );
+    }
+}
+
+/// Run the game in a GGRS sync test session with synthetic inputs.
+///
+/// Enabled with `--synctest <check distance>` or the environment variable `FVZ_SYNCTEST=<check distance>`.
+/// The number of simulated players can be set with `--players <count>`.
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct SyncTestSettings {
+    pub check_distance: usize,
+    pub num_players: usize,
+}
+
+impl SyncTestSettings {
+    pub fn from_args() -> Option<Self> {
+        let check_distance =
+            cli_arg("--synctest").or_else(|| std::env::var("FVZ_SYNCTEST").ok())?;
+        Some(SyncTestSettings {
+            check_distance: check_distance
+                .parse()
+                .expect("The sync test check distance needs to be a number"),
+            num_players: cli_arg("--players")
+                .map(|players| players.parse().expect("--players expects a number"))
+                .unwrap_or(2),
+        })
+    }
+}
+
+/// Deterministic pseudo random inputs; every player holds fire and changes course and aim twice a second
+pub fn synthetic_input(In(handle): In<PlayerHandle>, frame: Res<SeedFrame>) -> FvzInput {
+    let mut rng = ChaCha8Rng::seed_from_u64(((frame.0 / 30) as u64) << 8 | handle as u64);
+    FvzInput {
+        buttons: INPUT_FIRE
+            | (rng.gen::<u8>()
+                & (INPUT_UP | INPUT_DOWN | INPUT_LEFT | INPUT_RIGHT | INPUT_REVIVE | INPUT_AIM)),
+        aim: rng.gen(),
+    }
+}
+
+/// Checksums of the first simulation of each [`SessionFrame`], compared against any re-simulation after a rollback.
+///
+/// The seed frame stands still between rounds, so it would mix up frames of different rounds.
+#[derive(Default, Resource)]
+struct ChecksumHistory(HashMap<u32, Vec<(&'static str, u64)>>);
+
+fn start_sync_test(
+    mut commands: Commands,
+    mut players: ResMut<RemotePlayers>,
+    mut state: ResMut<NextState<GameState>>,
+) {
+    enter_offline_lobby(&mut commands, &mut players, &mut state, GameMode::SyncTest);
+}
+
+fn build_sync_test_session(
+    mut commands: Commands,
+    settings: Res<SyncTestSettings>,
+    mut state: ResMut<NextState<GameState>>,
+    mut interlude_timer: ResMut<InterludeTimer>,
+) {
+    info!("starting sync test with {:?}", *settings);
+    let session = SessionBuilder::<GgrsConfig>::new()
+        .with_num_players(settings.num_players)
+        .with_check_distance(settings.check_distance)
+        .start_synctest_session()
+        .expect("failed to start sync test session");
+
+    let seed = Seed::from_args().unwrap_or_else(Seed::random);
+    info!("sync test seed {}", seed);
+    commands.insert_resource(seed);
+    commands.insert_resource(LocalPlayerIds::single(0));
+    commands.insert_resource(Session::SyncTestSession(session));
+
+    interlude_timer.0 = 3;
+    state.set(GameState::Interlude);
+}
+
+fn compare_checksums(world: &mut World) {
+    let frame = world.resource::<SessionFrame>().0;
+    let checksums = world_checksums(world);
+    let mut history = world.resource_mut::<ChecksumHistory>();
+    let Some(expected) = history.0.get(&frame) else {
+        history.0.insert(frame, checksums);
+        history
+            .0
+            .retain(|recorded, _| recorded.wrapping_add(CHECKSUM_HISTORY) > frame);
+        return;
+    };
+    for ((component, expected), (_, actual)) in expected.iter().zip(checksums.iter()) {
+        if expected != actual {
+            panic!(
+                "Sync test failed in frame {}: checksum of {} is {:x} after rollback, but was {:x}",
+                frame, component, actual, expected
+            );
+        }
+    }
+}
@@ -0,0 +1,256 @@
+use crate::gamepad::STICK_DEAD_ZONE;
+use crate::input::{INPUT_FIRE, INPUT_RELOAD, INPUT_REVIVE};
+use crate::loading::FontAssets;
+use crate::networking::SessionOnly;
+use crate::GameState;
+use bevy::prelude::*;
+
+pub struct TouchPlugin;
+
+impl Plugin for TouchPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<TouchControls>()
+            .add_system(detect_touch_screen)
+            .add_system(spawn_touch_controls.in_schedule(OnExit(GameState::Matchmaking)))
+            .add_system(release_touch_controls.in_schedule(OnExit(GameState::InGame)))
+            .add_systems((
+                read_touch_controls.run_if(in_state(GameState::InGame)),
+                show_touch_controls.after(detect_touch_screen),
+            ));
+    }
+}
+
+const JOYSTICK_SIZE: f32 = 160.;
+const KNOB_SIZE: f32 = 60.;
+const TOUCH_BUTTON_SIZE: f32 = 90.;
+
+/// On-screen controls, shown while the screen is used instead of mouse and keyboard
+#[derive(Default, Resource)]
+pub struct TouchControls {
+    pub enabled: bool,
+    /// Deflection of the virtual joystick, at most 1 long
+    pub stick: Vec2,
+    /// Input bits of the pressed on-screen buttons
+    pub buttons: u8,
+    joystick_touch: Option<u64>,
+}
+
+impl TouchControls {
+    fn release(&mut self) {
+        self.stick = Vec2::ZERO;
+        self.buttons = 0;
+        self.joystick_touch = None;
+    }
+}
+
+/// Only shown on touch screens
+#[derive(Component)]
+pub struct TouchOnly;
+
+#[derive(Component)]
+struct Joystick;
+
+#[derive(Component)]
+struct JoystickKnob;
+
+#[derive(Component)]
+struct TouchButton(u8);
+
+/// Show the touch controls once the screen is touched, and hide them again on mouse or keyboard input
+fn detect_touch_screen(
+    touches: Res<Touches>,
+    keys: Res<Input<KeyCode>>,
+    mouse_buttons: Res<Input<MouseButton>>,
+    mut touch_controls: ResMut<TouchControls>,
+) {
+    let touched = touches.iter_just_pressed().next().is_some();
+    if !touch_controls.enabled && touched {
+        info!("Touch screen detected, showing touch controls");
+        touch_controls.enabled = true;
+    }
+    // browsers emulate a mouse click when a touch ends, so clicks only count without any touch
+    let clicked = mouse_buttons.get_just_pressed().next().is_some()
+        && touches.iter().next().is_none()
+        && touches.iter_just_released().next().is_none();
+    if touch_controls.enabled && !touched && (clicked || keys.get_just_pressed().next().is_some()) {
+        info!("Mouse or keyboard used, hiding touch controls");
+        touch_controls.enabled = false;
+        touch_controls.release();
+    }
+}
+
+fn show_touch_controls(
+    touch_controls: Res<TouchControls>,
+    mut touch_only: Query<(&mut Style, &mut Visibility), With<TouchOnly>>,
+    new_touch_only: Query<(), Added<TouchOnly>>,
+) {
+    if !touch_controls.is_changed() && new_touch_only.is_empty() {
+        return;
+    }
+    for (mut style, mut visibility) in &mut touch_only {
+        let (display, shown) = if touch_controls.enabled {
+            (Display::Flex, Visibility::Inherited)
+        } else {
+            (Display::None, Visibility::Hidden)
+        };
+        if style.display != display {
+            style.display = display;
+        }
+        if *visibility != shown {
+            *visibility = shown;
+        }
+    }
+}
+
+fn spawn_touch_controls(mut commands: Commands, font_assets: Res<FontAssets>) {
+    commands
+        .spawn(NodeBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: UiRect {
+                    left: Val::Px(30.),
+                    bottom: Val::Px(30.),
+                    ..default()
+                },
+                size: Size::new(Val::Px(JOYSTICK_SIZE), Val::Px(JOYSTICK_SIZE)),
+                ..default()
+            },
+            background_color: Color::rgba(1., 1., 1., 0.15).into(),
+            ..default()
+        })
+        .insert(Joystick)
+        .insert(TouchOnly)
+        .insert(SessionOnly)
+        .with_children(|parent| {
+            parent
+                .spawn(NodeBundle {
+                    style: Style {
+                        position_type: PositionType::Absolute,
+                        position: knob_position(Vec2::ZERO),
+                        size: Size::new(Val::Px(KNOB_SIZE), Val::Px(KNOB_SIZE)),
+                        ..default()
+                    },
+                    background_color: Color::rgba(1., 1., 1., 0.4).into(),
+                    ..default()
+                })
+                .insert(JoystickKnob);
+        });
+
+    let buttons = [
+        (INPUT_FIRE, "Fire", 30., 30.),
+        (INPUT_REVIVE, "Revive", 30. + TOUCH_BUTTON_SIZE + 20., 30.),
+        (INPUT_RELOAD, "Reload", 30., 30. + TOUCH_BUTTON_SIZE + 20.),
+    ];
+    for (bit, label, right, bottom) in buttons {
+        commands
+            .spawn(NodeBundle {
+                style: Style {
+                    position_type: PositionType::Absolute,
+                    position: UiRect {
+                        right: Val::Px(right),
+                        bottom: Val::Px(bottom),
+                        ..default()
+                    },
+                    size: Size::new(Val::Px(TOUCH_BUTTON_SIZE), Val::Px(TOUCH_BUTTON_SIZE)),
+                    justify_content: JustifyContent::Center,
+                    align_items: AlignItems::Center,
+                    ..default()
+                },
+                background_color: Color::rgba(1., 1., 1., 0.15).into(),
+                ..default()
+            })
+            .insert(TouchButton(bit))
+            .insert(TouchOnly)
+            .insert(SessionOnly)
+            .with_children(|parent| {
+                parent.spawn(TextBundle {
+                    text: Text::from_section(
+                        label,
+                        TextStyle {
+                            font: font_assets.fira_sans.clone(),
+                            font_size: 25.0,
+                            color: Color::rgba(1., 1., 1., 0.7),
+                        },
+                    ),
+                    ..default()
+                });
+            });
+    }
+}
+
+/// Nothing stays pressed into the next round
+fn release_touch_controls(mut touch_controls: ResMut<TouchControls>) {
+    touch_controls.release();
+}
+
+/// Offset of the knob inside the joystick for the given deflection; y points up
+fn knob_position(stick: Vec2) -> UiRect {
+    let center = (JOYSTICK_SIZE - KNOB_SIZE) / 2.;
+    UiRect {
+        left: Val::Px(center + stick.x * center),
+        top: Val::Px(center - stick.y * center),
+        ..default()
+    }
+}
+
+fn contains(node: &Node, transform: &GlobalTransform, position: Vec2) -> bool {
+    let center = transform.translation().truncate();
+    let half_size = node.size() / 2.;
+    (position - center).abs().cmple(half_size).all()
+}
+
+/// The first touch on the joystick drags it until released, any touch on a button presses it.
+///
+/// Touch positions and UI nodes both start at the top left of the window.
+fn read_touch_controls(
+    touches: Res<Touches>,
+    mut touch_controls: ResMut<TouchControls>,
+    joystick: Query<(&Node, &GlobalTransform), With<Joystick>>,
+    buttons: Query<(&Node, &GlobalTransform, &TouchButton)>,
+    mut knob: Query<&mut Style, With<JoystickKnob>>,
+) {
+    if !touch_controls.enabled {
+        return;
+    }
+    let Ok((joystick_node, joystick_transform)) = joystick.get_single() else {
+        return;
+    };
+
+    if let Some(id) = touch_controls.joystick_touch {
+        if touches.get_pressed(id).is_none() {
+            touch_controls.joystick_touch = None;
+        }
+    }
+    if touch_controls.joystick_touch.is_none() {
+        touch_controls.joystick_touch = touches
+            .iter_just_pressed()
+            .find(|touch| contains(joystick_node, joystick_transform, touch.position()))
+            .map(|touch| touch.id());
+    }
+    let stick = touch_controls
+        .joystick_touch
+        .and_then(|id| touches.get_pressed(id))
+        .map(|touch| {
+            let offset = touch.position() - joystick_transform.translation().truncate();
+            let stick = Vec2::new(offset.x, -offset.y) / (JOYSTICK_SIZE / 2.);
+            stick.clamp_length_max(1.)
+        })
+        .unwrap_or_default();
+    touch_controls.stick = if stick.length() > STICK_DEAD_ZONE {
+        stick
+    } else {
+        Vec2::ZERO
+    };
+    if let Ok(mut knob) = knob.get_single_mut() {
+        knob.position = knob_position(stick);
+    }
+
+    touch_controls.buttons = buttons
+        .iter()
+        .filter(|(node, transform, _)| {
+            touches
+                .iter()
+                .any(|touch| contains(node, transform, touch.position()))
+        })
+        .fold(0, |bits, (_, _, TouchButton(bit))| bits | bit);
+}
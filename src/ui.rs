@@ -1,12 +1,17 @@
+use crate::desync::Desync;
 use crate::loading::{FontAssets, ImageAssets, PlayerAssets};
+use crate::local_coop::{HudCamera, PlayerCamera};
 use crate::matchmaking::{LocalPlayer, RemotePlayers, StartGame};
 use crate::menu::{ButtonColors, GameCode};
-use crate::networking::{Dead, HealthBar};
-use crate::players::{Health, Player};
+use crate::networking::{Dead, HealthBar, HealthBarParent, SessionOnly};
+use crate::players::{Health, LocalPlayerIds, Player, Weapon};
+use crate::rules::{MatchRules, Rule};
+use crate::waves::Waves;
 use crate::{GameMode, GameState, Score};
 use bevy::math::Vec3Swizzles;
 use bevy::prelude::*;
 use bevy::render::camera::RenderTarget;
+use bevy::utils::HashMap;
 use bevy::window::{PrimaryWindow, WindowRef};
 
 pub struct UiPlugin;
@@ -20,12 +25,18 @@ impl Plugin for UiPlugin {
         .add_systems((
             update_player_list.run_if(in_state(GameState::Matchmaking)),
             click_start_button.run_if(in_state(GameState::Matchmaking)),
+            click_rule_button.run_if(in_state(GameState::Matchmaking)),
+            update_rule_labels.run_if(in_state(GameState::Matchmaking)),
         ))
         .add_system(prepare_game_ui.in_schedule(OnExit(GameState::Matchmaking)))
         .add_systems((
             update_health_bars.run_if(in_state(GameState::InGame)),
+            hide_health_bars_of_dead_players.run_if(in_state(GameState::InGame)),
             update_score.run_if(in_state(GameState::InGame)),
+            update_wave.run_if(in_state(GameState::InGame)),
+            update_ammo.run_if(in_state(GameState::InGame)),
             move_player_markers.run_if(in_state(GameState::InGame)),
+            show_desync_warning.run_if(resource_added::<Desync>()),
         ))
         .add_system(remove_matchmaking_only_ui.in_schedule(OnExit(GameState::Matchmaking)));
     }
@@ -50,6 +61,7 @@ fn spawn_player_list(mut commands: Commands) {
             background_color: BackgroundColor(Color::NONE),
             ..default()
         })
+        .insert(SessionOnly)
         .with_children(|parent| {
             parent
                 .spawn(TextBundle {
@@ -75,6 +87,10 @@ struct StartButton;
 #[derive(Component)]
 struct RootNode;
 
+/// Shows the current option of a rule, on the host's buttons and in the lobby of everybody else
+#[derive(Component)]
+struct RuleLabel(Rule);
+
 fn prepare_matchmaking_ui(
     mut commands: Commands,
     font_assets: Res<FontAssets>,
@@ -82,6 +98,7 @@ fn prepare_matchmaking_ui(
     game_mode: Res<GameMode>,
     game_code: Res<GameCode>,
     button_colors: Res<ButtonColors>,
+    rules: Res<MatchRules>,
 ) {
     commands
         .spawn(NodeBundle {
@@ -99,6 +116,7 @@ fn prepare_matchmaking_ui(
             ..default()
         })
         .insert(RootNode)
+        .insert(SessionOnly)
         .with_children(|parent| {
             if *game_mode == GameMode::Multi(true) {
                 parent.spawn(TextBundle {
@@ -118,6 +136,53 @@ fn prepare_matchmaking_ui(
                     },
                     ..Default::default()
                 }).insert(MatchmakingOnly);
+                parent
+                    .spawn(NodeBundle {
+                        style: Style {
+                            margin: UiRect::all(Val::Auto),
+                            flex_direction: FlexDirection::Column,
+                            align_items: AlignItems::Center,
+                            ..Default::default()
+                        },
+                        background_color: BackgroundColor(Color::NONE),
+                        ..Default::default()
+                    })
+                    .insert(MatchmakingOnly)
+                    .with_children(|parent| {
+                        for rule in Rule::ALL {
+                            parent
+                                .spawn(ButtonBundle {
+                                    style: Style {
+                                        size: Size::new(Val::Px(320.0), Val::Px(40.0)),
+                                        margin: UiRect::all(Val::Px(4.)),
+                                        justify_content: JustifyContent::Center,
+                                        align_items: AlignItems::Center,
+                                        ..Default::default()
+                                    },
+                                    background_color: button_colors.normal.into(),
+                                    ..Default::default()
+                                })
+                                .insert(rule)
+                                .with_children(|parent| {
+                                    parent.spawn(TextBundle {
+                                        text: Text {
+                                            sections: vec![TextSection {
+                                                value: rules.label(rule),
+                                                style: TextStyle {
+                                                    font: font_assets.fira_sans.clone(),
+                                                    font_size: 25.0,
+                                                    color: Color::rgb(0.9, 0.9, 0.9),
+                                                },
+                                            }],
+                                            alignment: TextAlignment::Center,
+                                            ..default()
+                                        },
+                                        ..Default::default()
+                                    })
+                                    .insert(RuleLabel(rule));
+                                });
+                        }
+                    });
                 parent
                     .spawn(ButtonBundle {
                         style: Style {
@@ -181,6 +246,44 @@ fn prepare_matchmaking_ui(
                             ..Default::default()
                         });
                     });
+                // the rules the host picked, updated whenever they send new ones
+                parent
+                    .spawn(NodeBundle {
+                        style: Style {
+                            margin: UiRect::all(Val::Auto),
+                            flex_direction: FlexDirection::Column,
+                            align_items: AlignItems::Center,
+                            ..Default::default()
+                        },
+                        background_color: BackgroundColor(Color::NONE),
+                        ..Default::default()
+                    })
+                    .insert(MatchmakingOnly)
+                    .with_children(|parent| {
+                        for rule in Rule::ALL {
+                            parent
+                                .spawn(TextBundle {
+                                    style: Style {
+                                        margin: UiRect::all(Val::Px(4.)),
+                                        ..Default::default()
+                                    },
+                                    text: Text {
+                                        sections: vec![TextSection {
+                                            value: rules.label(rule),
+                                            style: TextStyle {
+                                                font: font_assets.fira_sans.clone(),
+                                                font_size: 25.0,
+                                                color: Color::rgb(0.9, 0.9, 0.9),
+                                            },
+                                        }],
+                                        alignment: TextAlignment::Center,
+                                        ..default()
+                                    },
+                                    ..Default::default()
+                                })
+                                .insert(RuleLabel(rule));
+                        }
+                    });
             }
 
             parent.spawn(ImageBundle {
@@ -224,6 +327,15 @@ fn prepare_matchmaking_ui(
 #[derive(Component)]
 struct ScoreText;
 
+#[derive(Component)]
+struct DesyncText;
+
+#[derive(Component)]
+struct WaveText;
+
+#[derive(Component)]
+struct AmmoText;
+
 fn prepare_game_ui(mut commands: Commands, font_assets: Res<FontAssets>) {
     commands
         .spawn(TextBundle {
@@ -250,7 +362,98 @@ fn prepare_game_ui(mut commands: Commands, font_assets: Res<FontAssets>) {
             },
             ..Default::default()
         })
-        .insert(ScoreText);
+        .insert(ScoreText)
+        .insert(SessionOnly);
+    commands
+        .spawn(TextBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: UiRect {
+                    top: Val::Px(60.),
+                    left: Val::Px(15.),
+                    ..default()
+                },
+                ..default()
+            },
+            text: Text {
+                sections: vec![TextSection {
+                    value: "".to_owned(),
+                    style: TextStyle {
+                        font: font_assets.fira_sans.clone(),
+                        font_size: 30.0,
+                        color: Color::rgb(0.9, 0.9, 0.9),
+                    },
+                }],
+                alignment: TextAlignment::Left,
+                ..default()
+            },
+            ..Default::default()
+        })
+        .insert(WaveText)
+        .insert(SessionOnly);
+    commands
+        .spawn(TextBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: UiRect {
+                    bottom: Val::Px(15.),
+                    left: Val::Px(15.),
+                    ..default()
+                },
+                ..default()
+            },
+            text: Text {
+                sections: vec![TextSection {
+                    value: "".to_owned(),
+                    style: TextStyle {
+                        font: font_assets.fira_sans.clone(),
+                        font_size: 30.0,
+                        color: Color::rgb(0.9, 0.9, 0.9),
+                    },
+                }],
+                alignment: TextAlignment::Left,
+                ..default()
+            },
+            ..Default::default()
+        })
+        .insert(AmmoText)
+        .insert(SessionOnly);
+    commands
+        .spawn(TextBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: UiRect {
+                    top: Val::Px(15.),
+                    right: Val::Px(15.),
+                    ..default()
+                },
+                ..default()
+            },
+            text: Text {
+                sections: vec![TextSection {
+                    value: "".to_owned(),
+                    style: TextStyle {
+                        font: font_assets.fira_sans.clone(),
+                        font_size: 30.0,
+                        color: Color::RED,
+                    },
+                }],
+                alignment: TextAlignment::Right,
+                ..default()
+            },
+            ..Default::default()
+        })
+        .insert(DesyncText)
+        .insert(SessionOnly);
+}
+
+fn show_desync_warning(desync: Res<Desync>, mut desync_text: Query<&mut Text, With<DesyncText>>) {
+    if let Ok(mut text) = desync_text.get_single_mut() {
+        text.sections[0].value = format!(
+            "Desync detected in frame {}!\nThe game state was written to disk",
+            desync.frame
+        );
+    }
 }
 
 fn update_score(score: Res<Score>, mut score_text: Query<&mut Text, With<ScoreText>>) {
@@ -262,12 +465,103 @@ fn update_score(score: Res<Score>, mut score_text: Query<&mut Text, With<ScoreTe
     }
 }
 
+fn update_wave(waves: Res<Waves>, mut wave_text: Query<&mut Text, With<WaveText>>) {
+    if !waves.is_changed() {
+        return;
+    }
+    if let Ok(mut text) = wave_text.get_single_mut() {
+        text.sections[0].value = if waves.breather {
+            format!(
+                "Wave {} in {}s",
+                waves.wave + 1,
+                (waves.cooldown as f32 / 60.).ceil()
+            )
+        } else {
+            format!("Wave {}", waves.wave)
+        };
+    }
+}
+
+/// One line per local player, prefixed with the player number when there are several
+fn update_ammo(
+    local_players: Option<Res<LocalPlayerIds>>,
+    players: Query<(&Player, &Weapon)>,
+    changed: Query<(), (With<Player>, Changed<Weapon>)>,
+    mut ammo_text: Query<&mut Text, With<AmmoText>>,
+) {
+    // Spectators have no local player, replays show the ammo of the first player
+    let Some(local_players) = local_players else {
+        return;
+    };
+    if changed.is_empty() {
+        return;
+    }
+    let lines: Vec<String> = local_players
+        .handles()
+        .filter_map(|handle| {
+            let (_, weapon) = players.iter().find(|(player, _)| player.handle == handle)?;
+            let reserve = if weapon.unlimited_reserve {
+                "∞".to_owned()
+            } else {
+                weapon.reserve_ammo.to_string()
+            };
+            let ammo = if weapon.is_reloading() {
+                "Reloading...".to_owned()
+            } else {
+                format!("Ammo: {} / {}", weapon.ammo, reserve)
+            };
+            Some(if local_players.0.len() > 1 {
+                format!("P{} {}", handle + 1, ammo)
+            } else {
+                ammo
+            })
+        })
+        .collect();
+    if let Ok(mut text) = ammo_text.get_single_mut() {
+        text.sections[0].value = lines.join("\n");
+    }
+}
+
 fn remove_matchmaking_only_ui(mut commands: Commands, ui: Query<Entity, With<MatchmakingOnly>>) {
     for entity in &ui {
         commands.entity(entity).despawn_recursive();
     }
 }
 
+/// The host switches a rule to its next option; it is fixed once the game starts
+fn click_rule_button(
+    button_colors: Res<ButtonColors>,
+    start_game: Res<StartGame>,
+    game_mode: Res<GameMode>,
+    mut rules: ResMut<MatchRules>,
+    mut interaction_query: Query<(&Interaction, &mut BackgroundColor, &Rule), Changed<Interaction>>,
+) {
+    // everybody else plays by the rules of the host
+    if start_game.0 || !matches!(*game_mode, GameMode::Multi(true) | GameMode::Single) {
+        return;
+    }
+    for (interaction, mut color, rule) in &mut interaction_query {
+        match *interaction {
+            Interaction::Clicked => rules.cycle(*rule),
+            Interaction::Hovered => {
+                *color = button_colors.hovered.into();
+            }
+            Interaction::None => {
+                *color = button_colors.normal.into();
+            }
+        }
+    }
+}
+
+fn update_rule_labels(rules: Res<MatchRules>, mut labels: Query<(&mut Text, &RuleLabel)>) {
+    if !rules.is_changed() {
+        return;
+    }
+    for (mut text, RuleLabel(rule)) in &mut labels {
+        text.sections[0].value = rules.label(*rule);
+    }
+}
+
 fn click_start_button(
     button_colors: Res<ButtonColors>,
     mut start_game: ResMut<StartGame>,
@@ -342,17 +636,26 @@ fn move_player_markers(
     windows: Query<&Window>,
     primary_window: Query<&Window, With<PrimaryWindow>>,
     images: Res<PlayerAssets>,
-    camera: Query<(&Camera, &Transform), (Without<Player>, Without<PlayerMarker>)>,
+    camera: Query<
+        (&Camera, &Transform, &OrthographicProjection),
+        (
+            Without<Player>,
+            Without<PlayerMarker>,
+            Without<PlayerCamera>,
+            Without<HudCamera>,
+        ),
+    >,
 ) {
-    let (camera, center) = camera.single();
+    let (camera, center, projection) = camera.single();
     let window = if let RenderTarget::Window(WindowRef::Entity(id)) = camera.target {
         windows.get(id).unwrap()
     } else {
         primary_window.single()
     };
 
-    let width = 10.;
-    let height = 10. * (window.height() / window.width());
+    // the shared camera zooms out to frame all local players
+    let width = 10. * projection.scale;
+    let height = width * (window.height() / window.width());
     for (mut marker_transform, marker, mut visibility, mut image) in markers.iter_mut() {
         let Ok((player_entity, player_transform)) = player_query.get_mut(marker.0) else {
             warn!("No player for marker O.o");
@@ -391,13 +694,33 @@ pub fn update_health_bars(
     player: Query<(Entity, &Health), (Changed<Health>, Without<HealthBar>)>,
     mut bars: Query<(&mut Transform, &HealthBar)>,
 ) {
+    if player.is_empty() {
+        return;
+    }
+    let mut bars: HashMap<Entity, Mut<Transform>> = bars
+        .iter_mut()
+        .map(|(transform, health_bar)| (health_bar.0, transform))
+        .collect();
     for (player, health) in &player {
-        if let Some((mut transform, _)) = bars
-            .iter_mut()
-            .find(|(_, health_bar)| health_bar.0 == player)
-        {
+        if let Some(transform) = bars.get_mut(&player) {
             transform.scale.x = (health.current / health.max) as f32;
             transform.translation.x = 50. * (health.current / health.max) as f32 - 50.;
         }
     }
 }
+
+fn hide_health_bars_of_dead_players(
+    mut health_bars: Query<(&Parent, &mut Visibility), With<HealthBarParent>>,
+    dead: Query<&Dead>,
+) {
+    for (parent, mut visibility) in &mut health_bars {
+        let target = if dead.contains(parent.get()) {
+            Visibility::Hidden
+        } else {
+            Visibility::Visible
+        };
+        if *visibility != target {
+            *visibility = target;
+        }
+    }
+}
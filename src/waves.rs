@@ -0,0 +1,423 @@
+use crate::bosses::Boss;
+use crate::enemies::Enemy;
+use crate::loading::{EnemyAssets, EnemyData, GameData};
+use crate::map::Obstacles;
+use crate::matchmaking::{RandomStream, Seed};
+use crate::networking::{Dead, HealthBar, SeedFrame};
+use crate::players::{AnimationTimer, Health, Player};
+use crate::rules::MatchRules;
+use crate::{Score, ENEMY_RADIUS, MAP_SIZE};
+use bevy::math::Vec3Swizzles;
+use bevy::prelude::*;
+use bevy::reflect::TypeUuid;
+use bevy_ggrs::{Rollback, RollbackIdProvider};
+use rand::seq::SliceRandom;
+use rand::Rng;
+use rand_chacha::ChaCha8Rng;
+
+const FRAMES_PER_SECOND: f32 = 60.;
+/// Random spawn points tried before falling back to the map edge
+const SPAWN_ATTEMPTS: usize = 10;
+
+/// Definition of all waves, loaded from `survival.waves`
+#[derive(serde::Deserialize, TypeUuid)]
+#[uuid = "5d1c7f0e-3a5b-4a51-9a2e-0c8f6b8e2d41"]
+pub struct WaveDefinitions {
+    /// Seconds between the last enemy of a wave dying and the next wave
+    pub breather: f32,
+    /// Seconds between two spawns of the same wave
+    pub spawn_interval: f32,
+    /// Enemies never spawn closer than this to a living player
+    pub min_player_distance: f32,
+    pub waves: Vec<WaveDefinition>,
+    /// Applied once per wave after the first; waves after the last definition repeat it with more enemies
+    pub escalation: Escalation,
+    #[serde(default)]
+    pub bosses: Vec<BossSchedule>,
+}
+
+/// When a boss joins the fight, on top of the enemies of the current wave
+#[derive(serde::Deserialize)]
+pub struct BossSchedule {
+    /// Boss key from `enemies.my-assets`
+    pub boss: String,
+    /// At the start of every wave divisible by this
+    #[serde(default)]
+    pub every_waves: Option<u32>,
+    /// Whenever the score passes another multiple of this
+    #[serde(default)]
+    pub every_score: Option<f64>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct WaveDefinition {
+    /// Enemy keys from `enemies.my-assets` with their count
+    pub enemies: Vec<(String, u32)>,
+}
+
+#[derive(serde::Deserialize, Clone, Copy)]
+pub struct Escalation {
+    pub count: f32,
+    pub health: f64,
+    pub damage: f64,
+    pub speed: f32,
+}
+
+impl WaveDefinitions {
+    /// Enemy keys of the given wave, starting at 1, in spawn order
+    pub fn enemies(&self, wave: u32, seed: &Seed) -> Vec<&str> {
+        let mut enemies: Vec<&str> = self
+            .enemy_counts(wave)
+            .flat_map(|(key, count)| std::iter::repeat(key).take(count))
+            .collect();
+        enemies.shuffle(&mut seed.rng(RandomStream::Wave(wave)));
+        enemies
+    }
+
+    /// Number of enemies in the given wave, without putting them in spawn order
+    pub fn enemy_count(&self, wave: u32) -> usize {
+        self.enemy_counts(wave).map(|(_, count)| count).sum()
+    }
+
+    fn enemy_counts(&self, wave: u32) -> impl Iterator<Item = (&str, usize)> {
+        let definition = self
+            .waves
+            .get(wave.saturating_sub(1) as usize)
+            .or_else(|| self.waves.last());
+        let extra_waves = wave.saturating_sub(self.waves.len() as u32);
+        let count_factor = self.escalation.count.powi(extra_waves as i32);
+        definition
+            .into_iter()
+            .flat_map(|definition| &definition.enemies)
+            .map(move |(key, count)| {
+                (
+                    key.as_str(),
+                    (*count as f32 * count_factor).round() as usize,
+                )
+            })
+    }
+
+    /// Stat multipliers of enemies spawned in the given wave, including the difficulty of the match
+    pub fn enemy_stats(&self, wave: u32, rules: &MatchRules) -> Escalation {
+        let mut stats = self.stats(wave);
+        stats.health *= rules.difficulty;
+        stats.damage *= rules.difficulty;
+        stats
+    }
+
+    /// Stat multipliers of the given wave
+    pub fn stats(&self, wave: u32) -> Escalation {
+        let escalations = wave.saturating_sub(1) as i32;
+        Escalation {
+            count: self.escalation.count.powi(escalations),
+            health: self.escalation.health.powi(escalations),
+            damage: self.escalation.damage.powi(escalations),
+            speed: self.escalation.speed.powi(escalations),
+        }
+    }
+
+    pub fn breather_frames(&self) -> u32 {
+        (self.breather * FRAMES_PER_SECOND) as u32
+    }
+
+    fn spawn_interval_frames(&self) -> u32 {
+        (self.spawn_interval * FRAMES_PER_SECOND) as u32
+    }
+}
+
+/// Progress of the wave director
+#[derive(Reflect, Resource, Default)]
+pub struct Waves {
+    /// Current wave starting at 1; 0 before the first wave
+    pub wave: u32,
+    /// Enemies of the current wave spawned so far
+    pub spawned: u32,
+    /// Frames until the next spawn or wave
+    pub cooldown: u32,
+    /// Waiting for the next wave after the current one was cleared
+    pub breather: bool,
+    /// Bosses spawned for the score so far, per entry of [`WaveDefinitions::bosses`]
+    pub score_bosses: Vec<u32>,
+}
+
+/// Starts the next wave once all enemies of the current one are dead, after a breather
+pub fn direct_waves(
+    mut commands: Commands,
+    mut waves: ResMut<Waves>,
+    seed: Res<Seed>,
+    seed_frame: Res<SeedFrame>,
+    score: Res<Score>,
+    game_data: Res<GameData>,
+    wave_definitions: Res<Assets<WaveDefinitions>>,
+    enemy_assets: Res<EnemyAssets>,
+    enemy_data: Res<Assets<EnemyData>>,
+    enemies: Query<(), With<Enemy>>,
+    players: Query<&Transform, (With<Player>, Without<Dead>)>,
+    mut rollback_id_provider: ResMut<RollbackIdProvider>,
+    rules: Res<MatchRules>,
+    obstacles: Res<Obstacles>,
+) {
+    let definitions = wave_definitions.get(&game_data.waves).unwrap();
+    let players: Vec<Vec2> = players
+        .iter()
+        .map(|player| player.translation.xy())
+        .collect();
+    let mut spawn = |key: &str, wave: u32, stream: RandomStream, side: f32| {
+        let Some((handle, enemy)) = enemy_assets
+            .by_key(key)
+            .and_then(|handle| Some((handle, enemy_data.get(handle)?)))
+        else {
+            warn!("Unknown enemy '{}' in wave {}", key, wave);
+            return;
+        };
+        let mut rng = seed.rng(stream);
+        let position = spawn_position(
+            &mut rng,
+            &players,
+            &obstacles,
+            definitions.min_player_distance,
+        );
+        spawn_enemy(
+            &mut commands,
+            &mut rollback_id_provider,
+            handle,
+            enemy,
+            position.extend(100.),
+            &definitions.enemy_stats(wave, &rules),
+            side,
+        );
+    };
+
+    waves.score_bosses.resize(definitions.bosses.len(), 0);
+    for (index, schedule) in definitions.bosses.iter().enumerate() {
+        let Some(every_score) = schedule.every_score else {
+            continue;
+        };
+        let reached = (score.0 / every_score).floor() as u32;
+        if reached > waves.score_bosses[index] {
+            waves.score_bosses[index] = reached;
+            info!("Boss {} for reaching a score of {}", schedule.boss, score.0);
+            spawn(
+                &schedule.boss,
+                waves.wave,
+                RandomStream::Boss(seed_frame.0),
+                1.,
+            );
+        }
+    }
+
+    if waves.cooldown > 0 {
+        waves.cooldown -= 1;
+        return;
+    }
+    let wave_size = definitions.enemy_count(waves.wave);
+    if waves.wave == 0 || (waves.spawned as usize >= wave_size && enemies.is_empty()) {
+        if !waves.breather {
+            waves.breather = true;
+            waves.cooldown = definitions.breather_frames();
+            return;
+        }
+        waves.breather = false;
+        waves.wave += 1;
+        waves.spawned = 0;
+        info!("Wave {}", waves.wave);
+        for schedule in &definitions.bosses {
+            if let Some(every_waves) = schedule.every_waves {
+                if waves.wave % every_waves.max(1) == 0 {
+                    spawn(
+                        &schedule.boss,
+                        waves.wave,
+                        RandomStream::Boss(seed_frame.0),
+                        1.,
+                    );
+                }
+            }
+        }
+        return;
+    }
+    if waves.spawned as usize >= wave_size {
+        return;
+    }
+    let wave_enemies = definitions.enemies(waves.wave, &seed);
+    let key = wave_enemies[waves.spawned as usize];
+    // swarmers alternate between flanking left and right
+    let side = if waves.spawned % 2 == 0 { 1. } else { -1. };
+    spawn(key, waves.wave, RandomStream::Spawn(seed_frame.0), side);
+    waves.spawned += 1;
+    waves.cooldown = definitions.spawn_interval_frames();
+}
+
+/// A random free tile far enough from all living players, or the furthest of some points on the map edge
+fn spawn_position(
+    rng: &mut ChaCha8Rng,
+    players: &[Vec2],
+    obstacles: &Obstacles,
+    min_player_distance: f32,
+) -> Vec2 {
+    let half_size = MAP_SIZE as f32 / 2.;
+    let closest_player = |position: Vec2| {
+        players
+            .iter()
+            .map(|player| player.distance(position))
+            .fold(f32::INFINITY, f32::min)
+    };
+    for _ in 0..SPAWN_ATTEMPTS {
+        let position = Vec2::new(
+            rng.gen_range(0..MAP_SIZE) as f32 - half_size,
+            rng.gen_range(0..MAP_SIZE) as f32 - half_size,
+        );
+        if closest_player(position) >= min_player_distance
+            && !obstacles.blocks(position, ENEMY_RADIUS)
+        {
+            return position;
+        }
+    }
+    (0..SPAWN_ATTEMPTS)
+        .map(|_| {
+            let along = rng.gen_range(0..MAP_SIZE) as f32 - half_size;
+            match rng.gen_range(0..4) {
+                0 => Vec2::new(along, -half_size),
+                1 => Vec2::new(along, half_size),
+                2 => Vec2::new(-half_size, along),
+                _ => Vec2::new(half_size, along),
+            }
+        })
+        .max_by(|a, b| closest_player(*a).total_cmp(&closest_player(*b)))
+        .unwrap()
+}
+
+pub fn spawn_enemy(
+    commands: &mut Commands,
+    rollback_id_provider: &mut RollbackIdProvider,
+    handle: &Handle<EnemyData>,
+    enemy: &EnemyData,
+    translation: Vec3,
+    stats: &Escalation,
+    side: f32,
+) {
+    let mut enemy_commands = commands.spawn(SpriteSheetBundle {
+        transform: Transform {
+            translation,
+            scale: Vec3::splat(0.01 * enemy.radius / ENEMY_RADIUS),
+            ..Default::default()
+        },
+        sprite: TextureAtlasSprite::new(0),
+        texture_atlas: enemy.texture_atlas.clone(),
+        ..Default::default()
+    });
+    let enemy_id = enemy_commands.id();
+    enemy_commands
+        .insert(Health::new(enemy.health * stats.health))
+        .insert(Enemy {
+            damage: enemy.damage * stats.damage,
+            speed: enemy.speed * stats.speed,
+            attack_cooldown: enemy.attack_cooldown as u32,
+            last_attack: 0,
+            behavior: enemy.behavior,
+            action: default(),
+            side,
+            radius: enemy.radius,
+        })
+        .insert(AnimationTimer(
+            Timer::from_seconds(0.1, TimerMode::Repeating),
+            4,
+        ))
+        .insert(Rollback::new(rollback_id_provider.next_id()));
+    if !enemy.phases.is_empty() {
+        enemy_commands.insert(Boss::new(handle.clone()));
+    }
+    enemy_commands.with_children(|parent| {
+        parent.spawn(SpriteBundle {
+            sprite: Sprite {
+                color: Color::DARK_GRAY,
+                custom_size: Some(Vec2::new(100., 5.1)),
+                ..default()
+            },
+            transform: Transform::from_translation(Vec3::new(0., 50., 1.)),
+            ..default()
+        });
+        parent
+            .spawn(SpriteBundle {
+                sprite: Sprite {
+                    color: Color::RED,
+                    custom_size: Some(Vec2::new(100., 5.1)),
+                    ..default()
+                },
+                transform: Transform::from_translation(Vec3::new(0., 50., 2.)),
+                ..default()
+            })
+            .insert(HealthBar(enemy_id));
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loading::CustomDynamicAssetCollection;
+
+    fn survival() -> WaveDefinitions {
+        ron::from_str(include_str!("../assets/survival.waves")).unwrap()
+    }
+
+    #[test]
+    fn survival_waves_only_name_known_enemies() {
+        let enemies: CustomDynamicAssetCollection =
+            ron::from_str(include_str!("../assets/enemies.my-assets")).unwrap();
+        let waves = survival();
+
+        let keys = waves
+            .waves
+            .iter()
+            .flat_map(|wave| wave.enemies.iter().map(|(key, _)| key))
+            .chain(waves.bosses.iter().map(|boss| &boss.boss));
+        for key in keys {
+            assert!(enemies.0.contains_key(key), "unknown enemy {}", key);
+        }
+        assert!(waves
+            .bosses
+            .iter()
+            .all(|boss| boss.every_waves.is_some() || boss.every_score.is_some()));
+    }
+
+    #[test]
+    fn bosses_are_optional() {
+        let waves: WaveDefinitions = ron::from_str(
+            "(
+                breather: 2.5,
+                spawn_interval: 0.5,
+                min_player_distance: 4.,
+                waves: [(enemies: [(\"zombie\", 3), (\"devil\", 1)])],
+                escalation: (count: 2., health: 1., damage: 1., speed: 1.),
+            )",
+        )
+        .unwrap();
+
+        assert!(waves.bosses.is_empty());
+        assert_eq!(waves.breather_frames(), 150);
+        assert_eq!(waves.spawn_interval_frames(), 30);
+        assert_eq!(waves.enemy_count(1), 4);
+        // later waves repeat the last one, with twice the enemies per extra wave
+        assert_eq!(waves.enemy_count(2), 8);
+        assert_eq!(waves.enemy_count(3), 16);
+    }
+
+    #[test]
+    fn spawn_order_depends_only_on_the_seed() {
+        let waves = survival();
+        let wave = waves.waves.len() as u32;
+        let seed = Seed::from_u64(3);
+
+        let enemies = waves.enemies(wave, &seed);
+        assert_eq!(enemies, waves.enemies(wave, &seed));
+        assert_eq!(enemies.len(), waves.enemy_count(wave));
+        let mut sorted = enemies.clone();
+        sorted.sort_unstable();
+        let mut expected: Vec<&str> = waves.waves[wave as usize - 1]
+            .enemies
+            .iter()
+            .flat_map(|(key, count)| std::iter::repeat(key.as_str()).take(*count as usize))
+            .collect();
+        expected.sort_unstable();
+        assert_eq!(sorted, expected);
+    }
+}